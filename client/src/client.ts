@@ -2,6 +2,9 @@ import { Bitmap } from "./bitmap";
 import { Observable } from "./utils";
 
 export const PROTOCOL_VERSION = 1;
+// Minor version sent in the client hello. 1.9 is the first version the server pings, and the
+// client doesn't handle Board Info (1.14), so it's only accepted on boards with the default size.
+export const PROTOCOL_VERSION_MINOR = 9;
 export const CHUNK_SIZE = 64 * 64 * 64;
 export const CHUNK_SIZE_BYTES = CHUNK_SIZE / 8;
 export const CHUNK_COUNT = 64 * 64;
@@ -11,6 +14,9 @@ export const UPDATE_CHUNK_SIZE = 32;
 export const enum MessageType {
 	Hello = 0x0,
 	Stats = 0x1,
+	ClientHello = 0x2,
+	Ping = 0x5,
+	Pong = 0x6,
 	ChunkFullStateRequest = 0x10,
 	ChunkFullStateResponse = 0x11,
 	PartialStateUpdate = 0x12,
@@ -24,9 +30,30 @@ export interface HelloMessage {
 	versionMinor: number;
 }
 
+export interface ClientHelloMessage {
+	msg: MessageType.ClientHello;
+	versionMajor: number;
+	versionMinor: number;
+	capabilities: number;
+}
+
 export interface StatsMessage {
 	msg: MessageType.Stats;
 	currentClients: number;
+	checkedBits: number;
+	togglesPerSecond: number;
+	uptimeSeconds: number;
+	serverTimestamp: bigint;
+}
+
+export interface PingMessage {
+	msg: MessageType.Ping;
+	payload: bigint;
+}
+
+export interface PongMessage {
+	msg: MessageType.Pong;
+	payload: bigint;
 }
 
 export interface ChunkFullStateRequestMessage {
@@ -56,8 +83,18 @@ export interface PartialStateSubscriptionMessage {
 	chunkIndex: number;
 }
 
-export type ClientMessage = ChunkFullStateRequestMessage | ToggleBitMessage | PartialStateSubscriptionMessage;
-export type ServerMessage = HelloMessage | StatsMessage | ChunkFullStateResponseMessage | PartialStateUpdateMessage;
+export type ClientMessage =
+	| ClientHelloMessage
+	| ChunkFullStateRequestMessage
+	| ToggleBitMessage
+	| PartialStateSubscriptionMessage
+	| PongMessage;
+export type ServerMessage =
+	| HelloMessage
+	| StatsMessage
+	| PingMessage
+	| ChunkFullStateResponseMessage
+	| PartialStateUpdateMessage;
 
 export type Message = ClientMessage | ServerMessage;
 
@@ -71,6 +108,7 @@ export class BitmapClient {
 	currentChunkIndex = 0;
 	currentClients = new Observable<number>(1);
 	checkedCount = new Observable<number>(0);
+	totalCheckedCount = new Observable<number>(0);
 	chunkLoaded = false;
 
 	constructor() {
@@ -150,6 +188,13 @@ export class BitmapClient {
 				alert("Incompatible protocol version");
 			}
 
+			this.send({
+				msg: MessageType.ClientHello,
+				versionMajor: PROTOCOL_VERSION,
+				versionMinor: PROTOCOL_VERSION_MINOR,
+				capabilities: 0,
+			});
+
 			const chunkIndex = this.chunkIndex;
 
 			this.send({ msg: MessageType.PartialStateSubscription, chunkIndex });
@@ -158,6 +203,10 @@ export class BitmapClient {
 			const stats = msg as StatsMessage;
 			console.log("Current clients", stats.currentClients);
 			this.currentClients.value = stats.currentClients;
+			this.totalCheckedCount.value = stats.checkedBits;
+		} else if (msg.msg === MessageType.Ping) {
+			const ping = msg as PingMessage;
+			this.send({ msg: MessageType.Pong, payload: ping.payload });
 		} else if (msg.msg === MessageType.ChunkFullStateResponse) {
 			const fullState = msg as ChunkFullStateResponseMessage;
 			if (fullState.chunkIndex !== this.chunkIndex) return;
@@ -192,8 +241,23 @@ export class BitmapClient {
 			return { msg, versionMajor, versionMinor } as HelloMessage;
 		} else if (msg === MessageType.Stats) {
 			const currentClients = dataView.getUint32(1, true);
-
-			return { msg, currentClients } as StatsMessage;
+			const checkedBits = dataView.getUint32(5, true);
+			const togglesPerSecond = dataView.getUint32(9, true);
+			const uptimeSeconds = dataView.getUint32(13, true);
+			const serverTimestamp = dataView.getBigUint64(17, true);
+
+			return {
+				msg,
+				currentClients,
+				checkedBits,
+				togglesPerSecond,
+				uptimeSeconds,
+				serverTimestamp,
+			} as StatsMessage;
+		} else if (msg === MessageType.Ping) {
+			const pingPayload = dataView.getBigUint64(1, true);
+
+			return { msg, payload: pingPayload } as PingMessage;
 		} else if (msg === MessageType.ChunkFullStateResponse) {
 			const chunkIndex = dataView.getUint16(1, true);
 			const bitmap = payload.slice(3);
@@ -217,7 +281,16 @@ export class BitmapClient {
 	}
 
 	private serialize(msg: ClientMessage) {
-		if (msg.msg === MessageType.ChunkFullStateRequest || msg.msg === MessageType.PartialStateSubscription) {
+		if (msg.msg === MessageType.ClientHello) {
+			const data = new Uint8Array(9);
+			data[0] = msg.msg;
+			const view = new DataView(data.buffer);
+			view.setUint16(1, msg.versionMajor, true);
+			view.setUint16(3, msg.versionMinor, true);
+			view.setUint32(5, msg.capabilities, true);
+
+			return data;
+		} else if (msg.msg === MessageType.ChunkFullStateRequest || msg.msg === MessageType.PartialStateSubscription) {
 			const data = new Uint8Array(3);
 			data[0] = msg.msg;
 			const view = new DataView(data.buffer);
@@ -230,6 +303,13 @@ export class BitmapClient {
 			const view = new DataView(data.buffer);
 			view.setUint32(1, msg.index, true);
 
+			return data;
+		} else if (msg.msg === MessageType.Pong) {
+			const data = new Uint8Array(9);
+			data[0] = msg.msg;
+			const view = new DataView(data.buffer);
+			view.setBigUint64(1, msg.payload, true);
+
 			return data;
 		} else {
 			throw new Error("Invalid message type");
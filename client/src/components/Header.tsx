@@ -360,6 +360,7 @@ interface HeaderState {
 	menuOpen: boolean;
 	currentClients: number;
 	checkedCount: number;
+	totalCheckedCount: number;
 }
 
 export class Header extends Component<HeaderProps> {
@@ -370,6 +371,7 @@ export class Header extends Component<HeaderProps> {
 			menuOpen: false,
 			currentClients: props.client.currentClients.value,
 			checkedCount: props.client.checkedCount.value,
+			totalCheckedCount: props.client.totalCheckedCount.value,
 		};
 	}
 
@@ -377,12 +379,14 @@ export class Header extends Component<HeaderProps> {
 		const client = this.props.client;
 		client.currentClients.subscribe(this.#onCurrentClientsChange);
 		client.checkedCount.subscribe(this.#onCheckedCountChange);
+		client.totalCheckedCount.subscribe(this.#onTotalCheckedCountChange);
 	}
 
 	componentWillUnmount(): void {
 		const client = this.props.client;
 		client.currentClients.unsubscribe(this.#onCurrentClientsChange);
 		client.checkedCount.unsubscribe(this.#onCheckedCountChange);
+		client.totalCheckedCount.unsubscribe(this.#onTotalCheckedCountChange);
 	}
 
 	#onPageChange = (value: number): void => {
@@ -400,6 +404,10 @@ export class Header extends Component<HeaderProps> {
 		this.setState({ checkedCount });
 	};
 
+	#onTotalCheckedCountChange = (totalCheckedCount: number): void => {
+		this.setState({ totalCheckedCount });
+	};
+
 	#setOpen(menuOpen: boolean): void {
 		this.setState({ menuOpen });
 	}
@@ -427,6 +435,8 @@ export class Header extends Component<HeaderProps> {
 						</span>
 
 						<span className="small">{state.checkedCount} checked on this page</span>
+
+						<span className="small">{state.totalCheckedCount} checked in total</span>
 					</div>
 
 					<div className={"header-page"}>
@@ -0,0 +1,316 @@
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use checkboxes_server::{
+    client::{BitmapClient, ClientEvent},
+    common::PResult,
+    render::CHUNK_IMAGE_WIDTH,
+};
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use tokio::{net::TcpStream, sync::broadcast::error::RecvError};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+const DEFAULT_SERVER: &str = "[::1]:2253";
+
+const USAGE: &str = "\
+Usage: checkboxes-cli [--server <host:port>] [--board <name>] <command> [args]
+
+Commands:
+  toggle <index>...                      Toggle bits in the global bitmap
+  dump <chunk> [hex|ascii|pbm] [file]    Print a chunk, or write it to a file
+  follow <chunk>                         Print bits of a chunk as they change
+  checksum [chunk]...                    Print the checksums of chunks, or of every chunk
+  stats                                  Print the stats sent by the server
+  metrics                                Print the Prometheus metrics of the server
+
+The server defaults to [::1]:2253, and the board to the default board of the server.";
+
+enum DumpFormat {
+    Hex,
+    Ascii,
+    Pbm,
+}
+
+#[tokio::main]
+async fn main() -> PResult<()> {
+    let log_level = std::env::var("RUST_LOG").unwrap_or_else(|_| "warn".to_string());
+    pretty_env_logger::formatted_timed_builder()
+        .filter_level(log_level.parse()?)
+        .try_init()?;
+
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut server = DEFAULT_SERVER.to_string();
+    if let Some(position) = args.iter().position(|a| a == "--server" || a == "-s") {
+        args.remove(position);
+        if position >= args.len() {
+            return Err("--server requires an address".into());
+        }
+        server = args.remove(position);
+    }
+
+    let mut board = String::new();
+    if let Some(position) = args.iter().position(|a| a == "--board" || a == "-b") {
+        args.remove(position);
+        if position >= args.len() {
+            return Err("--board requires a name".into());
+        }
+        board = args.remove(position);
+    }
+
+    let Some((command, args)) = args.split_first() else {
+        println!("{}", USAGE);
+        return Ok(());
+    };
+
+    match command.as_str() {
+        "toggle" => toggle(&server, &board, args).await,
+        "dump" => dump(&server, &board, args).await,
+        "follow" => follow(&server, &board, args).await,
+        "checksum" => checksum(&server, &board, args).await,
+        "stats" => stats(&server, &board).await,
+        "metrics" => metrics(&server).await,
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => {
+            eprintln!("Unknown command: {}\n\n{}", command, USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+async fn toggle(server: &str, board: &str, args: &[String]) -> PResult<()> {
+    if args.is_empty() {
+        return Err("toggle requires at least one index".into());
+    }
+
+    let indices = args
+        .iter()
+        .map(|a| a.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let client = BitmapClient::connect_to_board(server, board).await?;
+    if let [index] = indices[..] {
+        client.toggle(index).await?;
+    } else {
+        client.toggle_many(&indices).await?;
+    }
+
+    client.close().await
+}
+
+async fn dump(server: &str, board: &str, args: &[String]) -> PResult<()> {
+    let chunk_index = parse_chunk_index(args.first())?;
+    let format = match args.get(1).map(String::as_str) {
+        None | Some("hex") => DumpFormat::Hex,
+        Some("ascii") => DumpFormat::Ascii,
+        Some("pbm") => DumpFormat::Pbm,
+        Some(format) => return Err(format!("Unknown dump format: {}", format).into()),
+    };
+
+    let client = BitmapClient::connect_to_board(server, board).await?;
+    client.subscribe(chunk_index).await?;
+    let chunk = client
+        .chunk(chunk_index)
+        .await
+        .ok_or("Chunk wasn't loaded")?;
+    client.close().await?;
+
+    let mut output = Vec::new();
+    match format {
+        DumpFormat::Hex => write_hex(&chunk[..], &mut output)?,
+        DumpFormat::Ascii => write_ascii(&chunk[..], &mut output)?,
+        DumpFormat::Pbm => write_pbm(&chunk[..], &mut output)?,
+    }
+
+    match args.get(2) {
+        Some(path) => std::fs::write(path, output)?,
+        None => std::io::stdout().lock().write_all(&output)?,
+    }
+
+    Ok(())
+}
+
+async fn follow(server: &str, board: &str, args: &[String]) -> PResult<()> {
+    let chunk_index = parse_chunk_index(args.first())?;
+
+    let client = BitmapClient::connect_to_board(server, board).await?;
+    let mut events = client.events();
+    client.subscribe(chunk_index).await?;
+
+    let mut previous = client
+        .chunk(chunk_index)
+        .await
+        .ok_or("Chunk wasn't loaded")?;
+    let started_at = Instant::now();
+    let chunk_offset = chunk_index as usize * client.geometry().chunk_size;
+
+    loop {
+        match events.recv().await {
+            Ok(ClientEvent::ChunkUpdated { chunk_index: c })
+            | Ok(ClientEvent::ChunkLoaded { chunk_index: c })
+                if c == chunk_index =>
+            {
+                let Some(current) = client.chunk(chunk_index).await else {
+                    continue;
+                };
+
+                let elapsed = started_at.elapsed().as_secs_f64();
+                for (byte_index, (old, new)) in previous.iter().zip(current.iter()).enumerate() {
+                    let mut changed = old ^ new;
+                    while changed != 0 {
+                        let bit = changed.trailing_zeros() as usize;
+                        changed &= changed - 1;
+
+                        let value = (new >> bit) & 1;
+                        let index = chunk_offset + byte_index * 8 + bit;
+                        println!("[{:10.3}] {} = {}", elapsed, index, value);
+                    }
+                }
+
+                previous = current;
+            }
+            Ok(ClientEvent::ChunkResync { chunk_index: c }) if c == chunk_index => {
+                println!("Chunk fell out of sync, reloading");
+            }
+            Ok(ClientEvent::LockedRanges { chunk_index: c }) if c == chunk_index => {
+                for range in client.locked_ranges(chunk_index).await {
+                    let start = chunk_offset + range.start as usize;
+                    let end = chunk_offset + range.end as usize;
+                    println!("Locked: {}..{}", start, end);
+                }
+            }
+            Ok(ClientEvent::Disconnected) | Err(RecvError::Closed) => {
+                return Err("Connection closed".into());
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(skipped)) => {
+                println!("Skipped {} events", skipped);
+            }
+        }
+    }
+}
+
+async fn checksum(server: &str, board: &str, args: &[String]) -> PResult<()> {
+    let chunk_indices = args
+        .iter()
+        .map(|a| a.parse::<u16>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let client = BitmapClient::connect_to_board(server, board).await?;
+    let checksums = client.checksums(&chunk_indices).await?;
+    client.close().await?;
+
+    let mut stdout = std::io::stdout().lock();
+    for (chunk_index, checksum) in checksums {
+        writeln!(stdout, "{:4} {:08x}", chunk_index, checksum)?;
+    }
+
+    Ok(())
+}
+
+async fn stats(server: &str, board: &str) -> PResult<()> {
+    let client = BitmapClient::connect_to_board(server, board).await?;
+    let mut events = client.events();
+
+    loop {
+        match events.recv().await {
+            Ok(ClientEvent::Stats(stats)) => {
+                let current_clients = stats.current_clients.get();
+                let checked_bits = stats.checked_bits.get();
+                let toggles_per_second = stats.toggles_per_second.get();
+                let uptime = Duration::from_secs(stats.uptime_seconds.get() as u64);
+                let server_timestamp = stats.server_timestamp.get();
+
+                println!(
+                    "clients: {}, checked: {}, toggles/s: {}, uptime: {:?}, server time: {}",
+                    current_clients, checked_bits, toggles_per_second, uptime, server_timestamp
+                );
+            }
+            Ok(ClientEvent::Disconnected) | Err(RecvError::Closed) => {
+                return Err("Connection closed".into());
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+        }
+    }
+}
+
+async fn metrics(server: &str) -> PResult<()> {
+    let mut stream = TcpStream::connect(server).await?.compat();
+    let request = format!(
+        "GET /metrics HTTP/1.1\r\n\
+        Host: {}\r\n\
+        Connection: close\r\n\
+        \r\n",
+        server
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let mut header_buf = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut header_buf);
+    let body_offset = match parsed.parse(&response)? {
+        httparse::Status::Complete(offset) => offset,
+        httparse::Status::Partial => return Err("Incomplete HTTP response".into()),
+    };
+
+    if parsed.code != Some(200) {
+        return Err(format!("Server responded with status {:?}", parsed.code).into());
+    }
+
+    std::io::stdout()
+        .lock()
+        .write_all(&response[body_offset..])?;
+    Ok(())
+}
+
+fn parse_chunk_index(arg: Option<&String>) -> PResult<u16> {
+    let arg = arg.ok_or("Missing chunk index")?;
+    Ok(arg.parse()?)
+}
+
+/// Writes the chunk like `xxd`, 32 bytes per line prefixed with the offset within the chunk.
+fn write_hex(chunk: &[u8], output: &mut Vec<u8>) -> PResult<()> {
+    for (line_index, line) in chunk.chunks(32).enumerate() {
+        write!(output, "{:06x}:", line_index * 32)?;
+        for byte in line {
+            write!(output, " {:02x}", byte)?;
+        }
+        writeln!(output)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the chunk as rows of '#' (checked) and '.' (unchecked), 64 bits per row.
+fn write_ascii(chunk: &[u8], output: &mut Vec<u8>) -> PResult<()> {
+    for row in chunk.chunks(8) {
+        for byte in row {
+            for bit in 0..8 {
+                let c = if (byte >> bit) & 1 != 0 { b'#' } else { b'.' };
+                output.push(c);
+            }
+        }
+        output.push(b'\n');
+    }
+
+    Ok(())
+}
+
+/// Writes the chunk as a binary PBM image, with checked bits as black pixels.
+fn write_pbm(chunk: &[u8], output: &mut Vec<u8>) -> PResult<()> {
+    let width = CHUNK_IMAGE_WIDTH.min(chunk.len() * 8);
+    let height = (chunk.len() * 8).div_ceil(width);
+    write!(output, "P4\n{} {}\n", width, height)?;
+
+    // PBM stores the leftmost pixel in the most significant bit. The last row is padded with
+    // unchecked pixels.
+    output.extend(chunk.iter().map(|byte| byte.reverse_bits()));
+    output.resize(output.len() + width * height / 8 - chunk.len(), 0);
+    Ok(())
+}
@@ -0,0 +1,484 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use checkboxes_server::{
+    client::{BitmapClient, ClientEvent},
+    common::PResult,
+};
+use tokio::{sync::broadcast::error::RecvError, task::JoinSet};
+
+const USAGE: &str = "\
+Usage: checkboxes-loadgen [options]
+
+Options:
+  --server <host:port>          Server to connect to (default: [::1]:2253)
+  --board <name>                Board to connect to (default: the default board of the server)
+  --clients <n>                 Number of simulated clients (default: 100)
+  --duration <secs>             How long to run for, after all clients connected (default: 30)
+  --ramp-up <secs>              Time over which the clients connect (default: 5)
+  --toggle-rate <n>             Toggles per second sent by each client (default: 1)
+  --full-state-interval <secs>  How often each client requests a full state, 0 to disable
+                                (default: 10)
+  --distribution <dist>         How clients pick the chunk they subscribe to (default: uniform)
+                                  uniform        any chunk with equal probability
+                                  zipf[:<s>]     low chunks are more popular, exponent s
+                                                 (default: 1.0)
+                                  fixed:<chunk>  every client subscribes to the same chunk";
+
+/// How often each client pings the server to measure the round-trip time.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the progress is reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The highest toggle rate of a client, so the interval between toggles doesn't round to zero.
+const MAX_TOGGLE_RATE: f64 = 1_000_000.0;
+
+struct Options {
+    server: String,
+    board: String,
+    clients: usize,
+    duration: Duration,
+    ramp_up: Duration,
+    toggle_rate: f64,
+    full_state_interval: Duration,
+    distribution: Distribution,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> PResult<Self> {
+        let mut options = Options {
+            server: "[::1]:2253".to_string(),
+            board: String::new(),
+            clients: 100,
+            duration: Duration::from_secs(30),
+            ramp_up: Duration::from_secs(5),
+            toggle_rate: 1.0,
+            full_state_interval: Duration::from_secs(10),
+            distribution: Distribution::Uniform,
+        };
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            if flag == "--help" || flag == "-h" {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} requires a value", flag))?;
+
+            match flag.as_str() {
+                "--server" => options.server = value.clone(),
+                "--board" => options.board = value.clone(),
+                "--clients" => options.clients = value.parse()?,
+                "--duration" => options.duration = Duration::from_secs(value.parse()?),
+                "--ramp-up" => options.ramp_up = Duration::from_secs(value.parse()?),
+                "--toggle-rate" => options.toggle_rate = value.parse()?,
+                "--full-state-interval" => {
+                    options.full_state_interval = Duration::from_secs(value.parse()?)
+                }
+                "--distribution" => options.distribution = Distribution::parse(value)?,
+                _ => return Err(format!("Unknown option: {}\n\n{}", flag, USAGE).into()),
+            }
+        }
+
+        if !(0.0..=MAX_TOGGLE_RATE).contains(&options.toggle_rate) {
+            return Err(format!("--toggle-rate must be between 0 and {}", MAX_TOGGLE_RATE).into());
+        }
+
+        Ok(options)
+    }
+}
+
+enum Distribution {
+    Uniform,
+    Zipf { exponent: f64 },
+    Fixed(u16),
+}
+
+impl Distribution {
+    fn parse(value: &str) -> PResult<Self> {
+        let (name, parameter) = match value.split_once(':') {
+            Some((name, parameter)) => (name, Some(parameter)),
+            None => (value, None),
+        };
+
+        match (name, parameter) {
+            ("uniform", None) => Ok(Distribution::Uniform),
+            ("zipf", exponent) => {
+                let exponent: f64 = exponent.map(str::parse).transpose()?.unwrap_or(1.0);
+                Ok(Distribution::Zipf { exponent })
+            }
+            ("fixed", Some(chunk)) => Ok(Distribution::Fixed(chunk.parse()?)),
+            _ => Err(format!("Unknown distribution: {}", value).into()),
+        }
+    }
+
+    /// Returns a sampler of this distribution over the chunks of a bitmap.
+    fn sampler(&self, chunk_count: usize) -> PResult<ChunkSampler> {
+        match *self {
+            Distribution::Uniform => Ok(ChunkSampler::Uniform { chunk_count }),
+            Distribution::Zipf { exponent } => {
+                let mut cdf = Vec::with_capacity(chunk_count);
+                let mut total = 0.0;
+                for rank in 1..=chunk_count {
+                    total += 1.0 / (rank as f64).powf(exponent);
+                    cdf.push(total);
+                }
+                cdf.iter_mut().for_each(|p| *p /= total);
+
+                Ok(ChunkSampler::Zipf(cdf))
+            }
+            Distribution::Fixed(chunk) => {
+                if chunk as usize >= chunk_count {
+                    return Err(format!("Chunk {} is out of range", chunk).into());
+                }
+                Ok(ChunkSampler::Fixed(chunk))
+            }
+        }
+    }
+}
+
+enum ChunkSampler {
+    Uniform {
+        chunk_count: usize,
+    },
+    /// Cumulative probabilities of each chunk.
+    Zipf(Vec<f64>),
+    Fixed(u16),
+}
+
+impl ChunkSampler {
+    fn sample(&self, rng: &mut Rng) -> u16 {
+        match self {
+            ChunkSampler::Uniform { chunk_count } => (rng.next() % *chunk_count as u64) as u16,
+            ChunkSampler::Zipf(cdf) => {
+                let value = rng.next_f64();
+                cdf.partition_point(|&p| p < value).min(cdf.len() - 1) as u16
+            }
+            ChunkSampler::Fixed(chunk) => *chunk,
+        }
+    }
+}
+
+/// xorshift64*, good enough for picking chunks and bits.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Counters shared by all simulated clients. The reporter takes the deltas every interval.
+#[derive(Default)]
+struct LoadStats {
+    connected: AtomicU64,
+    failed: AtomicU64,
+    disconnected: AtomicU64,
+    toggles: AtomicU64,
+    updates: AtomicU64,
+    full_states: AtomicU64,
+    lag_events: AtomicU64,
+    errors: AtomicU64,
+    ping_latencies: Mutex<Vec<Duration>>,
+    update_latencies: Mutex<Vec<Duration>>,
+}
+
+#[derive(Default)]
+struct Totals {
+    toggles: u64,
+    updates: u64,
+    full_states: u64,
+    lag_events: u64,
+    errors: u64,
+    ping_latencies: Vec<Duration>,
+    update_latencies: Vec<Duration>,
+}
+
+#[tokio::main]
+async fn main() -> PResult<()> {
+    let log_level = std::env::var("RUST_LOG").unwrap_or_else(|_| "warn".to_string());
+    pretty_env_logger::formatted_timed_builder()
+        .filter_level(log_level.parse()?)
+        .try_init()?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = Arc::new(Options::parse(&args)?);
+    let stats = Arc::new(LoadStats::default());
+    let started_at = Instant::now();
+    let stop_at = started_at + options.ramp_up + options.duration;
+
+    println!(
+        "Starting {} clients against {}, running for {:?} after a {:?} ramp-up",
+        options.clients, options.server, options.duration, options.ramp_up
+    );
+
+    // The chunks clients subscribe to depend on the number of chunks on the server.
+    let probe = BitmapClient::connect_to_board(&options.server, &options.board).await?;
+    let geometry = probe.geometry();
+    probe.close().await?;
+    let sampler = Arc::new(options.distribution.sampler(geometry.chunk_count)?);
+
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+
+    let mut clients = JoinSet::new();
+    for client_id in 0..options.clients {
+        let options = options.clone();
+        let sampler = sampler.clone();
+        let stats = stats.clone();
+        let delay = options
+            .ramp_up
+            .mul_f64(client_id as f64 / options.clients as f64);
+        let rng = Rng::new(seed ^ client_id as u64);
+
+        clients.spawn(async move {
+            tokio::time::sleep(delay).await;
+            let result =
+                simulate_client(&options, &sampler, &stats, rng, started_at, stop_at).await;
+            if let Err(e) = result {
+                log::debug!("[Client{}] {}", client_id, e);
+            }
+        });
+    }
+
+    let mut totals = Totals::default();
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+    interval.tick().await;
+
+    while Instant::now() < stop_at {
+        interval.tick().await;
+        report(&stats, &mut totals, started_at);
+    }
+
+    clients.shutdown().await;
+    report_totals(&totals, started_at.elapsed());
+
+    Ok(())
+}
+
+async fn simulate_client(
+    options: &Options,
+    sampler: &ChunkSampler,
+    stats: &LoadStats,
+    mut rng: Rng,
+    started_at: Instant,
+    stop_at: Instant,
+) -> PResult<()> {
+    let client = match BitmapClient::connect_to_board(&options.server, &options.board).await {
+        Ok(client) => client,
+        Err(e) => {
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+    };
+    stats.connected.fetch_add(1, Ordering::Relaxed);
+
+    let chunk_index = sampler.sample(&mut rng);
+    let result = drive_client(
+        &client,
+        chunk_index,
+        options,
+        stats,
+        &mut rng,
+        started_at,
+        stop_at,
+    )
+    .await;
+    if result.is_err() {
+        stats.disconnected.fetch_add(1, Ordering::Relaxed);
+    }
+    stats.connected.fetch_sub(1, Ordering::Relaxed);
+
+    result
+}
+
+async fn drive_client(
+    client: &BitmapClient,
+    chunk_index: u16,
+    options: &Options,
+    stats: &LoadStats,
+    rng: &mut Rng,
+    started_at: Instant,
+    stop_at: Instant,
+) -> PResult<()> {
+    let chunk_size = client.geometry().chunk_size;
+    let mut events = client.events();
+    client.subscribe(chunk_index).await?;
+    stats.full_states.fetch_add(1, Ordering::Relaxed);
+
+    // Intervals can't be zero, so disabled timers just never fire before the end of the run.
+    let far_future = stop_at - started_at + Duration::from_secs(1);
+    let mut toggle_timer = tokio::time::interval(if options.toggle_rate > 0.0 {
+        Duration::from_secs_f64(1.0 / options.toggle_rate)
+    } else {
+        far_future
+    });
+    let mut full_state_timer = tokio::time::interval(if options.full_state_interval.is_zero() {
+        far_future
+    } else {
+        options.full_state_interval
+    });
+    let mut ping_timer = tokio::time::interval(PING_INTERVAL);
+    full_state_timer.tick().await;
+
+    // A toggled bit whose update hasn't been seen yet, with its expected value.
+    let mut probe: Option<(u32, bool, Instant)> = None;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(stop_at.into()) => return Ok(()),
+            _ = toggle_timer.tick() => {
+                let offset = (rng.next() % chunk_size as u64) as u32;
+                let index = chunk_index as u32 * chunk_size as u32 + offset;
+
+                if probe.is_none() {
+                    if let Some(value) = client.get(index).await {
+                        probe = Some((index, !value, Instant::now()));
+                    }
+                }
+
+                client.toggle(index).await?;
+                stats.toggles.fetch_add(1, Ordering::Relaxed);
+            }
+            _ = full_state_timer.tick() => {
+                client.request_full_state(chunk_index).await?;
+            }
+            _ = ping_timer.tick() => {
+                client.ping(started_at.elapsed().as_micros() as u64).await?;
+            }
+            event = events.recv() => match event {
+                Ok(ClientEvent::ChunkUpdated { .. }) => {
+                    stats.updates.fetch_add(1, Ordering::Relaxed);
+
+                    if let Some((index, expected, sent_at)) = probe {
+                        if client.get(index).await == Some(expected) {
+                            stats.update_latencies.lock().unwrap().push(sent_at.elapsed());
+                            probe = None;
+                        }
+                    }
+                }
+                Ok(ClientEvent::ChunkLoaded { .. }) => {
+                    stats.full_states.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(ClientEvent::ChunkResync { .. }) | Err(RecvError::Lagged(_)) => {
+                    stats.lag_events.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(ClientEvent::Error { .. }) => {
+                    stats.errors.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(ClientEvent::Pong { payload }) => {
+                    let sent_at = Duration::from_micros(payload);
+                    let latency = started_at.elapsed().saturating_sub(sent_at);
+                    stats.ping_latencies.lock().unwrap().push(latency);
+                }
+                Ok(ClientEvent::Disconnected) | Err(RecvError::Closed) => {
+                    return Err("Disconnected".into());
+                }
+                Ok(ClientEvent::Stats(_))
+                | Ok(ClientEvent::Checksums { .. })
+                | Ok(ClientEvent::LockedRanges { .. }) => {}
+            },
+        }
+    }
+}
+
+fn report(stats: &LoadStats, totals: &mut Totals, started_at: Instant) {
+    let toggles = stats.toggles.swap(0, Ordering::Relaxed);
+    let updates = stats.updates.swap(0, Ordering::Relaxed);
+    let full_states = stats.full_states.swap(0, Ordering::Relaxed);
+    let lag_events = stats.lag_events.swap(0, Ordering::Relaxed);
+    let errors = stats.errors.swap(0, Ordering::Relaxed);
+    let mut ping_latencies = std::mem::take(&mut *stats.ping_latencies.lock().unwrap());
+    let mut update_latencies = std::mem::take(&mut *stats.update_latencies.lock().unwrap());
+
+    println!(
+        "[{:6.1}s] clients: {} (failed: {}, dropped: {}) | toggles/s: {} updates/s: {} \
+        full states/s: {} | lag events: {} errors: {} | rtt p50/p99: {} | update p50/p99: {}",
+        started_at.elapsed().as_secs_f64(),
+        stats.connected.load(Ordering::Relaxed),
+        stats.failed.load(Ordering::Relaxed),
+        stats.disconnected.load(Ordering::Relaxed),
+        toggles,
+        updates,
+        full_states,
+        lag_events,
+        errors,
+        format_percentiles(&mut ping_latencies),
+        format_percentiles(&mut update_latencies),
+    );
+
+    totals.toggles += toggles;
+    totals.updates += updates;
+    totals.full_states += full_states;
+    totals.lag_events += lag_events;
+    totals.errors += errors;
+    totals.ping_latencies.append(&mut ping_latencies);
+    totals.update_latencies.append(&mut update_latencies);
+}
+
+fn report_totals(totals: &Totals, duration: Duration) {
+    let mut ping_latencies = totals.ping_latencies.clone();
+    let mut update_latencies = totals.update_latencies.clone();
+    let seconds = duration.as_secs_f64().max(1.0);
+
+    println!();
+    println!("Summary:");
+    println!(
+        "  toggles:     {} ({:.1}/s)",
+        totals.toggles,
+        totals.toggles as f64 / seconds
+    );
+    println!(
+        "  updates:     {} ({:.1}/s)",
+        totals.updates,
+        totals.updates as f64 / seconds
+    );
+    println!("  full states: {}", totals.full_states);
+    println!("  lag events:  {}", totals.lag_events);
+    println!("  errors:      {}", totals.errors);
+    println!(
+        "  rtt p50/p99:    {}",
+        format_percentiles(&mut ping_latencies)
+    );
+    println!(
+        "  update p50/p99: {}",
+        format_percentiles(&mut update_latencies)
+    );
+}
+
+fn format_percentiles(latencies: &mut [Duration]) -> String {
+    if latencies.is_empty() {
+        return "-".to_string();
+    }
+
+    latencies.sort_unstable();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+
+    format!(
+        "{:.1}/{:.1}ms",
+        percentile(0.5).as_secs_f64() * 1000.0,
+        percentile(0.99).as_secs_f64() * 1000.0
+    )
+}
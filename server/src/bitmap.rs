@@ -1,75 +1,198 @@
 use std::{
     collections::HashMap,
     io::{Read, Write},
+    ops::Range,
+    path::Path,
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
     },
 };
 
-use bitvec::{array::BitArray, order::BitOrder, view::BitViewSized};
+use bitvec::{boxed::BitBox, order::BitOrder, order::Lsb0, store::BitStore, vec::BitVec};
 use tokio::sync::broadcast;
 
-use crate::common::PResult;
+use crate::{
+    common::PResult,
+    protocol::{ChunkFullStateResponseMessage, MAX_MESSAGE_SIZE},
+    toggle_log::ToggleLog,
+};
+
+/// The dimensions of a bitmap. The bitmap is divided into chunks, which are the unit of
+/// subscriptions and full states, and each chunk into update windows, which are the unit of
+/// partial updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitmapGeometry {
+    /// The size of a single chunk in bits
+    pub chunk_size: usize,
+    /// The number of chunks
+    pub chunk_count: usize,
+    /// The size of a single update window in bytes
+    pub update_chunk_size: usize,
+}
+
+impl BitmapGeometry {
+    /// 4096 chunks of 64 * 64 * 64 bits, updated in windows of 32 bytes. Clients that don't
+    /// receive a Board Info message assume this geometry.
+    pub const DEFAULT: Self = Self {
+        chunk_size: 64 * 64 * 64,
+        chunk_count: 64 * 64,
+        update_chunk_size: 32,
+    };
+
+    /// The size of a single chunk in bytes
+    pub const fn chunk_size_bytes(&self) -> usize {
+        self.chunk_size / 8
+    }
+
+    /// The size of the entire bitmap in bits
+    pub const fn bitmap_size(&self) -> usize {
+        self.chunk_size * self.chunk_count
+    }
+
+    /// The size of a single update window in bits
+    pub const fn update_chunk_size_bits(&self) -> usize {
+        self.update_chunk_size * 8
+    }
+
+    /// Checks that chunks and bits can be addressed by the protocol, and that a full state
+    /// fits in a single message.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.update_chunk_size == 0 || self.update_chunk_size > u16::MAX as usize {
+            return Err(format!(
+                "update_chunk_size must be between 1 and {}",
+                u16::MAX
+            ));
+        }
+
+        if self.chunk_size == 0
+            || !self
+                .chunk_size
+                .is_multiple_of(self.update_chunk_size_bits())
+        {
+            return Err("chunk_size must be a non-zero multiple of update_chunk_size * 8".into());
+        }
+
+        let max_chunk_size_bytes =
+            MAX_MESSAGE_SIZE - 1 - size_of::<ChunkFullStateResponseMessage>();
+        if self.chunk_size_bytes() > max_chunk_size_bytes {
+            return Err(format!(
+                "chunk_size must be at most {} bits",
+                max_chunk_size_bytes * 8
+            ));
+        }
 
-// The size of a single chunk in bits
-pub const CHUNK_SIZE: usize = 64 * 64 * 64;
-// The size of a single chunk in bytes
-pub const CHUNK_SIZE_BYTES: usize = CHUNK_SIZE / 8;
-// The number of chunks
-pub const CHUNK_COUNT: usize = 64 * 64;
-// The size of the entire bitmap in bits
-pub const BITMAP_SIZE: usize = CHUNK_SIZE * CHUNK_COUNT;
-// The size of a single update chunk in bytes
-pub const UPDATE_CHUNK_SIZE: usize = 32;
-// The size of a single update chunk in bits
-pub const UPDATE_CHUNK_SIZE_BITS: usize = UPDATE_CHUNK_SIZE * 8;
+        // Chunk indices are 16-bit and bit indices 32-bit.
+        if self.chunk_count == 0 || self.chunk_count > 1 << 16 {
+            return Err("chunk_count must be between 1 and 65536".into());
+        }
 
-type BitmapType = BitArray<[u8; CHUNK_SIZE_BYTES]>;
+        if self.bitmap_size() > 1 << 32 {
+            return Err("chunk_size * chunk_count must be at most 2^32".into());
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for BitmapGeometry {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+type ChunkBits = BitBox<u8, Lsb0>;
 
 pub struct Bitmap {
-    pub data: Box<[BitmapType; CHUNK_COUNT]>,
+    pub data: Box<[ChunkBits]>,
     pub change_tracker: ChangeTracker,
+    geometry: BitmapGeometry,
+    checksums: ChecksumCache,
+    /// Records every flipped bit, if set
+    toggle_log: Option<Arc<ToggleLog>>,
 }
 
 impl Bitmap {
-    pub fn new() -> Self {
-        let data = vec![BitArray::default(); CHUNK_COUNT]
-            .into_boxed_slice()
-            .try_into()
-            .unwrap();
-        let change_tracker = ChangeTracker::new(ChangeTrackerOptions::default());
+    pub fn new(geometry: BitmapGeometry) -> Self {
+        Self::with_options(geometry, ChangeTrackerOptions::default())
+    }
+
+    pub fn with_options(geometry: BitmapGeometry, options: ChangeTrackerOptions) -> Self {
+        let data = (0..geometry.chunk_count)
+            .map(|_| BitVec::repeat(false, geometry.chunk_size).into_boxed_bitslice())
+            .collect();
+        let change_tracker = ChangeTracker::new(geometry, options);
 
         Self {
             data,
             change_tracker,
+            geometry,
+            checksums: ChecksumCache::new(geometry.chunk_count),
+            toggle_log: None,
         }
     }
 
-    pub fn load_from_file(&mut self, path: &str) -> PResult<()> {
+    /// Records every bit flipped from now on in the toggle log.
+    pub fn set_toggle_log(&mut self, toggle_log: Arc<ToggleLog>) {
+        self.toggle_log = Some(toggle_log);
+    }
+
+    /// Loads the bitmap from a file written by `save_to_file`. Fails without modifying the
+    /// bitmap if the size of the file doesn't match the geometry.
+    pub fn load_from_file(&mut self, path: impl AsRef<Path>) -> PResult<()> {
         let file = std::fs::OpenOptions::new().read(true).open(path)?;
+        let expected_size = (self.geometry.chunk_count * self.geometry.chunk_size_bytes()) as u64;
+        let size = file.metadata()?.len();
+        if size != expected_size {
+            return Err(format!(
+                "State file is {} bytes, but the bitmap geometry requires {} bytes",
+                size, expected_size
+            )
+            .into());
+        }
+
         let mut reader = std::io::BufReader::new(file);
         for chunk in self.data.iter_mut() {
-            reader.read_exact(&mut chunk.data)?;
+            reader.read_exact(chunk.as_raw_mut_slice())?;
         }
+        self.checksums.invalidate_all();
 
         Ok(())
     }
 
-    pub fn save_to_file(&self, path: &str) -> PResult<()> {
+    /// Saves the bitmap to a file. The data is written to a temporary file and synced to disk
+    /// before replacing the file, so an interrupted save leaves the previous file intact.
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> PResult<()> {
+        let path = path.as_ref();
+        let temp_path = path.with_extension("tmp");
         let file = std::fs::OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(path)?;
+            .open(&temp_path)?;
         let mut writer = std::io::BufWriter::new(file);
         for chunk in self.data.iter() {
-            writer.write_all(&chunk.data)?;
+            writer.write_all(chunk.as_raw_slice())?;
         }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
 
+        std::fs::rename(temp_path, path)?;
         Ok(())
     }
 
+    /// CRC32 of the data written by `save_to_file`, identifying the state a toggle log
+    /// applies to.
+    pub fn state_checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        for chunk in self.data.iter() {
+            hasher.update(chunk.as_raw_slice());
+        }
+        hasher.finalize()
+    }
+
     pub fn count_ones(&self) -> usize {
         let mut count = 0;
         for chunk in self.data.iter() {
@@ -83,17 +206,32 @@ impl Bitmap {
         self.change_tracker.clear();
     }
 
-    pub fn set(&mut self, index: usize, value: bool) {
+    /// Sets the bit to the given value. The bit is only marked as changed if its value
+    /// actually changed. Returns the change in the number of checked bits.
+    pub fn set(&self, index: usize, value: bool) -> i32 {
         if index >= self.len() {
-            return;
+            return 0;
         }
 
-        let chunk_index = index / CHUNK_SIZE;
-        let bit_index = index % CHUNK_SIZE;
-        let chunk = &mut self.data[chunk_index];
+        let chunk_index = index / self.geometry.chunk_size;
+        let bit_index = index % self.geometry.chunk_size;
+        let curr = assign_bit_atomic(&self.data[chunk_index], bit_index, value);
+
+        if curr == value {
+            return 0;
+        }
 
-        chunk.set(bit_index, value);
+        self.checksums.invalidate(chunk_index);
         self.change_tracker.mark_bit_changed(index);
+        if let Some(toggle_log) = &self.toggle_log {
+            toggle_log.record_flip(index);
+        }
+
+        if curr {
+            -1
+        } else {
+            1
+        }
     }
 
     pub fn toggle(&self, index: usize) -> i32 {
@@ -101,11 +239,15 @@ impl Bitmap {
             return 0;
         }
 
-        let chunk_index = index / CHUNK_SIZE;
-        let bit_index = index % CHUNK_SIZE;
+        let chunk_index = index / self.geometry.chunk_size;
+        let bit_index = index % self.geometry.chunk_size;
         let curr = toggle_bit_atomic(&self.data[chunk_index], bit_index);
 
+        self.checksums.invalidate(chunk_index);
         self.change_tracker.mark_bit_changed(index);
+        if let Some(toggle_log) = &self.toggle_log {
+            toggle_log.record_flip(index);
+        }
 
         if curr {
             -1
@@ -114,163 +256,538 @@ impl Bitmap {
         }
     }
 
+    /// Toggles every bit in `indices` in a single pass, skipping out-of-range indices.
+    /// Returns the change in the number of checked bits and the number of toggled bits.
+    pub fn toggle_many(&self, indices: impl IntoIterator<Item = usize>) -> (i32, u32) {
+        let mut addend = 0;
+        let mut toggled = 0;
+
+        for index in indices {
+            if index >= self.len() {
+                continue;
+            }
+
+            addend += self.toggle(index);
+            toggled += 1;
+        }
+
+        (addend, toggled)
+    }
+
+    /// Sets every bit in the range to the given value, skipping the part of the range past
+    /// the end of the bitmap. Returns the change in the number of checked bits and the number
+    /// of changed bits.
+    pub fn set_range(&mut self, range: Range<usize>, value: bool) -> (i64, u64) {
+        self.modify_range(range, Some(value))
+    }
+
+    /// Toggles every bit in the range, skipping the part of the range past the end of the
+    /// bitmap. Returns the change in the number of checked bits and the number of toggled
+    /// bits.
+    pub fn toggle_range(&mut self, range: Range<usize>) -> (i64, u64) {
+        self.modify_range(range, None)
+    }
+
+    /// Sets the bits in the range to `value`, or toggles them if it's None. Works on whole
+    /// update windows at a time, which are marked as changed without recording their flips.
+    /// The range is recorded in the toggle log as a single record.
+    fn modify_range(&mut self, range: Range<usize>, value: Option<bool>) -> (i64, u64) {
+        let range = range.start..range.end.min(self.len());
+        let chunk_size = self.geometry.chunk_size;
+        let window_size = self.geometry.update_chunk_size_bits();
+        let mut addend = 0;
+        let mut changed = 0;
+
+        let mut window_index = range.start / window_size;
+        while window_index * window_size < range.end {
+            let window_start = window_index * window_size;
+            let start = range.start.max(window_start);
+            let end = range.end.min(window_start + window_size);
+            let chunk_index = start / chunk_size;
+            let chunk_start = chunk_index * chunk_size;
+            let bits = &mut self.data[chunk_index][start - chunk_start..end - chunk_start];
+
+            let ones = bits.count_ones();
+            let differing = match value {
+                Some(true) => bits.len() - ones,
+                Some(false) => ones,
+                None => bits.len(),
+            };
+            window_index += 1;
+            if differing == 0 {
+                continue;
+            }
+
+            addend += match value {
+                Some(value) => {
+                    bits.fill(value);
+                    if value {
+                        differing as i64
+                    } else {
+                        -(differing as i64)
+                    }
+                }
+                None => {
+                    // Inverts the bits in place, a word at a time.
+                    let _ = !bits;
+                    differing as i64 - 2 * ones as i64
+                }
+            };
+            changed += differing as u64;
+            self.checksums.invalidate(chunk_index);
+            self.change_tracker.mark_window_changed(window_index - 1);
+        }
+
+        if let Some(toggle_log) = self.toggle_log.as_ref().filter(|_| changed > 0) {
+            toggle_log.record_range(range, value);
+        }
+
+        (addend, changed)
+    }
+
+    /// Overwrites the bytes of a chunk starting at `byte_offset`. Only the bits that differ
+    /// are marked as changed. Returns the change in the number of checked bits and the number
+    /// of changed bits, or None if the bytes don't fit in the chunk.
+    pub fn write_chunk_bytes(
+        &mut self,
+        chunk_index: usize,
+        byte_offset: usize,
+        bytes: &[u8],
+    ) -> Option<(i64, u64)> {
+        if chunk_index >= self.geometry.chunk_count
+            || byte_offset + bytes.len() > self.geometry.chunk_size_bytes()
+        {
+            return None;
+        }
+
+        let chunk_offset = chunk_index * self.geometry.chunk_size;
+        let mut addend = 0;
+        let mut changed = 0;
+
+        for (i, &byte) in bytes.iter().enumerate() {
+            let byte_index = byte_offset + i;
+            let mut differing = self.as_raw_slice(chunk_index)[byte_index] ^ byte;
+            while differing != 0 {
+                let bit = differing.trailing_zeros() as usize;
+                differing &= differing - 1;
+
+                addend += self.toggle(chunk_offset + byte_index * 8 + bit) as i64;
+                changed += 1;
+            }
+        }
+
+        Some((addend, changed))
+    }
+
     pub fn get(&self, index: usize) -> bool {
         if index >= self.len() {
             return false;
         }
 
-        let chunk_index = index / CHUNK_SIZE;
-        let bit_index = index % CHUNK_SIZE;
+        let chunk_index = index / self.geometry.chunk_size;
+        let bit_index = index % self.geometry.chunk_size;
         let chunk = &self.data[chunk_index];
 
         chunk[bit_index]
     }
 
-    pub const fn len(&self) -> usize {
-        CHUNK_SIZE * CHUNK_COUNT
+    pub fn len(&self) -> usize {
+        self.geometry.bitmap_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn geometry(&self) -> BitmapGeometry {
+        self.geometry
     }
 
-    pub fn as_raw_slice(&self, chunk_index: usize) -> &[u8; CHUNK_SIZE_BYTES] {
-        &self.data[chunk_index].data
+    pub fn as_raw_slice(&self, chunk_index: usize) -> &[u8] {
+        self.data[chunk_index].as_raw_slice()
     }
 
-    pub fn subscribe(&mut self, chunk_index: usize) -> broadcast::Receiver<Change> {
+    pub fn subscribe(&mut self, chunk_index: usize) -> Option<broadcast::Receiver<Change>> {
         self.change_tracker.subscribe_chunk(chunk_index)
     }
+
+    /// Returns the number of ticks during which the chunk was modified.
+    pub fn chunk_version(&self, chunk_index: usize) -> u32 {
+        self.change_tracker.versions[chunk_index]
+    }
+
+    /// Returns the checksum of the chunk data. The checksum is cached until the chunk changes.
+    pub fn chunk_checksum(&self, chunk_index: usize) -> u32 {
+        self.checksums
+            .get_or_compute(chunk_index, self.data[chunk_index].as_raw_slice())
+    }
 }
 
-pub struct ChangeData {
+/// Computes the checksum of chunk data, as sent in Chunk Checksum Response messages.
+pub fn chunk_checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// Checksums of the chunks, kept until the chunk is modified.
+struct ChecksumCache {
+    /// Incremented every time a bit of the chunk changes
+    generations: Box<[AtomicU32]>,
+    /// The generation the checksum was computed at in the upper 32 bits, and the checksum in
+    /// the lower 32 bits
+    checksums: Box<[AtomicU64]>,
+}
+
+impl ChecksumCache {
+    /// Generation of empty entries. A chunk only reaches it after 2^32 - 1 changes.
+    const EMPTY_GENERATION: u32 = u32::MAX;
+
+    fn new(chunk_count: usize) -> Self {
+        let empty = (Self::EMPTY_GENERATION as u64) << 32;
+        Self {
+            generations: (0..chunk_count).map(|_| AtomicU32::new(0)).collect(),
+            checksums: (0..chunk_count).map(|_| AtomicU64::new(empty)).collect(),
+        }
+    }
+
+    fn invalidate(&self, chunk_index: usize) {
+        // Released after the bit was written, so a checksum computed at the new generation
+        // includes the change.
+        self.generations[chunk_index].fetch_add(1, Ordering::Release);
+    }
+
+    fn invalidate_all(&self) {
+        for generation in self.generations.iter() {
+            generation.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    fn get_or_compute(&self, chunk_index: usize, data: &[u8]) -> u32 {
+        let generation = self.generations[chunk_index].load(Ordering::Acquire);
+        let cached = self.checksums[chunk_index].load(Ordering::Relaxed);
+        if (cached >> 32) as u32 == generation {
+            return cached as u32;
+        }
+
+        // If the chunk changes while the checksum is computed, the stored generation is already
+        // outdated and the checksum is computed again on the next call.
+        let checksum = chunk_checksum(data);
+        let entry = ((generation as u64) << 32) | checksum as u64;
+        self.checksums[chunk_index].store(entry, Ordering::Relaxed);
+        checksum
+    }
+}
+
+pub struct UpdateWindow {
     /// The offset in global byte array (chunk_index * chunk_size)
     pub byte_array_offset: u32,
     /// The changed chunk data
-    pub chunk_data: [u8; UPDATE_CHUNK_SIZE],
+    pub chunk_data: Box<[u8]>,
+}
+
+/// All changes made to a single chunk during a tick.
+pub struct ChangeData {
+    pub chunk_index: u32,
+    /// The version of the chunk after applying the change
+    pub version: u32,
+    /// The modified update windows of the chunk
+    pub windows: Vec<UpdateWindow>,
+    /// The bits whose value changed during the tick, as (offset within chunk, new value) pairs.
+    /// Only present if sending them is cheaper than sending the windows.
+    pub bits: Option<Vec<(u32, bool)>>,
+}
+
+impl ChangeData {
+    /// Approximate wire size of the windows, including the message type and offset of each window.
+    fn windows_size(&self) -> usize {
+        self.windows
+            .iter()
+            .map(|window| 1 + size_of::<u32>() + window.chunk_data.len())
+            .sum()
+    }
+
+    /// Approximate wire size of the changed bits, including the message type and chunk index.
+    fn bits_size(&self) -> Option<usize> {
+        self.bits
+            .as_ref()
+            .map(|bits| 1 + size_of::<u16>() + bits.len() * size_of::<u32>())
+    }
 }
 
 pub type Change = Arc<ChangeData>;
 
-const CHANGE_MASK_SIZE: usize =
-    (CHUNK_SIZE * CHUNK_COUNT) / (UPDATE_CHUNK_SIZE * size_of::<usize>());
+/// The bits flipped in a single chunk during a tick.
+#[derive(Default)]
+pub struct ChunkFlips {
+    /// Indices of the flipped bits in the global bitmap, once per flip
+    pub indices: Vec<u32>,
+    /// Set if not every flip was recorded, in which case `indices` is empty
+    pub overflowed: bool,
+}
 
 pub struct ChangeTrackerOptions {
     /// The maximum number of changes that can be stored in the backlog for each receiver.
+    /// Each change holds the updates of a single chunk over a single tick.
     pub backlog_capacity: usize,
+    /// The maximum number of bit flips recorded in a single chunk during a single tick. If
+    /// there are more flips, only the modified update windows of the chunk are sent for that
+    /// tick.
+    pub max_tracked_flips: usize,
 }
 
 impl Default for ChangeTrackerOptions {
     fn default() -> Self {
         Self {
             backlog_capacity: 128,
+            max_tracked_flips: 65536,
         }
     }
 }
 
 /// Tracks changes to a bitmap.
-/// The bitmap is divided into chunks of CHUNK_SIZE bytes.
-/// The change_mask stores a boolean for each chunk, indicating whether the chunk has been modified.
+/// The bitmap is divided into update windows of `update_chunk_size` bytes.
+/// The change_mask stores a boolean for each window, indicating whether the window has been modified.
 /// The clients only receive the chunks that have been modified.
+/// Additionally, the indices of flipped bits in subscribed chunks are recorded, so the exact set
+/// of changed bits can be sent instead of whole windows when that's cheaper. Each chunk records
+/// its flips separately, so toggles in different chunks don't contend for a lock.
+/// Each chunk has a version, which is incremented at the end of every tick it was modified in.
 pub struct ChangeTracker {
-    pub change_mask: Box<BitArray<[usize; CHANGE_MASK_SIZE]>>,
+    pub change_mask: BitBox<usize, Lsb0>,
+    pub versions: Vec<u32>,
+    pub flips: Box<[Mutex<ChunkFlips>]>,
     pub senders: HashMap<u32, broadcast::Sender<Change>>,
     pub options: ChangeTrackerOptions,
+    pub geometry: BitmapGeometry,
 }
 
 impl ChangeTracker {
-    pub fn new(options: ChangeTrackerOptions) -> Self {
-        let change_mask: Box<[usize; CHANGE_MASK_SIZE]> = vec![0usize; CHANGE_MASK_SIZE]
-            .into_boxed_slice()
-            .try_into()
-            .unwrap();
-        // Safety: BitArray<T> is #[repr(transparent)]
-        let change_mask = unsafe { std::mem::transmute(change_mask) };
+    pub fn new(geometry: BitmapGeometry, options: ChangeTrackerOptions) -> Self {
+        let window_count = geometry.bitmap_size() / geometry.update_chunk_size_bits();
+        let change_mask = BitVec::repeat(false, window_count).into_boxed_bitslice();
 
         Self {
             change_mask,
+            versions: vec![0; geometry.chunk_count],
+            flips: (0..geometry.chunk_count)
+                .map(|_| Mutex::default())
+                .collect(),
             senders: HashMap::new(),
             options,
+            geometry,
         }
     }
 
+    /// Marks the bit as flipped. Must be called exactly once per flip.
     pub fn mark_bit_changed(&self, bit_index: usize) {
-        set_bit_atomic(&self.change_mask, bit_index / UPDATE_CHUNK_SIZE_BITS);
+        let window_index = bit_index / self.geometry.update_chunk_size_bits();
+        set_bit_atomic(&self.change_mask, window_index);
+
+        // Changes of chunks nobody is subscribed to are never sent.
+        let chunk_index = bit_index / self.geometry.chunk_size;
+        if !self.has_subscribers(chunk_index) {
+            return;
+        }
+
+        let mut flips = self.flips[chunk_index].lock().unwrap();
+        if flips.overflowed {
+            return;
+        }
+        if flips.indices.len() >= self.options.max_tracked_flips {
+            flips.overflowed = true;
+            flips.indices.clear();
+            return;
+        }
+        flips.indices.push(bit_index as u32);
+    }
+
+    /// Marks a whole update window as changed. The flips of its chunk aren't recorded for
+    /// this tick, so subscribers receive the changed windows instead.
+    pub fn mark_window_changed(&mut self, window_index: usize) {
+        self.change_mask.set(window_index, true);
+
+        let chunk_index =
+            window_index * self.geometry.update_chunk_size_bits() / self.geometry.chunk_size;
+        let flips = self.flips[chunk_index].get_mut().unwrap();
+        flips.overflowed = true;
+        flips.indices.clear();
     }
 
     pub fn clear(&mut self) {
         self.change_mask.fill(false);
+        for flips in self.flips.iter_mut() {
+            let flips = flips.get_mut().unwrap();
+            flips.indices.clear();
+            flips.overflowed = false;
+        }
+    }
+
+    fn has_subscribers(&self, chunk_index: usize) -> bool {
+        self.senders
+            .get(&(chunk_index as u32))
+            .is_some_and(|sender| sender.receiver_count() > 0)
     }
 
-    pub fn subscribe_chunk(&mut self, chunk_index: usize) -> broadcast::Receiver<Change> {
+    /// Returns a receiver for changes to the chunk, or None if the chunk doesn't exist.
+    pub fn subscribe_chunk(&mut self, chunk_index: usize) -> Option<broadcast::Receiver<Change>> {
+        if chunk_index >= self.geometry.chunk_count {
+            return None;
+        }
+
+        // Flips made earlier in this tick weren't recorded, so the windows are sent instead.
+        let windows_per_chunk = self.geometry.chunk_size / self.geometry.update_chunk_size_bits();
+        let windows = chunk_index * windows_per_chunk..(chunk_index + 1) * windows_per_chunk;
+        if !self.has_subscribers(chunk_index) && self.change_mask[windows].any() {
+            self.flips[chunk_index].get_mut().unwrap().overflowed = true;
+        }
+
         let chunk_index = chunk_index as u32;
         if let Some(sender) = self.senders.get(&chunk_index) {
-            return sender.subscribe();
+            return Some(sender.subscribe());
         }
 
         let (sender, receiver) = broadcast::channel(self.options.backlog_capacity);
         self.senders.insert(chunk_index, sender);
 
-        receiver
+        Some(receiver)
     }
 
-    pub fn send_changes(&self, chunks: &[BitmapType; CHUNK_COUNT]) {
-        for i in self.change_mask.iter_ones() {
-            let offset_in_bits = i * UPDATE_CHUNK_SIZE_BITS;
-            let chunk_index = (offset_in_bits / CHUNK_SIZE) as u32;
-            let offset_within_chunk = offset_in_bits % CHUNK_SIZE;
+    pub fn send_changes(&mut self, chunks: &[ChunkBits]) {
+        let mut changes: HashMap<u32, ChangeData> = HashMap::new();
+        let mut last_chunk_index = None;
+        let chunk_size = self.geometry.chunk_size;
 
-            let sender = if let Some(sender) = self.senders.get(&chunk_index) {
-                sender
-            } else {
+        for i in self.change_mask.iter_ones() {
+            let offset_in_bits = i * self.geometry.update_chunk_size_bits();
+            let chunk_index = (offset_in_bits / chunk_size) as u32;
+            let offset_within_chunk = offset_in_bits % chunk_size;
+
+            // The windows are visited in order, so each modified chunk is seen in a single run.
+            if last_chunk_index != Some(chunk_index) {
+                let version = &mut self.versions[chunk_index as usize];
+                *version = version.wrapping_add(1);
+                last_chunk_index = Some(chunk_index);
+            }
+
+            if !self.senders.contains_key(&chunk_index) {
                 continue;
-            };
+            }
 
             let data = &chunks[chunk_index as usize];
             let byte_offset = offset_within_chunk / 8;
-            let range = byte_offset..byte_offset + UPDATE_CHUNK_SIZE;
-
-            let chunk_data = data.as_raw_slice()[range].try_into();
-            let chunk_data = if let Ok(chunk_data) = chunk_data {
-                chunk_data
-            } else {
-                debug_assert!(false, "Failed to convert slice to array");
-                break;
-            };
+            let range = byte_offset..byte_offset + self.geometry.update_chunk_size;
+            let chunk_data = data.as_raw_slice()[range].into();
 
-            let change_data = ChangeData {
+            let window = UpdateWindow {
                 byte_array_offset: (offset_in_bits / 8) as u32,
                 chunk_data,
             };
-            let change = Arc::new(change_data);
-            let _ = sender.send(change);
+            changes
+                .entry(chunk_index)
+                .or_insert_with(|| ChangeData {
+                    chunk_index,
+                    version: self.versions[chunk_index as usize],
+                    windows: Vec::new(),
+                    bits: None,
+                })
+                .windows
+                .push(window);
+        }
+
+        self.collect_changed_bits(chunks, &mut changes);
+
+        for (chunk_index, change_data) in changes {
+            if let Some(sender) = self.senders.get(&chunk_index) {
+                let _ = sender.send(Arc::new(change_data));
+            }
+        }
+    }
+
+    /// Fills in the changed bits of every change, using the flips recorded during the tick.
+    /// Bits flipped an even number of times didn't change and are left out.
+    fn collect_changed_bits(&self, chunks: &[ChunkBits], changes: &mut HashMap<u32, ChangeData>) {
+        for (&chunk_index, change_data) in changes.iter_mut() {
+            let mut flips = self.flips[chunk_index as usize].lock().unwrap();
+            if flips.overflowed {
+                continue;
+            }
+            flips.indices.sort_unstable();
+
+            let mut bits = Vec::new();
+            for run in flips.indices.chunk_by(|a, b| a == b) {
+                if run.len().is_multiple_of(2) {
+                    continue;
+                }
+
+                let offset_within_chunk = run[0] as usize % self.geometry.chunk_size;
+                let value = chunks[chunk_index as usize][offset_within_chunk];
+                bits.push((offset_within_chunk as u32, value));
+            }
+
+            change_data.bits = Some(bits);
+            if change_data.bits_size() >= Some(change_data.windows_size()) {
+                change_data.bits = None;
+            }
         }
     }
 }
 
-fn set_bit_atomic<S, O>(bit_slice: &BitArray<S, O>, index: usize)
+/// Returns the byte holding the bit at `index` and the mask of the bit within it.
+///
+/// Bits are numbered from the least significant bit of each store element, so the byte within
+/// the element depends on the target endianness.
+fn atomic_bit<T, O>(bit_slice: &BitBox<T, O>, index: usize) -> (&AtomicU8, u8)
 where
-    S: BitViewSized,
+    T: BitStore,
     O: BitOrder,
 {
+    let store_size = size_of::<T>();
+    let element = index / (store_size * 8);
+    let bit = index % (store_size * 8);
+    let byte_in_element = if cfg!(target_endian = "little") {
+        bit / 8
+    } else {
+        store_size - 1 - bit / 8
+    };
+
     let slice = bit_slice.as_raw_slice();
+    let byte_index = element * store_size + byte_in_element;
+    assert!(byte_index < size_of_val(slice));
 
     unsafe {
-        let ptr = slice.as_ptr().add(index / (size_of::<S::Store>() * 8)) as *mut usize;
-        let mask = 1 << (index % (size_of::<S::Store>() * 8));
-
-        AtomicUsize::from_ptr(ptr).fetch_or(mask, Ordering::Relaxed);
+        let ptr = (slice.as_ptr() as *mut u8).add(byte_index);
+        (AtomicU8::from_ptr(ptr), 1 << (bit % 8))
     }
 }
 
-fn toggle_bit_atomic<S, O>(bit_slice: &BitArray<S, O>, index: usize) -> bool
+fn set_bit_atomic<T, O>(bit_slice: &BitBox<T, O>, index: usize)
 where
-    S: BitViewSized,
+    T: BitStore,
     O: BitOrder,
 {
-    let slice = bit_slice.as_raw_slice();
+    let (byte, mask) = atomic_bit(bit_slice, index);
+    byte.fetch_or(mask, Ordering::Relaxed);
+}
 
-    unsafe {
-        let ptr = slice.as_ptr().add(index / (size_of::<S::Store>() * 8)) as *mut usize;
-        let mask = 1 << (index % (size_of::<S::Store>() * 8));
+fn assign_bit_atomic<T, O>(bit_slice: &BitBox<T, O>, index: usize, value: bool) -> bool
+where
+    T: BitStore,
+    O: BitOrder,
+{
+    let (byte, mask) = atomic_bit(bit_slice, index);
+    let old = if value {
+        byte.fetch_or(mask, Ordering::Relaxed)
+    } else {
+        byte.fetch_and(!mask, Ordering::Relaxed)
+    };
+    old & mask != 0
+}
 
-        let old = AtomicUsize::from_ptr(ptr).fetch_xor(mask, Ordering::Relaxed);
-        old & mask != 0
-    }
+fn toggle_bit_atomic<T, O>(bit_slice: &BitBox<T, O>, index: usize) -> bool
+where
+    T: BitStore,
+    O: BitOrder,
+{
+    let (byte, mask) = atomic_bit(bit_slice, index);
+    let old = byte.fetch_xor(mask, Ordering::Relaxed);
+    old & mask != 0
 }
@@ -0,0 +1,789 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use bitvec::{order::Lsb0, slice::BitSlice};
+use soketto::{
+    connection::{Receiver, Sender},
+    handshake::{Client, ServerResponse},
+};
+use tokio::{
+    net::TcpStream,
+    sync::{broadcast, Mutex, RwLock},
+    task::JoinHandle,
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+use crate::{
+    bitmap::{self, BitmapGeometry},
+    common::PResult,
+    encoding::{self, ChunkEncoding},
+    protocol::{
+        ErrorCode, Message, MessageMut, MessageType, StatsMessage, CAPABILITY_CHUNK_VERSIONS,
+        CAPABILITY_COMPRESSED_FULL_STATE, CAPABILITY_LOCKED_RANGES, CAPABILITY_SPARSE_UPDATES,
+        MAX_MESSAGE_SIZE, PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR, SPARSE_UPDATE_VALUE_BIT,
+    },
+};
+
+/// Capabilities requested by the client. All of them are handled transparently.
+const CLIENT_CAPABILITIES: u32 = CAPABILITY_COMPRESSED_FULL_STATE
+    | CAPABILITY_SPARSE_UPDATES
+    | CAPABILITY_CHUNK_VERSIONS
+    | CAPABILITY_LOCKED_RANGES;
+
+/// The first minor version supporting the ClientHello message.
+const CLIENT_HELLO_MIN_VERSION_MINOR: u16 = 7;
+
+/// The first minor version supporting the chunk checksum messages.
+const CHECKSUM_MIN_VERSION_MINOR: u16 = 12;
+
+/// The first minor version in which the server sends the full state of chunks it dropped
+/// updates for, without being asked.
+const PUSHED_RESYNC_MIN_VERSION_MINOR: u16 = 13;
+
+/// The first minor version in which the server sends a Board Info message after the
+/// Client Hello Ack. Older servers always use the default geometry.
+const BOARD_INFO_MIN_VERSION_MINOR: u16 = 14;
+
+/// The maximum number of events buffered for each event receiver.
+const EVENT_BACKLOG_CAPACITY: usize = 256;
+
+type ClientSender = Sender<Compat<TcpStream>>;
+type ClientReceiver = Receiver<Compat<TcpStream>>;
+
+#[derive(Debug, Clone)]
+pub enum ClientError {
+    /// The server didn't accept the WebSocket handshake.
+    HandshakeRejected { status_code: u16 },
+    /// The server speaks an incompatible protocol version.
+    UnsupportedVersion { major: u16, minor: u16 },
+    /// The server sent a message that wasn't expected at this point.
+    UnexpectedMessage,
+    /// The server rejected a request.
+    Server { code: ErrorCode, context: u32 },
+    /// The connection was closed.
+    Disconnected,
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::HandshakeRejected { status_code } => {
+                write!(f, "Handshake rejected with status {}", status_code)
+            }
+            ClientError::UnsupportedVersion { major, minor } => {
+                write!(f, "Unsupported server version {}.{}", major, minor)
+            }
+            ClientError::UnexpectedMessage => write!(f, "Unexpected message"),
+            ClientError::Server { code, context } => {
+                write!(f, "Request rejected: {:?} ({})", code, context)
+            }
+            ClientError::Disconnected => write!(f, "Disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Something that happened on the connection, delivered to every event receiver.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// The full state of a subscribed chunk was received.
+    ChunkLoaded {
+        chunk_index: u16,
+    },
+    /// A partial update was applied to a subscribed chunk.
+    ChunkUpdated {
+        chunk_index: u16,
+    },
+    /// The mirror of the chunk fell out of sync, and its full state was requested again or is
+    /// being sent by the server.
+    ChunkResync {
+        chunk_index: u16,
+    },
+    /// The read-only ranges of a subscribed chunk were received.
+    LockedRanges {
+        chunk_index: u16,
+    },
+    Stats(StatsMessage),
+    /// The server rejected a request.
+    Error {
+        code: ErrorCode,
+        request_type: u8,
+        context: u32,
+    },
+    /// The server sent the checksums requested with `BitmapClient::checksums`.
+    Checksums {
+        /// (chunk index, checksum) pairs, in the order they were requested
+        checksums: Vec<(u16, u32)>,
+        /// Loaded chunks whose local copy didn't match the checksum when it was received
+        mismatched: Vec<u16>,
+    },
+    /// The server answered a ping sent with `BitmapClient::ping`.
+    Pong {
+        payload: u64,
+    },
+    /// The connection was closed. No more events will be received.
+    Disconnected,
+}
+
+/// The local copy of a subscribed chunk.
+struct MirroredChunk {
+    data: Box<[u8]>,
+    /// Whether the full state of the chunk has been received
+    loaded: bool,
+    /// The last version received from the server, if chunk versions are enabled
+    version: Option<u32>,
+    /// Ranges of bits that can't be modified, relative to the start of the chunk
+    locked: Vec<Range<u32>>,
+}
+
+impl MirroredChunk {
+    fn new(geometry: &BitmapGeometry) -> Self {
+        Self {
+            data: vec![0u8; geometry.chunk_size_bytes()].into_boxed_slice(),
+            loaded: false,
+            version: None,
+            locked: Vec::new(),
+        }
+    }
+}
+
+/// State shared between the client handle and the receive task.
+struct ClientShared {
+    sender: Mutex<ClientSender>,
+    chunks: RwLock<HashMap<u16, MirroredChunk>>,
+    events: broadcast::Sender<ClientEvent>,
+    /// Capabilities accepted by the server
+    capabilities: AtomicU32,
+}
+
+/// State of the receive task that isn't needed anywhere else.
+#[derive(Default)]
+struct ReceiveState {
+    /// The geometry of the bitmap announced by the server
+    geometry: BitmapGeometry,
+    /// The chunk whose full state was received last, if it may still be followed by its version
+    last_full_state: Option<u16>,
+    /// Whether the server follows Chunk Resync messages with the full state of the chunk
+    server_pushes_resync: bool,
+}
+
+/// A client for the bitmap protocol.
+///
+/// Keeps a local mirror of every subscribed chunk, kept up to date with the partial updates
+/// sent by the server. If the server supports chunk versions, chunks that fall out of sync are
+/// requested again automatically.
+pub struct BitmapClient {
+    shared: Arc<ClientShared>,
+    server_version: (u16, u16),
+    geometry: BitmapGeometry,
+    recv_task: JoinHandle<PResult<()>>,
+}
+
+impl BitmapClient {
+    /// Connects to the default board of the server at `address` (host:port) and negotiates
+    /// the optional features.
+    pub async fn connect(address: &str) -> PResult<Self> {
+        Self::connect_to_board(address, "").await
+    }
+
+    /// Connects to the named board of the server at `address`, or to the default board if
+    /// `board` is empty.
+    pub async fn connect_to_board(address: &str, board: &str) -> PResult<Self> {
+        let socket = TcpStream::connect(address).await?;
+        socket.set_nodelay(true)?;
+        let path = format!("/{}", board);
+        let mut client = Client::new(socket.compat(), address, &path);
+
+        match client.handshake().await? {
+            ServerResponse::Accepted { .. } => {}
+            ServerResponse::Redirect { status_code, .. }
+            | ServerResponse::Rejected { status_code } => {
+                return Err(ClientError::HandshakeRejected { status_code }.into());
+            }
+        }
+
+        let mut builder = client.into_builder();
+        builder.set_max_message_size(MAX_MESSAGE_SIZE);
+        let (sender, mut receiver) = builder.finish();
+
+        let mut recv_data = Vec::new();
+        receiver.receive_data(&mut recv_data).await?;
+        let server_version = match Message::from_slice(&recv_data)? {
+            Message::Hello(hello) => (hello.version_major.get(), hello.version_minor.get()),
+            _ => return Err(ClientError::UnexpectedMessage.into()),
+        };
+
+        if server_version.0 != PROTOCOL_VERSION_MAJOR {
+            let (major, minor) = server_version;
+            return Err(ClientError::UnsupportedVersion { major, minor }.into());
+        }
+
+        let (events, _) = broadcast::channel(EVENT_BACKLOG_CAPACITY);
+        let shared = Arc::new(ClientShared {
+            sender: Mutex::new(sender),
+            chunks: RwLock::new(HashMap::new()),
+            events,
+            capabilities: AtomicU32::new(0),
+        });
+
+        let mut state = ReceiveState {
+            server_pushes_resync: server_version.1 >= PUSHED_RESYNC_MIN_VERSION_MINOR,
+            ..Default::default()
+        };
+        if server_version.1 >= CLIENT_HELLO_MIN_VERSION_MINOR {
+            let mut send_data = Vec::new();
+            let hello = MessageMut::create_message(MessageType::ClientHello, &mut send_data)?;
+            if let MessageMut::ClientHello(hello) = hello {
+                hello.version_major.set(PROTOCOL_VERSION_MAJOR);
+                hello.version_minor.set(PROTOCOL_VERSION_MINOR);
+                hello.capabilities.set(CLIENT_CAPABILITIES);
+            }
+            shared.sender.lock().await.send_binary(&send_data).await?;
+
+            // Other messages may arrive before the acknowledgement.
+            let mut acknowledged = false;
+            let mut board_info_pending = server_version.1 >= BOARD_INFO_MIN_VERSION_MINOR;
+            while !acknowledged || board_info_pending {
+                recv_data.clear();
+                if !receiver.receive_data(&mut recv_data).await?.is_binary() {
+                    continue;
+                }
+
+                match Message::from_slice(&recv_data)? {
+                    Message::ClientHelloAck(ack) => {
+                        shared
+                            .capabilities
+                            .store(ack.capabilities.get(), Ordering::Relaxed);
+                        acknowledged = true;
+                    }
+                    Message::BoardInfo(info) => {
+                        state.geometry = BitmapGeometry {
+                            chunk_size: info.chunk_size.get() as usize,
+                            chunk_count: info.chunk_count.get() as usize,
+                            update_chunk_size: info.update_chunk_size.get() as usize,
+                        };
+                        state
+                            .geometry
+                            .validate()
+                            .map_err(|_| ClientError::UnexpectedMessage)?;
+                        board_info_pending = false;
+                    }
+                    message => Self::handle_message(&shared, &mut state, message).await?,
+                }
+            }
+        }
+
+        let geometry = state.geometry;
+
+        let recv_task = tokio::spawn(Self::receive_task(shared.clone(), receiver, state));
+
+        Ok(Self {
+            shared,
+            server_version,
+            geometry,
+            recv_task,
+        })
+    }
+
+    /// The protocol version of the server, as (major, minor).
+    pub fn server_version(&self) -> (u16, u16) {
+        self.server_version
+    }
+
+    /// The geometry of the bitmap on the server.
+    pub fn geometry(&self) -> BitmapGeometry {
+        self.geometry
+    }
+
+    /// Bitwise OR of the CAPABILITY_* values enabled for the connection.
+    pub fn capabilities(&self) -> u32 {
+        self.shared.capabilities.load(Ordering::Relaxed)
+    }
+
+    /// Returns a receiver for events that happen from now on.
+    pub fn events(&self) -> broadcast::Receiver<ClientEvent> {
+        self.shared.events.subscribe()
+    }
+
+    pub async fn toggle(&self, index: u32) -> PResult<()> {
+        let mut send_data = Vec::new();
+        let toggle = MessageMut::create_message(MessageType::ToggleBit, &mut send_data)?;
+        if let MessageMut::ToggleBit(toggle) = toggle {
+            toggle.index.set(index);
+        }
+
+        self.send_raw(&send_data).await
+    }
+
+    /// Toggles every bit in `indices` with a single message.
+    pub async fn toggle_many(&self, indices: &[u32]) -> PResult<()> {
+        let mut send_data = Vec::new();
+        let toggles = MessageMut::create_variable_message(
+            MessageType::ToggleBits,
+            size_of_val(indices),
+            &mut send_data,
+        )?;
+        if let MessageMut::ToggleBits(toggles) = toggles {
+            for (toggle, &index) in toggles.iter_mut().zip(indices) {
+                toggle.index.set(index);
+            }
+        }
+
+        self.send_raw(&send_data).await
+    }
+
+    pub async fn set(&self, index: u32, value: bool) -> PResult<()> {
+        let mut send_data = Vec::new();
+        let set = MessageMut::create_message(MessageType::SetBit, &mut send_data)?;
+        if let MessageMut::SetBit(set) = set {
+            set.index.set(index);
+            set.value = value as u8;
+        }
+
+        self.send_raw(&send_data).await
+    }
+
+    /// Subscribes to the chunk and waits until its full state has been received.
+    pub async fn subscribe(&self, chunk_index: u16) -> PResult<()> {
+        let mut events = self.events();
+        self.shared
+            .chunks
+            .write()
+            .await
+            .entry(chunk_index)
+            .or_insert_with(|| MirroredChunk::new(&self.geometry));
+
+        let mut send_data = Vec::new();
+        let subscription =
+            MessageMut::create_message(MessageType::AddPartialStateSubscription, &mut send_data)?;
+        if let MessageMut::AddPartialStateSubscription(subscription) = subscription {
+            subscription.chunk_index.set(chunk_index);
+        }
+        self.send_raw(&send_data).await?;
+        self.request_full_state(chunk_index).await?;
+
+        loop {
+            match events.recv().await {
+                Ok(ClientEvent::ChunkLoaded {
+                    chunk_index: loaded,
+                }) if loaded == chunk_index => {
+                    return Ok(());
+                }
+                Ok(ClientEvent::Error {
+                    code,
+                    request_type,
+                    context,
+                }) if request_type == MessageType::AddPartialStateSubscription as u8 => {
+                    self.shared.chunks.write().await.remove(&chunk_index);
+                    return Err(ClientError::Server { code, context }.into());
+                }
+                Ok(ClientEvent::Disconnected) | Err(broadcast::error::RecvError::Closed) => {
+                    return Err(ClientError::Disconnected.into());
+                }
+                // The ChunkLoaded event may have been among the dropped ones.
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let chunks = self.shared.chunks.read().await;
+                    if chunks.get(&chunk_index).is_some_and(|chunk| chunk.loaded) {
+                        return Ok(());
+                    }
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+
+    pub async fn unsubscribe(&self, chunk_index: u16) -> PResult<()> {
+        self.shared.chunks.write().await.remove(&chunk_index);
+
+        let mut send_data = Vec::new();
+        let subscription = MessageMut::create_message(
+            MessageType::RemovePartialStateSubscription,
+            &mut send_data,
+        )?;
+        if let MessageMut::RemovePartialStateSubscription(subscription) = subscription {
+            subscription.chunk_index.set(chunk_index);
+        }
+
+        self.send_raw(&send_data).await
+    }
+
+    /// Sends a ping. The server answers with a pong carrying the same payload.
+    pub async fn ping(&self, payload: u64) -> PResult<()> {
+        let mut send_data = Vec::new();
+        let ping = MessageMut::create_message(MessageType::Ping, &mut send_data)?;
+        if let MessageMut::Ping(ping) = ping {
+            ping.payload.set(payload);
+        }
+
+        self.send_raw(&send_data).await
+    }
+
+    /// Requests the checksums of the chunks, or of every chunk if `chunk_indices` is empty,
+    /// and waits for the response.
+    pub async fn checksums(&self, chunk_indices: &[u16]) -> PResult<Vec<(u16, u32)>> {
+        let (checksums, _) = self.request_checksums(chunk_indices).await?;
+        Ok(checksums)
+    }
+
+    /// Compares every loaded chunk with the checksum sent by the server, and requests the
+    /// chunks that don't match again. Returns the indices of the mismatched chunks.
+    ///
+    /// The server may include changes it hasn't sent yet, so a chunk that is being modified
+    /// can be reported even if no update was lost.
+    pub async fn verify(&self) -> PResult<Vec<u16>> {
+        let chunk_indices: Vec<u16> = {
+            let chunks = self.shared.chunks.read().await;
+            let mut loaded: Vec<u16> = chunks
+                .iter()
+                .filter(|(_, chunk)| chunk.loaded)
+                .map(|(&chunk_index, _)| chunk_index)
+                .collect();
+            loaded.sort_unstable();
+            loaded
+        };
+        if chunk_indices.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let (_, mismatched) = self.request_checksums(&chunk_indices).await?;
+        for &chunk_index in &mismatched {
+            Self::resync_chunk(&self.shared, &self.geometry, chunk_index).await?;
+        }
+
+        Ok(mismatched)
+    }
+
+    /// Requests the full state of the chunk, without waiting for the response.
+    pub async fn request_full_state(&self, chunk_index: u16) -> PResult<()> {
+        Self::send_full_state_request(&self.shared, chunk_index).await
+    }
+
+    /// Returns the value of the bit, or None if its chunk isn't subscribed and loaded.
+    pub async fn get(&self, index: u32) -> Option<bool> {
+        let chunk_index = index as usize / self.geometry.chunk_size;
+        let bit_index = index as usize % self.geometry.chunk_size;
+
+        let chunks = self.shared.chunks.read().await;
+        let chunk = chunks.get(&(chunk_index as u16)).filter(|c| c.loaded)?;
+        Some(BitSlice::<u8, Lsb0>::from_slice(&chunk.data[..])[bit_index])
+    }
+
+    /// Returns a copy of the chunk, or None if it isn't subscribed and loaded.
+    pub async fn chunk(&self, chunk_index: u16) -> Option<Box<[u8]>> {
+        let chunks = self.shared.chunks.read().await;
+        let chunk = chunks.get(&chunk_index).filter(|c| c.loaded)?;
+        Some(chunk.data.clone())
+    }
+
+    /// Returns the read-only ranges of a subscribed chunk, relative to the start of the chunk.
+    /// Empty if nothing is locked, or the server doesn't support locked ranges.
+    pub async fn locked_ranges(&self, chunk_index: u16) -> Vec<Range<u32>> {
+        let chunks = self.shared.chunks.read().await;
+        chunks
+            .get(&chunk_index)
+            .map(|chunk| chunk.locked.clone())
+            .unwrap_or_default()
+    }
+
+    /// Sends an already serialized message.
+    pub async fn send_raw(&self, data: &[u8]) -> PResult<()> {
+        self.shared.sender.lock().await.send_binary(data).await?;
+        Ok(())
+    }
+
+    pub async fn close(self) -> PResult<()> {
+        self.shared.sender.lock().await.close().await?;
+        Ok(())
+    }
+
+    async fn request_checksums(
+        &self,
+        chunk_indices: &[u16],
+    ) -> PResult<(Vec<(u16, u32)>, Vec<u16>)> {
+        if self.server_version.1 < CHECKSUM_MIN_VERSION_MINOR {
+            let (major, minor) = self.server_version;
+            return Err(ClientError::UnsupportedVersion { major, minor }.into());
+        }
+
+        let mut events = self.events();
+        let mut send_data = Vec::new();
+        let request = MessageMut::create_variable_message(
+            MessageType::ChunkChecksumRequest,
+            size_of_val(chunk_indices),
+            &mut send_data,
+        )?;
+        if let MessageMut::ChunkChecksumRequest(request) = request {
+            for (entry, &chunk_index) in request.iter_mut().zip(chunk_indices) {
+                entry.chunk_index.set(chunk_index);
+            }
+        }
+        self.send_raw(&send_data).await?;
+
+        loop {
+            match events.recv().await {
+                Ok(ClientEvent::Checksums {
+                    checksums,
+                    mismatched,
+                }) => {
+                    return Ok((checksums, mismatched));
+                }
+                Ok(ClientEvent::Error {
+                    code,
+                    request_type,
+                    context,
+                }) if request_type == MessageType::ChunkChecksumRequest as u8 => {
+                    return Err(ClientError::Server { code, context }.into());
+                }
+                Ok(ClientEvent::Disconnected) | Err(broadcast::error::RecvError::Closed) => {
+                    return Err(ClientError::Disconnected.into());
+                }
+                // The response may have been among the dropped events, so it's requested again.
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    self.send_raw(&send_data).await?;
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+
+    async fn send_full_state_request(shared: &ClientShared, chunk_index: u16) -> PResult<()> {
+        let mut send_data = Vec::new();
+        let request =
+            MessageMut::create_message(MessageType::ChunkFullStateRequest, &mut send_data)?;
+        if let MessageMut::ChunkFullStateRequest(request) = request {
+            request.chunk_index.set(chunk_index);
+        }
+
+        shared.sender.lock().await.send_binary(&send_data).await?;
+        Ok(())
+    }
+
+    async fn receive_task(
+        shared: Arc<ClientShared>,
+        mut receiver: ClientReceiver,
+        mut state: ReceiveState,
+    ) -> PResult<()> {
+        let result = Self::receive_loop(&shared, &mut receiver, &mut state).await;
+        let _ = shared.events.send(ClientEvent::Disconnected);
+        result
+    }
+
+    async fn receive_loop(
+        shared: &ClientShared,
+        receiver: &mut ClientReceiver,
+        state: &mut ReceiveState,
+    ) -> PResult<()> {
+        let mut recv_data = Vec::new();
+
+        loop {
+            recv_data.clear();
+            if !receiver.receive_data(&mut recv_data).await?.is_binary() {
+                continue;
+            }
+
+            match Message::from_slice(&recv_data) {
+                Ok(message) => Self::handle_message(shared, state, message).await?,
+                Err(e) => log::debug!("Received invalid message: {}", e),
+            }
+        }
+    }
+
+    async fn handle_message(
+        shared: &ClientShared,
+        state: &mut ReceiveState,
+        message: Message<'_>,
+    ) -> PResult<()> {
+        let last_full_state = state.last_full_state.take();
+
+        match message {
+            Message::Stats(stats) => {
+                let _ = shared.events.send(ClientEvent::Stats(stats.clone()));
+            }
+            Message::Error(error) => {
+                let _ = shared.events.send(ClientEvent::Error {
+                    code: ErrorCode::from_u8(error.code),
+                    request_type: error.request_type,
+                    context: error.context.get(),
+                });
+            }
+            Message::Ping(ping) => {
+                let mut send_data = Vec::new();
+                let pong = MessageMut::create_message(MessageType::Pong, &mut send_data)?;
+                if let MessageMut::Pong(pong) = pong {
+                    pong.payload.set(ping.payload.get());
+                }
+
+                shared.sender.lock().await.send_binary(&send_data).await?;
+            }
+            Message::Pong(pong) => {
+                let payload = pong.payload.get();
+                let _ = shared.events.send(ClientEvent::Pong { payload });
+            }
+            Message::ChunkFullStateResponse(msg, data) => {
+                let chunk_index = msg.chunk_index.get();
+                if let Some(chunk) = shared.chunks.write().await.get_mut(&chunk_index) {
+                    if data.len() != chunk.data.len() {
+                        return Err(ClientError::UnexpectedMessage.into());
+                    }
+                    chunk.data.copy_from_slice(data);
+                    chunk.loaded = true;
+                }
+
+                state.last_full_state = Some(chunk_index);
+                let _ = shared.events.send(ClientEvent::ChunkLoaded { chunk_index });
+            }
+            Message::CompressedChunkFullStateResponse(msg, data) => {
+                let chunk_index = msg.chunk_index.get();
+                let encoding =
+                    ChunkEncoding::from_u8(msg.encoding).ok_or(ClientError::UnexpectedMessage)?;
+                if let Some(chunk) = shared.chunks.write().await.get_mut(&chunk_index) {
+                    encoding::decode(encoding, data, &mut chunk.data[..])?;
+                    chunk.loaded = true;
+                }
+
+                state.last_full_state = Some(chunk_index);
+                let _ = shared.events.send(ClientEvent::ChunkLoaded { chunk_index });
+            }
+            Message::PartialStateUpdate(msg, data) => {
+                let offset = msg.offset.get() as usize;
+                let chunk_size_bytes = state.geometry.chunk_size_bytes();
+                let chunk_index = (offset / chunk_size_bytes) as u16;
+                let byte_offset = offset % chunk_size_bytes;
+
+                if let Some(chunk) = shared.chunks.write().await.get_mut(&chunk_index) {
+                    let window = chunk
+                        .data
+                        .get_mut(byte_offset..byte_offset + data.len())
+                        .ok_or(ClientError::UnexpectedMessage)?;
+                    window.copy_from_slice(data);
+                }
+
+                let _ = shared
+                    .events
+                    .send(ClientEvent::ChunkUpdated { chunk_index });
+            }
+            Message::SparsePartialStateUpdate(msg, entries) => {
+                let chunk_index = msg.chunk_index.get();
+                if let Some(chunk) = shared.chunks.write().await.get_mut(&chunk_index) {
+                    let bits = BitSlice::<u8, Lsb0>::from_slice_mut(&mut chunk.data[..]);
+                    for entry in entries.chunks_exact(size_of::<u32>()) {
+                        let entry = u32::from_le_bytes(entry.try_into().unwrap());
+                        let index = (entry & !SPARSE_UPDATE_VALUE_BIT) as usize;
+                        if index < bits.len() {
+                            bits.set(index, entry & SPARSE_UPDATE_VALUE_BIT != 0);
+                        }
+                    }
+                }
+
+                let _ = shared
+                    .events
+                    .send(ClientEvent::ChunkUpdated { chunk_index });
+            }
+            Message::ChunkVersion(msg) => {
+                let chunk_index = msg.chunk_index.get();
+                let version = msg.version.get();
+                let is_full_state = last_full_state == Some(chunk_index);
+
+                let in_sync = match shared.chunks.write().await.get_mut(&chunk_index) {
+                    Some(chunk) => {
+                        let in_sync = match chunk.version {
+                            // A full state older than the updates already applied is outdated,
+                            // so the newer version is kept.
+                            Some(last) if is_full_state => (version.wrapping_sub(last) as i32) >= 0,
+                            Some(last) => version == last.wrapping_add(1),
+                            None => true,
+                        };
+                        if in_sync || !is_full_state {
+                            chunk.version = Some(version);
+                        }
+
+                        // Gaps before the first full state don't matter, as it replaces everything.
+                        in_sync || !chunk.loaded
+                    }
+                    None => true,
+                };
+
+                if !in_sync {
+                    Self::resync_chunk(shared, &state.geometry, chunk_index).await?;
+                }
+            }
+            Message::ChunkResync(msg) => {
+                let chunk_index = msg.chunk_index.get();
+                if state.server_pushes_resync {
+                    let _ = shared.events.send(ClientEvent::ChunkResync { chunk_index });
+                } else {
+                    Self::resync_chunk(shared, &state.geometry, chunk_index).await?;
+                }
+            }
+            Message::ChunkChecksumResponse(entries) => {
+                let checksums: Vec<(u16, u32)> = entries
+                    .iter()
+                    .map(|entry| (entry.chunk_index.get(), entry.checksum.get()))
+                    .collect();
+
+                // Compared here, as later updates would change the local copy.
+                let chunks = shared.chunks.read().await;
+                let mismatched = checksums
+                    .iter()
+                    .filter(|(chunk_index, checksum)| {
+                        chunks.get(chunk_index).is_some_and(|chunk| {
+                            chunk.loaded && bitmap::chunk_checksum(&chunk.data[..]) != *checksum
+                        })
+                    })
+                    .map(|&(chunk_index, _)| chunk_index)
+                    .collect();
+                drop(chunks);
+
+                let _ = shared.events.send(ClientEvent::Checksums {
+                    checksums,
+                    mismatched,
+                });
+            }
+            Message::LockedRanges(msg, entries) => {
+                let chunk_index = msg.chunk_index.get();
+                if let Some(chunk) = shared.chunks.write().await.get_mut(&chunk_index) {
+                    chunk.locked = entries
+                        .iter()
+                        .map(|entry| entry.start.get()..entry.end.get())
+                        .collect();
+                }
+
+                let _ = shared
+                    .events
+                    .send(ClientEvent::LockedRanges { chunk_index });
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    async fn resync_chunk(
+        shared: &ClientShared,
+        geometry: &BitmapGeometry,
+        chunk_index: u16,
+    ) -> PResult<()> {
+        if chunk_index as usize >= geometry.chunk_count {
+            return Ok(());
+        }
+
+        log::debug!("Chunk {} is out of sync, requesting it again", chunk_index);
+        Self::send_full_state_request(shared, chunk_index).await?;
+        let _ = shared.events.send(ClientEvent::ChunkResync { chunk_index });
+        Ok(())
+    }
+}
+
+impl Drop for BitmapClient {
+    fn drop(&mut self) {
+        self.recv_task.abort();
+    }
+}
@@ -3,3 +3,7 @@ pub type PResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync + 'stati
 pub const CONFIG_PATH: &str = "config.toml";
 pub const STATE_PATH: &str = "state.bin";
 pub const METRICS_PATH: &str = "metrics.json";
+pub const LOCKS_PATH: &str = "locks.json";
+pub const SNAPSHOTS_PATH: &str = "snapshots";
+pub const TOGGLE_LOG_PATH: &str = "toggles.log";
+pub const DEFAULT_BOARD: &str = "main";
@@ -1,7 +1,18 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
 use config::Config;
 use serde::Deserialize;
 
-use crate::common::{PResult, CONFIG_PATH};
+use crate::{
+    bitmap::{BitmapGeometry, ChangeTrackerOptions},
+    common::{
+        PResult, CONFIG_PATH, DEFAULT_BOARD, LOCKS_PATH, METRICS_PATH, SNAPSHOTS_PATH, STATE_PATH,
+        TOGGLE_LOG_PATH,
+    },
+};
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
@@ -9,6 +20,10 @@ pub struct Settings {
     /// The address to bind the server to.
     pub bind_address: String,
 
+    /// The directory the bitmap state and metrics are stored in.
+    #[serde(default = "Settings::default_data_dir")]
+    pub data_dir: String,
+
     /// Use CF-Connecting-IP and X-Forwarded-For headers to determine the client's IP address.
     #[serde(default = "Settings::default_parse_proxy_headers")]
     pub parse_proxy_headers: bool,
@@ -17,6 +32,95 @@ pub struct Settings {
     /// Currently disabled by default, due to https://github.com/paritytech/soketto/issues/49
     #[serde(default)]
     pub ws_permessage_deflate: bool,
+
+    /// The maximum number of chunks a single client can be subscribed to at the same time.
+    #[serde(default = "Settings::default_max_subscriptions")]
+    pub max_subscriptions: usize,
+
+    /// How often the server pings each client that specified version 1.9 or later in its
+    /// ClientHello, in seconds.
+    #[serde(default = "Settings::default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+
+    /// The number of consecutive unanswered pings after which a client is disconnected.
+    #[serde(default = "Settings::default_max_missed_pings")]
+    pub max_missed_pings: u32,
+
+    /// The number of ticks of updates buffered for each subscribed chunk of a client. A client
+    /// that falls further behind is sent the full state of the chunk instead. Each tick takes a
+    /// single entry, however many update windows of the chunk changed.
+    #[serde(default = "Settings::default_backlog_capacity")]
+    pub backlog_capacity: usize,
+
+    /// The size of a single chunk in bits. Must be a multiple of update_chunk_size * 8.
+    #[serde(default = "Settings::default_chunk_size")]
+    pub chunk_size: usize,
+
+    /// The number of chunks in the bitmap.
+    #[serde(default = "Settings::default_chunk_count")]
+    pub chunk_count: usize,
+
+    /// The size of the windows partial updates are sent in, in bytes.
+    #[serde(default = "Settings::default_update_chunk_size")]
+    pub update_chunk_size: usize,
+
+    /// The boards served by the server. The first one is served to clients that don't select
+    /// a board. If empty, a single board named "main" is served.
+    #[serde(default)]
+    pub boards: Vec<BoardSettings>,
+
+    /// The token required by the admin HTTP API, sent as `Authorization: Bearer <token>`.
+    /// The admin API is disabled if not set.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+
+    /// How long images rendered by the HTTP API are cached, in seconds. Images are rendered
+    /// for every request if 0.
+    #[serde(default)]
+    pub render_cache_secs: u64,
+
+    /// How often a snapshot of every board is taken, in seconds. Snapshots are only taken
+    /// through the admin API if 0.
+    #[serde(default)]
+    pub snapshot_interval_secs: u64,
+
+    /// The number of snapshots kept for each board. Older snapshots are deleted when a new
+    /// one is taken.
+    #[serde(default = "Settings::default_max_snapshots")]
+    pub max_snapshots: usize,
+
+    /// How often the toggle log is written to disk, in milliseconds. The log records every bit
+    /// flipped since the last save, and is replayed on startup. Disabled if 0.
+    #[serde(default = "Settings::default_toggle_log_flush_ms")]
+    pub toggle_log_flush_ms: u64,
+}
+
+/// A board served by the server, selected by clients with the WebSocket request path.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BoardSettings {
+    /// The name of the board, used in the request path, the state file name and metrics labels.
+    pub name: String,
+
+    /// Overrides the chunk_size of the server for this board.
+    pub chunk_size: Option<usize>,
+
+    /// Overrides the chunk_count of the server for this board.
+    pub chunk_count: Option<usize>,
+
+    /// Overrides the update_chunk_size of the server for this board.
+    pub update_chunk_size: Option<usize>,
+}
+
+impl BoardSettings {
+    /// A board with the default geometry of the server.
+    pub fn named(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            chunk_size: None,
+            chunk_count: None,
+            update_chunk_size: None,
+        }
+    }
 }
 
 impl Settings {
@@ -35,7 +139,123 @@ impl Settings {
         Ok(settings)
     }
 
+    /// The state file of a board. The default board keeps the file name used before multiple
+    /// boards were supported.
+    pub fn state_path(&self, board: &str) -> PathBuf {
+        if board == DEFAULT_BOARD {
+            Path::new(&self.data_dir).join(STATE_PATH)
+        } else {
+            Path::new(&self.data_dir).join(format!("state-{}.bin", board))
+        }
+    }
+
+    /// The file with the locked regions of a board, next to its state file.
+    pub fn locks_path(&self, board: &str) -> PathBuf {
+        if board == DEFAULT_BOARD {
+            Path::new(&self.data_dir).join(LOCKS_PATH)
+        } else {
+            Path::new(&self.data_dir).join(format!("locks-{}.json", board))
+        }
+    }
+
+    /// The toggle log of a board, next to its state file.
+    pub fn toggle_log_path(&self, board: &str) -> PathBuf {
+        if board == DEFAULT_BOARD {
+            Path::new(&self.data_dir).join(TOGGLE_LOG_PATH)
+        } else {
+            Path::new(&self.data_dir).join(format!("toggles-{}.log", board))
+        }
+    }
+
+    pub fn metrics_path(&self) -> PathBuf {
+        Path::new(&self.data_dir).join(METRICS_PATH)
+    }
+
+    pub fn snapshots_path(&self) -> PathBuf {
+        Path::new(&self.data_dir).join(SNAPSHOTS_PATH)
+    }
+
+    pub fn geometry(&self) -> BitmapGeometry {
+        BitmapGeometry {
+            chunk_size: self.chunk_size,
+            chunk_count: self.chunk_count,
+            update_chunk_size: self.update_chunk_size,
+        }
+    }
+
+    /// The configured boards, or the single default board if none are configured.
+    pub fn boards(&self) -> Vec<BoardSettings> {
+        if self.boards.is_empty() {
+            vec![BoardSettings::named(DEFAULT_BOARD)]
+        } else {
+            self.boards.clone()
+        }
+    }
+
+    /// The geometry of a board, falling back to the geometry of the server.
+    pub fn board_geometry(&self, board: &BoardSettings) -> BitmapGeometry {
+        BitmapGeometry {
+            chunk_size: board.chunk_size.unwrap_or(self.chunk_size),
+            chunk_count: board.chunk_count.unwrap_or(self.chunk_count),
+            update_chunk_size: board.update_chunk_size.unwrap_or(self.update_chunk_size),
+        }
+    }
+
     fn sanity_check(&self) -> PResult<()> {
+        if self.max_subscriptions == 0 {
+            return Err("max_subscriptions must be at least 1".into());
+        }
+
+        if self.ping_interval_secs == 0 {
+            return Err("ping_interval_secs must be at least 1".into());
+        }
+
+        if self.max_missed_pings == 0 {
+            return Err("max_missed_pings must be at least 1".into());
+        }
+
+        if self.backlog_capacity == 0 {
+            return Err("backlog_capacity must be at least 1".into());
+        }
+
+        if self.max_snapshots == 0 {
+            return Err("max_snapshots must be at least 1".into());
+        }
+
+        if self
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| token.is_empty())
+        {
+            return Err("admin_token must not be empty".into());
+        }
+
+        self.geometry().validate()?;
+
+        let mut names = HashSet::new();
+        for board in self.boards() {
+            let valid_name = !board.name.is_empty()
+                && board
+                    .name
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_');
+            if !valid_name {
+                return Err(format!(
+                    "Board name \"{}\" must be non-empty and only contain letters, digits, - and _",
+                    board.name
+                )
+                .into());
+            }
+
+            if !names.insert(board.name.clone()) {
+                return Err(format!("Board \"{}\" is declared more than once", board.name).into());
+            }
+
+            self.board_geometry(&board)
+                .validate()
+                .map_err(|e| format!("Board \"{}\": {}", board.name, e))?;
+        }
+
         Ok(())
     }
 
@@ -43,7 +263,71 @@ impl Settings {
         "[::1]:2253".to_string()
     }
 
+    fn default_data_dir() -> String {
+        ".".to_string()
+    }
+
     fn default_parse_proxy_headers() -> bool {
         true
     }
+
+    fn default_max_subscriptions() -> usize {
+        4
+    }
+
+    fn default_ping_interval_secs() -> u64 {
+        30
+    }
+
+    fn default_max_missed_pings() -> u32 {
+        3
+    }
+
+    fn default_backlog_capacity() -> usize {
+        ChangeTrackerOptions::default().backlog_capacity
+    }
+
+    fn default_max_snapshots() -> usize {
+        24
+    }
+
+    fn default_toggle_log_flush_ms() -> u64 {
+        100
+    }
+
+    fn default_chunk_size() -> usize {
+        BitmapGeometry::DEFAULT.chunk_size
+    }
+
+    fn default_chunk_count() -> usize {
+        BitmapGeometry::DEFAULT.chunk_count
+    }
+
+    fn default_update_chunk_size() -> usize {
+        BitmapGeometry::DEFAULT.update_chunk_size
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            bind_address: Self::default_bind_address(),
+            data_dir: Self::default_data_dir(),
+            parse_proxy_headers: Self::default_parse_proxy_headers(),
+            ws_permessage_deflate: false,
+            max_subscriptions: Self::default_max_subscriptions(),
+            ping_interval_secs: Self::default_ping_interval_secs(),
+            max_missed_pings: Self::default_max_missed_pings(),
+            backlog_capacity: Self::default_backlog_capacity(),
+            chunk_size: Self::default_chunk_size(),
+            chunk_count: Self::default_chunk_count(),
+            update_chunk_size: Self::default_update_chunk_size(),
+            boards: Vec::new(),
+            admin_token: None,
+            render_cache_secs: 0,
+            snapshot_interval_secs: 0,
+            max_snapshots: Self::default_max_snapshots(),
+            toggle_log_flush_ms: Self::default_toggle_log_flush_ms(),
+        }
+    }
 }
@@ -0,0 +1,304 @@
+use bitvec::{order::Lsb0, slice::BitSlice};
+
+use crate::protocol::ProtocolError;
+
+/// Encodings a chunk can be sent with in a `CompressedChunkFullStateResponse`.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChunkEncoding {
+    /// The chunk bitmap as-is.
+    Raw = 0,
+    /// A list of little-endian `u32` offsets of every set bit within the chunk.
+    Sparse = 1,
+    /// Lengths of alternating runs of unset and set bits, starting with unset bits,
+    /// encoded as LEB128 varints. Bits after the last run are unset.
+    RunLength = 2,
+}
+
+impl ChunkEncoding {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            x if x == ChunkEncoding::Raw as u8 => Some(ChunkEncoding::Raw),
+            x if x == ChunkEncoding::Sparse as u8 => Some(ChunkEncoding::Sparse),
+            x if x == ChunkEncoding::RunLength as u8 => Some(ChunkEncoding::RunLength),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes the chunk using whichever encoding produces the smallest output.
+/// The output buffer is cleared first.
+pub fn encode_smallest(data: &[u8], out: &mut Vec<u8>) -> ChunkEncoding {
+    let bits = BitSlice::<u8, Lsb0>::from_slice(data);
+    let sparse_size = bits.count_ones() * size_of::<u32>();
+
+    encode_run_length(data, out);
+    if out.len() <= sparse_size && out.len() < data.len() {
+        return ChunkEncoding::RunLength;
+    }
+
+    if sparse_size < data.len() {
+        encode_sparse(data, out);
+        return ChunkEncoding::Sparse;
+    }
+
+    out.clear();
+    out.extend_from_slice(data);
+    ChunkEncoding::Raw
+}
+
+pub fn encode_sparse(data: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+
+    let bits = BitSlice::<u8, Lsb0>::from_slice(data);
+    for index in bits.iter_ones() {
+        out.extend_from_slice(&(index as u32).to_le_bytes());
+    }
+}
+
+pub fn encode_run_length(data: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+
+    let bits = BitSlice::<u8, Lsb0>::from_slice(data);
+    let mut prev_end = 0;
+    // The current run of set bits, as a [start, end) range.
+    let mut run: Option<(usize, usize)> = None;
+
+    for index in bits.iter_ones() {
+        match run {
+            Some((start, end)) if end == index => run = Some((start, end + 1)),
+            Some((start, end)) => {
+                write_varint(out, start - prev_end);
+                write_varint(out, end - start);
+                prev_end = end;
+                run = Some((index, index + 1));
+            }
+            None => run = Some((index, index + 1)),
+        }
+    }
+
+    if let Some((start, end)) = run {
+        write_varint(out, start - prev_end);
+        write_varint(out, end - start);
+    }
+}
+
+/// Decodes `encoded` data into `out`, which must be the size of a chunk.
+pub fn decode(
+    encoding: ChunkEncoding,
+    encoded: &[u8],
+    out: &mut [u8],
+) -> Result<(), ProtocolError> {
+    match encoding {
+        ChunkEncoding::Raw => {
+            if encoded.len() != out.len() {
+                return Err(ProtocolError::InvalidMessageSize);
+            }
+            out.copy_from_slice(encoded);
+        }
+        ChunkEncoding::Sparse => {
+            if !encoded.len().is_multiple_of(size_of::<u32>()) {
+                return Err(ProtocolError::InvalidMessageSize);
+            }
+
+            out.fill(0);
+            let bits = BitSlice::<u8, Lsb0>::from_slice_mut(out);
+            for offset in encoded.chunks_exact(size_of::<u32>()) {
+                let index = u32::from_le_bytes(offset.try_into().unwrap()) as usize;
+                if index >= bits.len() {
+                    return Err(ProtocolError::InvalidMessageSize);
+                }
+                bits.set(index, true);
+            }
+        }
+        ChunkEncoding::RunLength => {
+            out.fill(0);
+            let bits = BitSlice::<u8, Lsb0>::from_slice_mut(out);
+            let mut encoded = encoded;
+            let mut position = 0;
+            let mut value = false;
+
+            while !encoded.is_empty() {
+                let length = read_varint(&mut encoded).ok_or(ProtocolError::InvalidMessageSize)?;
+                let end = position + length;
+                if end > bits.len() {
+                    return Err(ProtocolError::InvalidMessageSize);
+                }
+
+                bits[position..end].fill(value);
+                position = end;
+                value = !value;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varint(data: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    let mut shift = 0;
+
+    loop {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+
+        if shift >= usize::BITS {
+            return None;
+        }
+
+        value |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE_BYTES: usize = 1024;
+
+    /// Encodes the chunk with the smallest encoding, checks that it's the expected one and
+    /// that it decodes back to the chunk.
+    fn assert_round_trip(data: &[u8], expected: ChunkEncoding) {
+        let mut encoded = Vec::new();
+        let encoding = encode_smallest(data, &mut encoded);
+        assert_eq!(encoding, expected);
+
+        let mut decoded = vec![0xA5; data.len()];
+        decode(encoding, &encoded, &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    /// Bytes from a xorshift generator, so the test data is the same on every run.
+    fn pseudo_random_bytes(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty_chunk_is_run_length_encoded() {
+        let data = [0; CHUNK_SIZE_BYTES];
+        let mut encoded = Vec::new();
+        assert_eq!(
+            encode_smallest(&data, &mut encoded),
+            ChunkEncoding::RunLength
+        );
+        assert!(encoded.is_empty());
+        assert_round_trip(&data, ChunkEncoding::RunLength);
+    }
+
+    #[test]
+    fn full_chunk_is_run_length_encoded() {
+        let data = [0xFF; CHUNK_SIZE_BYTES];
+        let mut encoded = Vec::new();
+        encode_smallest(&data, &mut encoded);
+        // An empty run of unset bits, then a single run of every bit.
+        assert_eq!(encoded, [0x00, 0x80, 0x40]);
+        assert_round_trip(&data, ChunkEncoding::RunLength);
+    }
+
+    #[test]
+    fn scattered_bits_are_sparse_encoded() {
+        let mut data = [0; CHUNK_SIZE_BYTES];
+        data[0] = 0b0000_0101;
+        data[100] = 0b1000_0000;
+        data[CHUNK_SIZE_BYTES - 1] = 0b1000_0000;
+        let mut encoded = Vec::new();
+        encode_sparse(&data, &mut encoded);
+        let offsets: Vec<u32> = encoded
+            .chunks_exact(4)
+            .map(|offset| u32::from_le_bytes(offset.try_into().unwrap()))
+            .collect();
+        assert_eq!(offsets, [0, 2, 807, CHUNK_SIZE_BYTES as u32 * 8 - 1]);
+
+        // Run lengths only take more bytes than sparse entries if the gaps between the bits
+        // need 4-byte varints, which takes chunks larger than the default.
+        let mut data = vec![0; 1 << 20];
+        data[300_000] = 0b0100_0000;
+        data[600_000] = 0b0000_0001;
+        assert_round_trip(&data, ChunkEncoding::Sparse);
+    }
+
+    #[test]
+    fn random_chunk_is_sent_raw() {
+        let data = pseudo_random_bytes(CHUNK_SIZE_BYTES);
+        assert_round_trip(&data, ChunkEncoding::Raw);
+    }
+
+    #[test]
+    fn runs_round_trip() {
+        let mut data = [0; CHUNK_SIZE_BYTES];
+        data[10..20].fill(0xFF);
+        data[500] = 0b0011_1100;
+        data[CHUNK_SIZE_BYTES - 8..].fill(0xFF);
+        assert_round_trip(&data, ChunkEncoding::RunLength);
+
+        // Every encoding decodes to the same chunk, whichever is the smallest.
+        let data = pseudo_random_bytes(CHUNK_SIZE_BYTES);
+        let mut encoded = Vec::new();
+        let mut decoded = vec![0; CHUNK_SIZE_BYTES];
+        encode_run_length(&data, &mut encoded);
+        decode(ChunkEncoding::RunLength, &encoded, &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+        encode_sparse(&data, &mut encoded);
+        decode(ChunkEncoding::Sparse, &encoded, &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        let mut out = [0; CHUNK_SIZE_BYTES];
+        let chunk_bits = CHUNK_SIZE_BYTES as u32 * 8;
+
+        let cases: [(ChunkEncoding, Vec<u8>); 6] = [
+            (ChunkEncoding::Raw, vec![0; CHUNK_SIZE_BYTES - 1]),
+            (ChunkEncoding::Raw, vec![0; CHUNK_SIZE_BYTES + 1]),
+            // Truncated entry
+            (ChunkEncoding::Sparse, vec![1, 0, 0]),
+            (ChunkEncoding::Sparse, chunk_bits.to_le_bytes().to_vec()),
+            // Truncated varint
+            (ChunkEncoding::RunLength, vec![0x05, 0x80]),
+            // Runs past the end of the chunk
+            (ChunkEncoding::RunLength, vec![0x00, 0x81, 0x40]),
+        ];
+        for (encoding, encoded) in cases {
+            let result = decode(encoding, &encoded, &mut out);
+            assert!(
+                matches!(result, Err(ProtocolError::InvalidMessageSize)),
+                "{:?} {:?}",
+                encoding,
+                encoded
+            );
+        }
+
+        // A varint longer than usize
+        let result = decode(ChunkEncoding::RunLength, &[0xFF; 12], &mut out);
+        assert!(matches!(result, Err(ProtocolError::InvalidMessageSize)));
+    }
+}
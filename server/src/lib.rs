@@ -1,5 +1,11 @@
 pub mod bitmap;
+pub mod client;
 pub mod common;
 pub mod config;
+pub mod encoding;
+pub mod locks;
 pub mod protocol;
-pub mod server;
\ No newline at end of file
+pub mod render;
+pub mod server;
+pub mod snapshots;
+pub mod toggle_log;
\ No newline at end of file
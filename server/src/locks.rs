@@ -0,0 +1,110 @@
+use std::{ops::Range, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{bitmap::BitmapGeometry, common::PResult};
+
+/// A range of locked bits in the locks file, `end` is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// The contents of the locks file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LockFile {
+    /// Chunks locked as a whole
+    #[serde(default)]
+    pub chunks: Vec<u16>,
+    /// Ranges of bits, in global bit indices
+    #[serde(default)]
+    pub ranges: Vec<LockedRange>,
+}
+
+/// Parts of a bitmap that clients can't modify, set by the admins of the server.
+#[derive(Debug, Clone, Default)]
+pub struct RegionLocks {
+    file: LockFile,
+    /// The locked chunks and ranges combined, sorted and without overlaps
+    locked: Vec<Range<usize>>,
+}
+
+impl RegionLocks {
+    /// Checks the chunks and ranges against the geometry of the bitmap.
+    pub fn new(file: LockFile, geometry: &BitmapGeometry) -> PResult<Self> {
+        let mut locked = Vec::with_capacity(file.chunks.len() + file.ranges.len());
+
+        for &chunk_index in file.chunks.iter() {
+            let chunk_index = chunk_index as usize;
+            if chunk_index >= geometry.chunk_count {
+                return Err(format!("Locked chunk {} is out of range", chunk_index).into());
+            }
+            let start = chunk_index * geometry.chunk_size;
+            locked.push(start..start + geometry.chunk_size);
+        }
+
+        for range in file.ranges.iter() {
+            let (start, end) = (range.start as usize, range.end as usize);
+            if start >= end || end > geometry.bitmap_size() {
+                return Err(format!("Locked range {}..{} is invalid", start, end).into());
+            }
+            locked.push(start..end);
+        }
+
+        locked.sort_unstable_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(locked.len());
+        for range in locked {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+
+        Ok(Self {
+            file,
+            locked: merged,
+        })
+    }
+
+    /// Loads the locks from a file. A missing file means nothing is locked.
+    pub fn load_from_file(path: impl AsRef<Path>, geometry: &BitmapGeometry) -> PResult<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = std::fs::read_to_string(path)?;
+        let file = serde_json::from_str(&data)?;
+        Self::new(file, geometry)
+    }
+
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> PResult<()> {
+        let data = serde_json::to_string_pretty(&self.file)?;
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    pub fn is_locked(&self, index: usize) -> bool {
+        let position = self.locked.partition_point(|range| range.end <= index);
+        self.locked
+            .get(position)
+            .is_some_and(|range| range.start <= index)
+    }
+
+    /// Returns the locked ranges overlapping a chunk, relative to the start of the chunk.
+    pub fn ranges_in_chunk(&self, chunk_index: usize, chunk_size: usize) -> Vec<Range<usize>> {
+        let chunk = chunk_index * chunk_size..(chunk_index + 1) * chunk_size;
+        let first = self
+            .locked
+            .partition_point(|range| range.end <= chunk.start);
+
+        self.locked[first..]
+            .iter()
+            .take_while(|range| range.start < chunk.end)
+            .map(|range| {
+                range.start.max(chunk.start) - chunk.start..range.end.min(chunk.end) - chunk.start
+            })
+            .collect()
+    }
+}
@@ -10,7 +10,7 @@ async fn main() -> PResult<()> {
     log::info!("Starting server");
     let settings = Settings::load_from_file_and_env()?;
 
-    BitmapServer::new(settings).run().await?;
+    BitmapServer::new(settings)?.run().await?;
 
     Ok(())
 }
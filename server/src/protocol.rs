@@ -1,33 +1,90 @@
 use std::fmt::Display;
 
-use zerocopy::{AsBytes, FromBytes, FromZeroes, Unaligned};
-
-use crate::bitmap::{CHUNK_SIZE_BYTES, UPDATE_CHUNK_SIZE};
+use zerocopy::{
+    byteorder::little_endian::{U16, U32, U64},
+    AsBytes, FromBytes, FromZeroes, Unaligned,
+};
 
 pub const PROTOCOL_VERSION_MAJOR: u16 = 1;
-pub const PROTOCOL_VERSION_MINOR: u16 = 1;
+pub const PROTOCOL_VERSION_MINOR: u16 = 15;
+
+/// The maximum size of a WebSocket message accepted by the server and the client.
+pub const MAX_MESSAGE_SIZE: usize = 512 * 1024;
+
+/// Full state requests are answered with compressed responses.
+pub const CAPABILITY_COMPRESSED_FULL_STATE: u32 = 1 << 0;
+/// Partial updates may be sent as a list of changed bits.
+pub const CAPABILITY_SPARSE_UPDATES: u32 = 1 << 1;
+/// Chunk versions and resync hints are sent alongside full states and partial updates.
+pub const CAPABILITY_CHUNK_VERSIONS: u32 = 1 << 2;
+/// The read-only ranges of subscribed chunks are sent on subscription and when they change.
+pub const CAPABILITY_LOCKED_RANGES: u32 = 1 << 3;
+
+/// Capabilities supported by this server.
+pub const SUPPORTED_CAPABILITIES: u32 = CAPABILITY_COMPRESSED_FULL_STATE
+    | CAPABILITY_SPARSE_UPDATES
+    | CAPABILITY_CHUNK_VERSIONS
+    | CAPABILITY_LOCKED_RANGES;
+
+/// Allows the server to respond with a compressed chunk encoding.
+pub const FULL_STATE_FLAG_ALLOW_COMPRESSION: u8 = 1 << 0;
+
+/// Allows the server to send partial updates as a list of changed bits.
+pub const UPDATE_FLAG_ALLOW_SPARSE: u8 = 1 << 0;
+
+/// Set in a sparse partial update entry if the bit is now checked.
+pub const SPARSE_UPDATE_VALUE_BIT: u32 = 1 << 31;
 
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MessageType {
     Hello = 0x0,
     Stats = 0x1,
+    ClientHello = 0x2,
+    ClientHelloAck = 0x3,
+    Error = 0x4,
+    Ping = 0x5,
+    Pong = 0x6,
+    BoardInfo = 0x7,
     ChunkFullStateRequest = 0x10,
     ChunkFullStateResponse = 0x11,
     PartialStateUpdate = 0x12,
     ToggleBit = 0x13,
     PartialStateSubscription = 0x14,
     PartialStateUnsubscription = 0x15,
+    ToggleBits = 0x16,
+    SetBit = 0x17,
+    AddPartialStateSubscription = 0x18,
+    RemovePartialStateSubscription = 0x19,
+    ChunkFullStateRequestWithFlags = 0x1A,
+    CompressedChunkFullStateResponse = 0x1B,
+    SetUpdateFlags = 0x1C,
+    SparsePartialStateUpdate = 0x1D,
+    ChunkVersion = 0x1E,
+    ChunkResync = 0x1F,
+    ChunkChecksumRequest = 0x20,
+    ChunkChecksumResponse = 0x21,
+    LockedRanges = 0x22,
 }
 
 impl MessageType {
     pub const fn is_client_message(&self) -> bool {
         matches!(
             self,
-            MessageType::ChunkFullStateRequest
+            MessageType::ClientHello
+                | MessageType::Ping
+                | MessageType::Pong
+                | MessageType::ChunkFullStateRequest
                 | MessageType::ToggleBit
                 | MessageType::PartialStateSubscription
                 | MessageType::PartialStateUnsubscription
+                | MessageType::ToggleBits
+                | MessageType::SetBit
+                | MessageType::AddPartialStateSubscription
+                | MessageType::RemovePartialStateSubscription
+                | MessageType::ChunkFullStateRequestWithFlags
+                | MessageType::SetUpdateFlags
+                | MessageType::ChunkChecksumRequest
         )
     }
 
@@ -36,59 +93,235 @@ impl MessageType {
             self,
             MessageType::Hello
                 | MessageType::Stats
+                | MessageType::ClientHelloAck
+                | MessageType::Error
+                | MessageType::Ping
+                | MessageType::Pong
+                | MessageType::BoardInfo
                 | MessageType::ChunkFullStateResponse
                 | MessageType::PartialStateUpdate
+                | MessageType::CompressedChunkFullStateResponse
+                | MessageType::SparsePartialStateUpdate
+                | MessageType::ChunkVersion
+                | MessageType::ChunkResync
+                | MessageType::ChunkChecksumResponse
+                | MessageType::LockedRanges
         )
     }
 }
 
-#[cfg(not(target_endian = "little"))]
-compile_error!("This code below is only intended to run on little-endian architectures");
+// Multi-byte fields are stored in little-endian byte order regardless of the target, so the
+// message structs can be cast to and from wire bytes directly. Use `get` and `set` to access them.
 
-#[repr(packed)]
+#[repr(C)]
 #[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
 pub struct HelloMessage {
-    pub version_major: u16,
-    pub version_minor: u16,
+    pub version_major: U16,
+    pub version_minor: U16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct ClientHelloMessage {
+    pub version_major: U16,
+    pub version_minor: U16,
+    /// Bitwise OR of the CAPABILITY_* values the client supports.
+    pub capabilities: U32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct ClientHelloAckMessage {
+    /// Bitwise OR of the CAPABILITY_* values enabled for the connection.
+    pub capabilities: U32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct ErrorMessage {
+    /// One of the ErrorCode values.
+    pub code: u8,
+    /// Type of the message that caused the error.
+    pub request_type: u8,
+    /// Additional information about the error, depending on the code. Zero if unused.
+    pub context: U32,
 }
 
-#[repr(packed)]
+/// Used by both Ping and Pong messages. A Pong carries the payload of the Ping it answers.
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct PingMessage {
+    pub payload: U64,
+}
+
+/// The dimensions of the bitmap, sent after the Client Hello Ack message.
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct BoardInfoMessage {
+    /// The size of a chunk in bits
+    pub chunk_size: U32,
+    /// The number of chunks
+    pub chunk_count: U32,
+    /// The size of a Partial State Update window in bytes
+    pub update_chunk_size: U16,
+}
+
+#[repr(C)]
 #[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
 pub struct StatsMessage {
-    pub current_clients: u32,
-    pub reserved: [u8; 60],
+    pub current_clients: U32,
+    pub checked_bits: U32,
+    pub toggles_per_second: U32,
+    pub uptime_seconds: U32,
+    /// Milliseconds since the Unix epoch
+    pub server_timestamp: U64,
+    pub reserved: [u8; 40],
 }
 
-#[repr(packed)]
+#[repr(C)]
 #[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
 pub struct ChunkFullStateRequestMessage {
-    pub chunk_index: u16,
+    pub chunk_index: U16,
 }
 
-#[repr(packed)]
+/// Followed by the chunk data, `chunk_size / 8` bytes.
+#[repr(C)]
 #[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
 pub struct ChunkFullStateResponseMessage {
-    pub chunk_index: u16,
-    pub bitmap: [u8; CHUNK_SIZE_BYTES],
+    pub chunk_index: U16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct ChunkFullStateRequestWithFlagsMessage {
+    pub chunk_index: U16,
+    pub flags: u8,
+}
+
+/// Followed by the chunk data, encoded as specified by `encoding`.
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct CompressedChunkFullStateResponseMessage {
+    pub chunk_index: U16,
+    pub encoding: u8,
 }
 
-#[repr(packed)]
+/// Followed by the contents of the update window, `update_chunk_size` bytes.
+#[repr(C)]
 #[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
 pub struct PartialStateUpdateMessage {
-    pub offset: u32,
-    pub chunk: [u8; UPDATE_CHUNK_SIZE],
+    /// Offset of the window in the global byte array (chunk_index * chunk_size / 8 + offset
+    /// within the chunk)
+    pub offset: U32,
 }
 
-#[repr(packed)]
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct SetUpdateFlagsMessage {
+    pub flags: u8,
+}
+
+/// Followed by a list of little-endian `u32` entries, each holding the offset of a changed bit
+/// within the chunk, with `SPARSE_UPDATE_VALUE_BIT` set if the bit is now checked.
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct SparsePartialStateUpdateMessage {
+    pub chunk_index: U16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct ChunkVersionMessage {
+    pub chunk_index: U16,
+    pub version: U32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct ChunkResyncMessage {
+    pub chunk_index: U16,
+}
+
+/// An entry of the Chunk Checksum Request message. An empty request asks for every chunk.
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct ChunkChecksumRequestMessage {
+    pub chunk_index: U16,
+}
+
+/// An entry of the Chunk Checksum Response message.
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct ChunkChecksumMessage {
+    pub chunk_index: U16,
+    /// CRC-32 of the chunk data
+    pub checksum: U32,
+}
+
+/// Followed by a list of `LockedRangeMessage` entries, sorted and non-overlapping.
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct LockedRangesMessage {
+    pub chunk_index: U16,
+}
+
+/// An entry of the Locked Ranges message.
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct LockedRangeMessage {
+    /// Offset of the first locked bit within the chunk
+    pub start: U32,
+    /// Offset of the bit after the last locked bit within the chunk
+    pub end: U32,
+}
+
+#[repr(C)]
 #[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
 pub struct ToggleBitMessage {
-    pub index: u32,
+    pub index: U32,
 }
 
-#[repr(packed)]
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
+pub struct SetBitMessage {
+    pub index: U32,
+    /// 0 clears the bit, any other value sets it.
+    pub value: u8,
+}
+
+#[repr(C)]
 #[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Unaligned)]
 pub struct PartialStateSubscriptionMessage {
-    pub chunk_index: u16,
+    pub chunk_index: U16,
+}
+
+/// Error codes sent to the client in the Error message.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    Unknown = 0,
+    UnsupportedMessage = 1,
+    InvalidMessageSize = 2,
+    UnsupportedVersion = 3,
+    InvalidIndex = 4,
+    TooManySubscriptions = 5,
+    RateLimited = 6,
+    ReadOnly = 7,
+}
+
+impl ErrorCode {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            x if x == ErrorCode::UnsupportedMessage as u8 => ErrorCode::UnsupportedMessage,
+            x if x == ErrorCode::InvalidMessageSize as u8 => ErrorCode::InvalidMessageSize,
+            x if x == ErrorCode::UnsupportedVersion as u8 => ErrorCode::UnsupportedVersion,
+            x if x == ErrorCode::InvalidIndex as u8 => ErrorCode::InvalidIndex,
+            x if x == ErrorCode::TooManySubscriptions as u8 => ErrorCode::TooManySubscriptions,
+            x if x == ErrorCode::RateLimited as u8 => ErrorCode::RateLimited,
+            x if x == ErrorCode::ReadOnly as u8 => ErrorCode::ReadOnly,
+            _ => ErrorCode::Unknown,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -96,6 +329,27 @@ pub enum ProtocolError {
     InvalidMessageId,
     InvalidMessageSize,
     InvalidMessageVersion,
+    UnexpectedMessage,
+    InvalidIndex,
+    TooManySubscriptions,
+    RateLimited,
+    ReadOnly,
+}
+
+impl ProtocolError {
+    /// The code this error is reported to the client with.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ProtocolError::InvalidMessageId => ErrorCode::UnsupportedMessage,
+            ProtocolError::InvalidMessageSize => ErrorCode::InvalidMessageSize,
+            ProtocolError::InvalidMessageVersion => ErrorCode::UnsupportedVersion,
+            ProtocolError::UnexpectedMessage => ErrorCode::UnsupportedMessage,
+            ProtocolError::InvalidIndex => ErrorCode::InvalidIndex,
+            ProtocolError::TooManySubscriptions => ErrorCode::TooManySubscriptions,
+            ProtocolError::RateLimited => ErrorCode::RateLimited,
+            ProtocolError::ReadOnly => ErrorCode::ReadOnly,
+        }
+    }
 }
 
 impl Display for ProtocolError {
@@ -104,6 +358,11 @@ impl Display for ProtocolError {
             ProtocolError::InvalidMessageId => write!(f, "Invalid message ID"),
             ProtocolError::InvalidMessageSize => write!(f, "Invalid message size"),
             ProtocolError::InvalidMessageVersion => write!(f, "Invalid message version"),
+            ProtocolError::UnexpectedMessage => write!(f, "Unexpected message"),
+            ProtocolError::InvalidIndex => write!(f, "Invalid index"),
+            ProtocolError::TooManySubscriptions => write!(f, "Too many subscriptions"),
+            ProtocolError::RateLimited => write!(f, "Rate limited"),
+            ProtocolError::ReadOnly => write!(f, "Read-only"),
         }
     }
 }
@@ -113,12 +372,31 @@ impl std::error::Error for ProtocolError {}
 pub enum Message<'a> {
     Hello(&'a HelloMessage),
     Stats(&'a StatsMessage),
+    ClientHello(&'a ClientHelloMessage),
+    ClientHelloAck(&'a ClientHelloAckMessage),
+    Error(&'a ErrorMessage),
+    Ping(&'a PingMessage),
+    Pong(&'a PingMessage),
+    BoardInfo(&'a BoardInfoMessage),
     ChunkFullStateRequest(&'a ChunkFullStateRequestMessage),
-    ChunkFullStateResponse(&'a ChunkFullStateResponseMessage),
-    PartialStateUpdate(&'a PartialStateUpdateMessage),
+    ChunkFullStateResponse(&'a ChunkFullStateResponseMessage, &'a [u8]),
+    PartialStateUpdate(&'a PartialStateUpdateMessage, &'a [u8]),
     ToggleBit(&'a ToggleBitMessage),
     PartialStateSubscription(&'a PartialStateSubscriptionMessage),
     PartialStateUnsubscription,
+    ToggleBits(&'a [ToggleBitMessage]),
+    SetBit(&'a SetBitMessage),
+    AddPartialStateSubscription(&'a PartialStateSubscriptionMessage),
+    RemovePartialStateSubscription(&'a PartialStateSubscriptionMessage),
+    ChunkFullStateRequestWithFlags(&'a ChunkFullStateRequestWithFlagsMessage),
+    CompressedChunkFullStateResponse(&'a CompressedChunkFullStateResponseMessage, &'a [u8]),
+    SetUpdateFlags(&'a SetUpdateFlagsMessage),
+    SparsePartialStateUpdate(&'a SparsePartialStateUpdateMessage, &'a [u8]),
+    ChunkVersion(&'a ChunkVersionMessage),
+    ChunkResync(&'a ChunkResyncMessage),
+    ChunkChecksumRequest(&'a [ChunkChecksumRequestMessage]),
+    ChunkChecksumResponse(&'a [ChunkChecksumMessage]),
+    LockedRanges(&'a LockedRangesMessage, &'a [LockedRangeMessage]),
 }
 
 impl Message<'_> {
@@ -126,19 +404,62 @@ impl Message<'_> {
         match self {
             Message::Hello(_) => MessageType::Hello,
             Message::Stats(_) => MessageType::Stats,
+            Message::ClientHello(_) => MessageType::ClientHello,
+            Message::ClientHelloAck(_) => MessageType::ClientHelloAck,
+            Message::Error(_) => MessageType::Error,
+            Message::Ping(_) => MessageType::Ping,
+            Message::Pong(_) => MessageType::Pong,
+            Message::BoardInfo(_) => MessageType::BoardInfo,
             Message::ChunkFullStateRequest(_) => MessageType::ChunkFullStateRequest,
-            Message::ChunkFullStateResponse(_) => MessageType::ChunkFullStateResponse,
-            Message::PartialStateUpdate(_) => MessageType::PartialStateUpdate,
+            Message::ChunkFullStateResponse(..) => MessageType::ChunkFullStateResponse,
+            Message::PartialStateUpdate(..) => MessageType::PartialStateUpdate,
             Message::ToggleBit(_) => MessageType::ToggleBit,
             Message::PartialStateSubscription(_) => MessageType::PartialStateSubscription,
             Message::PartialStateUnsubscription => MessageType::PartialStateUnsubscription,
+            Message::ToggleBits(_) => MessageType::ToggleBits,
+            Message::SetBit(_) => MessageType::SetBit,
+            Message::AddPartialStateSubscription(_) => MessageType::AddPartialStateSubscription,
+            Message::RemovePartialStateSubscription(_) => {
+                MessageType::RemovePartialStateSubscription
+            }
+            Message::ChunkFullStateRequestWithFlags(_) => {
+                MessageType::ChunkFullStateRequestWithFlags
+            }
+            Message::CompressedChunkFullStateResponse(..) => {
+                MessageType::CompressedChunkFullStateResponse
+            }
+            Message::SetUpdateFlags(_) => MessageType::SetUpdateFlags,
+            Message::SparsePartialStateUpdate(..) => MessageType::SparsePartialStateUpdate,
+            Message::ChunkVersion(_) => MessageType::ChunkVersion,
+            Message::ChunkResync(_) => MessageType::ChunkResync,
+            Message::ChunkChecksumRequest(_) => MessageType::ChunkChecksumRequest,
+            Message::ChunkChecksumResponse(_) => MessageType::ChunkChecksumResponse,
+            Message::LockedRanges(..) => MessageType::LockedRanges,
+        }
+    }
+
+    /// Returns the chunk index for messages that address a single chunk.
+    pub fn chunk_index(&self) -> Option<u16> {
+        match self {
+            Message::ChunkFullStateRequest(msg) => Some(msg.chunk_index.get()),
+            Message::ChunkFullStateRequestWithFlags(msg) => Some(msg.chunk_index.get()),
+            Message::ChunkFullStateResponse(msg, _) => Some(msg.chunk_index.get()),
+            Message::CompressedChunkFullStateResponse(msg, _) => Some(msg.chunk_index.get()),
+            Message::SparsePartialStateUpdate(msg, _) => Some(msg.chunk_index.get()),
+            Message::ChunkVersion(msg) => Some(msg.chunk_index.get()),
+            Message::ChunkResync(msg) => Some(msg.chunk_index.get()),
+            Message::PartialStateSubscription(msg) => Some(msg.chunk_index.get()),
+            Message::AddPartialStateSubscription(msg) => Some(msg.chunk_index.get()),
+            Message::RemovePartialStateSubscription(msg) => Some(msg.chunk_index.get()),
+            Message::LockedRanges(msg, _) => Some(msg.chunk_index.get()),
+            _ => None,
         }
     }
 
     /// Parses a message from a slice of bytes and if the message is valid,
     /// returns an enum variant with a reference to the message data, casted to the correct type.
-    pub fn from_slice(slice: &[u8]) -> Result<Message, ProtocolError> {
-        if slice.len() < 1 {
+    pub fn from_slice(slice: &[u8]) -> Result<Message<'_>, ProtocolError> {
+        if slice.is_empty() {
             return Err(ProtocolError::InvalidMessageSize);
         }
 
@@ -154,19 +475,42 @@ impl Message<'_> {
             }};
         }
 
+        macro_rules! payload_message_handler {
+            ($name:ident, $message:ty) => {{
+                let (header, payload) = slice[1..]
+                    .split_at_checked(std::mem::size_of::<$message>())
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                let message =
+                    <$message>::ref_from(header).ok_or(ProtocolError::InvalidMessageSize)?;
+                Ok(Message::$name(message, payload))
+            }};
+        }
+
         match id {
             x if x == MessageType::Hello as u8 => message_handler!(Hello, HelloMessage),
             x if x == MessageType::Stats as u8 => {
                 message_handler!(Stats, StatsMessage)
             }
+            x if x == MessageType::ClientHello as u8 => {
+                message_handler!(ClientHello, ClientHelloMessage)
+            }
+            x if x == MessageType::ClientHelloAck as u8 => {
+                message_handler!(ClientHelloAck, ClientHelloAckMessage)
+            }
+            x if x == MessageType::Error as u8 => message_handler!(Error, ErrorMessage),
+            x if x == MessageType::Ping as u8 => message_handler!(Ping, PingMessage),
+            x if x == MessageType::Pong as u8 => message_handler!(Pong, PingMessage),
+            x if x == MessageType::BoardInfo as u8 => {
+                message_handler!(BoardInfo, BoardInfoMessage)
+            }
             x if x == MessageType::ChunkFullStateRequest as u8 => {
                 message_handler!(ChunkFullStateRequest, ChunkFullStateRequestMessage)
             }
             x if x == MessageType::ChunkFullStateResponse as u8 => {
-                message_handler!(ChunkFullStateResponse, ChunkFullStateResponseMessage)
+                payload_message_handler!(ChunkFullStateResponse, ChunkFullStateResponseMessage)
             }
             x if x == MessageType::PartialStateUpdate as u8 => {
-                message_handler!(PartialStateUpdate, PartialStateUpdateMessage)
+                payload_message_handler!(PartialStateUpdate, PartialStateUpdateMessage)
             }
             x if x == MessageType::ToggleBit as u8 => {
                 message_handler!(ToggleBit, ToggleBitMessage)
@@ -177,6 +521,67 @@ impl Message<'_> {
             x if x == MessageType::PartialStateUnsubscription as u8 => {
                 Ok(Message::PartialStateUnsubscription)
             }
+            x if x == MessageType::ToggleBits as u8 => {
+                let messages = ToggleBitMessage::slice_from(&slice[1..])
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                Ok(Message::ToggleBits(messages))
+            }
+            x if x == MessageType::SetBit as u8 => {
+                message_handler!(SetBit, SetBitMessage)
+            }
+            x if x == MessageType::AddPartialStateSubscription as u8 => {
+                message_handler!(AddPartialStateSubscription, PartialStateSubscriptionMessage)
+            }
+            x if x == MessageType::RemovePartialStateSubscription as u8 => {
+                message_handler!(
+                    RemovePartialStateSubscription,
+                    PartialStateSubscriptionMessage
+                )
+            }
+            x if x == MessageType::ChunkFullStateRequestWithFlags as u8 => {
+                message_handler!(
+                    ChunkFullStateRequestWithFlags,
+                    ChunkFullStateRequestWithFlagsMessage
+                )
+            }
+            x if x == MessageType::CompressedChunkFullStateResponse as u8 => {
+                payload_message_handler!(
+                    CompressedChunkFullStateResponse,
+                    CompressedChunkFullStateResponseMessage
+                )
+            }
+            x if x == MessageType::SetUpdateFlags as u8 => {
+                message_handler!(SetUpdateFlags, SetUpdateFlagsMessage)
+            }
+            x if x == MessageType::SparsePartialStateUpdate as u8 => {
+                payload_message_handler!(SparsePartialStateUpdate, SparsePartialStateUpdateMessage)
+            }
+            x if x == MessageType::ChunkVersion as u8 => {
+                message_handler!(ChunkVersion, ChunkVersionMessage)
+            }
+            x if x == MessageType::ChunkResync as u8 => {
+                message_handler!(ChunkResync, ChunkResyncMessage)
+            }
+            x if x == MessageType::ChunkChecksumRequest as u8 => {
+                let messages = ChunkChecksumRequestMessage::slice_from(&slice[1..])
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                Ok(Message::ChunkChecksumRequest(messages))
+            }
+            x if x == MessageType::ChunkChecksumResponse as u8 => {
+                let messages = ChunkChecksumMessage::slice_from(&slice[1..])
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                Ok(Message::ChunkChecksumResponse(messages))
+            }
+            x if x == MessageType::LockedRanges as u8 => {
+                let (header, entries) = slice[1..]
+                    .split_at_checked(size_of::<LockedRangesMessage>())
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                let message = LockedRangesMessage::ref_from(header)
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                let entries = LockedRangeMessage::slice_from(entries)
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                Ok(Message::LockedRanges(message, entries))
+            }
             _ => Err(ProtocolError::InvalidMessageId),
         }
     }
@@ -185,12 +590,34 @@ impl Message<'_> {
 pub enum MessageMut<'a> {
     Hello(&'a mut HelloMessage),
     Stats(&'a mut StatsMessage),
+    ClientHello(&'a mut ClientHelloMessage),
+    ClientHelloAck(&'a mut ClientHelloAckMessage),
+    Error(&'a mut ErrorMessage),
+    Ping(&'a mut PingMessage),
+    Pong(&'a mut PingMessage),
+    BoardInfo(&'a mut BoardInfoMessage),
     ChunkFullStateRequest(&'a mut ChunkFullStateRequestMessage),
-    ChunkFullStateResponse(&'a mut ChunkFullStateResponseMessage),
-    PartialStateUpdate(&'a mut PartialStateUpdateMessage),
+    ChunkFullStateResponse(&'a mut ChunkFullStateResponseMessage, &'a mut [u8]),
+    PartialStateUpdate(&'a mut PartialStateUpdateMessage, &'a mut [u8]),
     ToggleBit(&'a mut ToggleBitMessage),
     PartialStateSubscription(&'a mut PartialStateSubscriptionMessage),
     PartialStateUnsubscription,
+    ToggleBits(&'a mut [ToggleBitMessage]),
+    SetBit(&'a mut SetBitMessage),
+    AddPartialStateSubscription(&'a mut PartialStateSubscriptionMessage),
+    RemovePartialStateSubscription(&'a mut PartialStateSubscriptionMessage),
+    ChunkFullStateRequestWithFlags(&'a mut ChunkFullStateRequestWithFlagsMessage),
+    CompressedChunkFullStateResponse(
+        &'a mut CompressedChunkFullStateResponseMessage,
+        &'a mut [u8],
+    ),
+    SetUpdateFlags(&'a mut SetUpdateFlagsMessage),
+    SparsePartialStateUpdate(&'a mut SparsePartialStateUpdateMessage, &'a mut [u8]),
+    ChunkVersion(&'a mut ChunkVersionMessage),
+    ChunkResync(&'a mut ChunkResyncMessage),
+    ChunkChecksumRequest(&'a mut [ChunkChecksumRequestMessage]),
+    ChunkChecksumResponse(&'a mut [ChunkChecksumMessage]),
+    LockedRanges(&'a mut LockedRangesMessage, &'a mut [LockedRangeMessage]),
 }
 
 impl MessageMut<'_> {
@@ -198,19 +625,44 @@ impl MessageMut<'_> {
         match self {
             MessageMut::Hello(_) => MessageType::Hello,
             MessageMut::Stats(_) => MessageType::Stats,
+            MessageMut::ClientHello(_) => MessageType::ClientHello,
+            MessageMut::ClientHelloAck(_) => MessageType::ClientHelloAck,
+            MessageMut::Error(_) => MessageType::Error,
+            MessageMut::Ping(_) => MessageType::Ping,
+            MessageMut::Pong(_) => MessageType::Pong,
+            MessageMut::BoardInfo(_) => MessageType::BoardInfo,
             MessageMut::ChunkFullStateRequest(_) => MessageType::ChunkFullStateRequest,
-            MessageMut::ChunkFullStateResponse(_) => MessageType::ChunkFullStateResponse,
-            MessageMut::PartialStateUpdate(_) => MessageType::PartialStateUpdate,
+            MessageMut::ChunkFullStateResponse(..) => MessageType::ChunkFullStateResponse,
+            MessageMut::PartialStateUpdate(..) => MessageType::PartialStateUpdate,
             MessageMut::ToggleBit(_) => MessageType::ToggleBit,
             MessageMut::PartialStateSubscription(_) => MessageType::PartialStateSubscription,
             MessageMut::PartialStateUnsubscription => MessageType::PartialStateUnsubscription,
+            MessageMut::ToggleBits(_) => MessageType::ToggleBits,
+            MessageMut::SetBit(_) => MessageType::SetBit,
+            MessageMut::AddPartialStateSubscription(_) => MessageType::AddPartialStateSubscription,
+            MessageMut::RemovePartialStateSubscription(_) => {
+                MessageType::RemovePartialStateSubscription
+            }
+            MessageMut::ChunkFullStateRequestWithFlags(_) => {
+                MessageType::ChunkFullStateRequestWithFlags
+            }
+            MessageMut::CompressedChunkFullStateResponse(..) => {
+                MessageType::CompressedChunkFullStateResponse
+            }
+            MessageMut::SetUpdateFlags(_) => MessageType::SetUpdateFlags,
+            MessageMut::SparsePartialStateUpdate(..) => MessageType::SparsePartialStateUpdate,
+            MessageMut::ChunkVersion(_) => MessageType::ChunkVersion,
+            MessageMut::ChunkResync(_) => MessageType::ChunkResync,
+            MessageMut::ChunkChecksumRequest(_) => MessageType::ChunkChecksumRequest,
+            MessageMut::ChunkChecksumResponse(_) => MessageType::ChunkChecksumResponse,
+            MessageMut::LockedRanges(..) => MessageType::LockedRanges,
         }
     }
 
     /// Parses a client message from a mutable slice of bytes and if the message is valid,
     /// returns an enum variant with a reference to the message data, casted to the correct type.
-    pub fn from_slice(slice: &mut [u8]) -> Result<MessageMut, ProtocolError> {
-        if slice.len() < 1 {
+    pub fn from_slice(slice: &mut [u8]) -> Result<MessageMut<'_>, ProtocolError> {
+        if slice.is_empty() {
             return Err(ProtocolError::InvalidMessageSize);
         }
 
@@ -227,19 +679,42 @@ impl MessageMut<'_> {
             }};
         }
 
+        macro_rules! payload_message_handler {
+            ($name:ident, $message:ty) => {{
+                let (header, payload) = slice[1..]
+                    .split_at_mut_checked(std::mem::size_of::<$message>())
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                let message =
+                    <$message>::mut_from(header).ok_or(ProtocolError::InvalidMessageSize)?;
+                Ok(MessageMut::$name(message, payload))
+            }};
+        }
+
         match id {
             x if x == MessageType::Hello as u8 => message_handler!(Hello, HelloMessage),
             x if x == MessageType::Stats as u8 => {
                 message_handler!(Stats, StatsMessage)
             }
+            x if x == MessageType::ClientHello as u8 => {
+                message_handler!(ClientHello, ClientHelloMessage)
+            }
+            x if x == MessageType::ClientHelloAck as u8 => {
+                message_handler!(ClientHelloAck, ClientHelloAckMessage)
+            }
+            x if x == MessageType::Error as u8 => message_handler!(Error, ErrorMessage),
+            x if x == MessageType::Ping as u8 => message_handler!(Ping, PingMessage),
+            x if x == MessageType::Pong as u8 => message_handler!(Pong, PingMessage),
+            x if x == MessageType::BoardInfo as u8 => {
+                message_handler!(BoardInfo, BoardInfoMessage)
+            }
             x if x == MessageType::ChunkFullStateRequest as u8 => {
                 message_handler!(ChunkFullStateRequest, ChunkFullStateRequestMessage)
             }
             x if x == MessageType::ChunkFullStateResponse as u8 => {
-                message_handler!(ChunkFullStateResponse, ChunkFullStateResponseMessage)
+                payload_message_handler!(ChunkFullStateResponse, ChunkFullStateResponseMessage)
             }
             x if x == MessageType::PartialStateUpdate as u8 => {
-                message_handler!(PartialStateUpdate, PartialStateUpdateMessage)
+                payload_message_handler!(PartialStateUpdate, PartialStateUpdateMessage)
             }
             x if x == MessageType::ToggleBit as u8 => {
                 message_handler!(ToggleBit, ToggleBitMessage)
@@ -250,6 +725,67 @@ impl MessageMut<'_> {
             x if x == MessageType::PartialStateUnsubscription as u8 => {
                 Ok(MessageMut::PartialStateUnsubscription)
             }
+            x if x == MessageType::ToggleBits as u8 => {
+                let messages = ToggleBitMessage::mut_slice_from(&mut slice[1..])
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                Ok(MessageMut::ToggleBits(messages))
+            }
+            x if x == MessageType::SetBit as u8 => {
+                message_handler!(SetBit, SetBitMessage)
+            }
+            x if x == MessageType::AddPartialStateSubscription as u8 => {
+                message_handler!(AddPartialStateSubscription, PartialStateSubscriptionMessage)
+            }
+            x if x == MessageType::RemovePartialStateSubscription as u8 => {
+                message_handler!(
+                    RemovePartialStateSubscription,
+                    PartialStateSubscriptionMessage
+                )
+            }
+            x if x == MessageType::ChunkFullStateRequestWithFlags as u8 => {
+                message_handler!(
+                    ChunkFullStateRequestWithFlags,
+                    ChunkFullStateRequestWithFlagsMessage
+                )
+            }
+            x if x == MessageType::CompressedChunkFullStateResponse as u8 => {
+                payload_message_handler!(
+                    CompressedChunkFullStateResponse,
+                    CompressedChunkFullStateResponseMessage
+                )
+            }
+            x if x == MessageType::SetUpdateFlags as u8 => {
+                message_handler!(SetUpdateFlags, SetUpdateFlagsMessage)
+            }
+            x if x == MessageType::SparsePartialStateUpdate as u8 => {
+                payload_message_handler!(SparsePartialStateUpdate, SparsePartialStateUpdateMessage)
+            }
+            x if x == MessageType::ChunkVersion as u8 => {
+                message_handler!(ChunkVersion, ChunkVersionMessage)
+            }
+            x if x == MessageType::ChunkResync as u8 => {
+                message_handler!(ChunkResync, ChunkResyncMessage)
+            }
+            x if x == MessageType::ChunkChecksumRequest as u8 => {
+                let messages = ChunkChecksumRequestMessage::mut_slice_from(&mut slice[1..])
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                Ok(MessageMut::ChunkChecksumRequest(messages))
+            }
+            x if x == MessageType::ChunkChecksumResponse as u8 => {
+                let messages = ChunkChecksumMessage::mut_slice_from(&mut slice[1..])
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                Ok(MessageMut::ChunkChecksumResponse(messages))
+            }
+            x if x == MessageType::LockedRanges as u8 => {
+                let (header, entries) = slice[1..]
+                    .split_at_mut_checked(size_of::<LockedRangesMessage>())
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                let message = LockedRangesMessage::mut_from(header)
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                let entries = LockedRangeMessage::mut_slice_from(entries)
+                    .ok_or(ProtocolError::InvalidMessageSize)?;
+                Ok(MessageMut::LockedRanges(message, entries))
+            }
             _ => Err(ProtocolError::InvalidMessageId),
         }
     }
@@ -259,19 +795,56 @@ impl MessageMut<'_> {
     pub fn create_message(
         id: MessageType,
         buffer: &mut Vec<u8>,
-    ) -> Result<MessageMut, ProtocolError> {
+    ) -> Result<MessageMut<'_>, ProtocolError> {
+        Self::create_variable_message(id, 0, buffer)
+    }
+
+    /// Same as `create_message`, but reserves `extra_size` additional bytes after the fixed part
+    /// of the message, for message types that carry a variable-length payload.
+    pub fn create_variable_message(
+        id: MessageType,
+        extra_size: usize,
+        buffer: &mut Vec<u8>,
+    ) -> Result<MessageMut<'_>, ProtocolError> {
         let size = match id {
             MessageType::Hello => size_of::<HelloMessage>(),
             MessageType::Stats => size_of::<StatsMessage>(),
+            MessageType::ClientHello => size_of::<ClientHelloMessage>(),
+            MessageType::ClientHelloAck => size_of::<ClientHelloAckMessage>(),
+            MessageType::Error => size_of::<ErrorMessage>(),
+            MessageType::Ping => size_of::<PingMessage>(),
+            MessageType::Pong => size_of::<PingMessage>(),
+            MessageType::BoardInfo => size_of::<BoardInfoMessage>(),
             MessageType::ChunkFullStateRequest => size_of::<ChunkFullStateRequestMessage>(),
             MessageType::ChunkFullStateResponse => size_of::<ChunkFullStateResponseMessage>(),
             MessageType::PartialStateUpdate => size_of::<PartialStateUpdateMessage>(),
             MessageType::ToggleBit => size_of::<ToggleBitMessage>(),
             MessageType::PartialStateSubscription => size_of::<PartialStateSubscriptionMessage>(),
             MessageType::PartialStateUnsubscription => 0,
+            MessageType::ToggleBits => 0,
+            MessageType::SetBit => size_of::<SetBitMessage>(),
+            MessageType::AddPartialStateSubscription => {
+                size_of::<PartialStateSubscriptionMessage>()
+            }
+            MessageType::RemovePartialStateSubscription => {
+                size_of::<PartialStateSubscriptionMessage>()
+            }
+            MessageType::ChunkFullStateRequestWithFlags => {
+                size_of::<ChunkFullStateRequestWithFlagsMessage>()
+            }
+            MessageType::CompressedChunkFullStateResponse => {
+                size_of::<CompressedChunkFullStateResponseMessage>()
+            }
+            MessageType::SetUpdateFlags => size_of::<SetUpdateFlagsMessage>(),
+            MessageType::SparsePartialStateUpdate => size_of::<SparsePartialStateUpdateMessage>(),
+            MessageType::ChunkVersion => size_of::<ChunkVersionMessage>(),
+            MessageType::ChunkResync => size_of::<ChunkResyncMessage>(),
+            MessageType::ChunkChecksumRequest => 0,
+            MessageType::ChunkChecksumResponse => 0,
+            MessageType::LockedRanges => size_of::<LockedRangesMessage>(),
         };
         buffer.clear();
-        buffer.resize(size + 1, 0);
+        buffer.resize(size + extra_size + 1, 0);
         buffer[0] = id as u8;
 
         Self::from_slice(&mut buffer[..])
@@ -280,10 +853,20 @@ impl MessageMut<'_> {
 
 pub fn is_valid_client_message_id(id: u8) -> bool {
     match id {
+        x if x == MessageType::ClientHello as u8 => true,
+        x if x == MessageType::Ping as u8 => true,
+        x if x == MessageType::Pong as u8 => true,
         x if x == MessageType::ChunkFullStateRequest as u8 => true,
         x if x == MessageType::ToggleBit as u8 => true,
         x if x == MessageType::PartialStateSubscription as u8 => true,
         x if x == MessageType::PartialStateUnsubscription as u8 => true,
+        x if x == MessageType::ToggleBits as u8 => true,
+        x if x == MessageType::SetBit as u8 => true,
+        x if x == MessageType::AddPartialStateSubscription as u8 => true,
+        x if x == MessageType::RemovePartialStateSubscription as u8 => true,
+        x if x == MessageType::ChunkFullStateRequestWithFlags as u8 => true,
+        x if x == MessageType::SetUpdateFlags as u8 => true,
+        x if x == MessageType::ChunkChecksumRequest as u8 => true,
         _ => false,
     }
 }
@@ -292,8 +875,19 @@ pub fn is_valid_server_message_id(id: u8) -> bool {
     match id {
         x if x == MessageType::Hello as u8 => true,
         x if x == MessageType::Stats as u8 => true,
+        x if x == MessageType::ClientHelloAck as u8 => true,
+        x if x == MessageType::Error as u8 => true,
+        x if x == MessageType::Ping as u8 => true,
+        x if x == MessageType::Pong as u8 => true,
+        x if x == MessageType::BoardInfo as u8 => true,
         x if x == MessageType::ChunkFullStateResponse as u8 => true,
         x if x == MessageType::PartialStateUpdate as u8 => true,
+        x if x == MessageType::CompressedChunkFullStateResponse as u8 => true,
+        x if x == MessageType::SparsePartialStateUpdate as u8 => true,
+        x if x == MessageType::ChunkVersion as u8 => true,
+        x if x == MessageType::ChunkResync as u8 => true,
+        x if x == MessageType::ChunkChecksumResponse as u8 => true,
+        x if x == MessageType::LockedRanges as u8 => true,
         _ => false,
     }
 }
@@ -0,0 +1,114 @@
+use std::io::{self, Write};
+
+use flate2::{write::ZlibEncoder, Compression};
+
+use crate::bitmap::{Bitmap, BitmapGeometry};
+
+/// Maximum width of a chunk rendered as an image, in pixels. Chunks of the default size are
+/// square.
+pub const CHUNK_IMAGE_WIDTH: usize = 512;
+
+/// The dimensions of a chunk rendered as an image. Bits are laid out in rows from the top
+/// left, like in the web client.
+pub fn chunk_image_size(geometry: &BitmapGeometry) -> (usize, usize) {
+    let width = CHUNK_IMAGE_WIDTH.min(geometry.chunk_size);
+    (width, geometry.chunk_size.div_ceil(width))
+}
+
+/// Renders a chunk as a black and white PNG, with checked bits as black pixels.
+pub fn render_chunk(data: &[u8], geometry: &BitmapGeometry) -> io::Result<Vec<u8>> {
+    let (width, height) = chunk_image_size(geometry);
+
+    // PNG stores the leftmost pixel in the most significant bit, and white as 1. The last row
+    // is padded with unchecked pixels.
+    let mut pixels: Vec<u8> = data.iter().map(|byte| !byte.reverse_bits()).collect();
+    pixels.resize(width * height / 8, 0xFF);
+
+    encode_png(width, height, 1, &pixels)
+}
+
+/// Renders every chunk of the bitmap as a grayscale PNG, with each pixel covering `scale` by
+/// `scale` bits, darker the more of them are checked. Chunks are placed in a square grid in
+/// the order of their indices. `scale` must be a multiple of 8.
+pub fn render_density(bitmap: &Bitmap, scale: usize) -> io::Result<Vec<u8>> {
+    let geometry = bitmap.geometry();
+    let (chunk_width, chunk_height) = chunk_image_size(&geometry);
+    let (cell_width, cell_height) = (chunk_width.div_ceil(scale), chunk_height.div_ceil(scale));
+
+    let columns = (1..).find(|c| c * c >= geometry.chunk_count).unwrap_or(1);
+    let rows = geometry.chunk_count.div_ceil(columns);
+    let (width, height) = (columns * cell_width, rows * cell_height);
+
+    let mut pixels = vec![0xFF; width * height];
+    let mut checked = vec![0u32; cell_width * cell_height];
+    let mut total = vec![0u32; cell_width * cell_height];
+
+    for chunk_index in 0..geometry.chunk_count {
+        checked.fill(0);
+        total.fill(0);
+
+        // Bytes never straddle cells, as both the chunk width and the scale are multiples of 8.
+        for (byte_index, byte) in bitmap.as_raw_slice(chunk_index).iter().enumerate() {
+            let x = byte_index * 8 % chunk_width / scale;
+            let y = byte_index * 8 / chunk_width / scale;
+            checked[y * cell_width + x] += byte.count_ones();
+            total[y * cell_width + x] += 8;
+        }
+
+        let left = chunk_index % columns * cell_width;
+        let top = chunk_index / columns * cell_height;
+        for y in 0..cell_height {
+            for x in 0..cell_width {
+                let cell = y * cell_width + x;
+                if total[cell] != 0 {
+                    let density = checked[cell] as f32 / total[cell] as f32;
+                    pixels[(top + y) * width + left + x] = 255 - (density * 255.0).round() as u8;
+                }
+            }
+        }
+    }
+
+    encode_png(width, height, 8, &pixels)
+}
+
+/// Encodes a grayscale image as PNG. `pixels` holds the rows one after another, each packed
+/// into whole bytes.
+pub fn encode_png(
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+    pixels: &[u8],
+) -> io::Result<Vec<u8>> {
+    let stride = (width * bit_depth as usize).div_ceil(8);
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth, grayscale color type, deflate compression, adaptive filtering, no interlace
+    header.extend_from_slice(&[bit_depth, 0, 0, 0, 0]);
+
+    // Every row is prefixed with its filter type, rows aren't filtered.
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in pixels.chunks(stride).take(height) {
+        encoder.write_all(&[0])?;
+        encoder.write_all(row)?;
+    }
+    let data = encoder.finish()?;
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_png_chunk(&mut png, b"IHDR", &header);
+    write_png_chunk(&mut png, b"IDAT", &data);
+    write_png_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
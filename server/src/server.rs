@@ -1,12 +1,23 @@
 use crate::{
-    bitmap::{Bitmap, Change},
-    common::{PResult, METRICS_PATH, STATE_PATH},
+    bitmap::{Bitmap, BitmapGeometry, Change, ChangeTrackerOptions},
+    common::{PResult, DEFAULT_BOARD},
     config::Settings,
-    protocol::{Message, MessageMut, MessageType, PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR},
+    encoding,
+    locks::{LockFile, RegionLocks},
+    protocol::{
+        ChunkChecksumMessage, LockedRangeMessage, Message, MessageMut, MessageType, ProtocolError,
+        CAPABILITY_CHUNK_VERSIONS, CAPABILITY_COMPRESSED_FULL_STATE, CAPABILITY_LOCKED_RANGES,
+        CAPABILITY_SPARSE_UPDATES, FULL_STATE_FLAG_ALLOW_COMPRESSION, MAX_MESSAGE_SIZE,
+        PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR, SPARSE_UPDATE_VALUE_BIT,
+        SUPPORTED_CAPABILITIES, UPDATE_FLAG_ALLOW_SPARSE,
+    },
+    render,
+    snapshots::SnapshotStore,
+    toggle_log::ToggleLog,
 };
-use futures_util::AsyncWriteExt;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
 use serde::{Deserialize, Serialize};
-use signal_hook::consts::{SIGINT, SIGQUIT, SIGTERM};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
 use signal_hook_tokio::Signals;
 use soketto::{
     extension::deflate::Deflate,
@@ -14,20 +25,26 @@ use soketto::{
     Data,
 };
 use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
     io,
     net::IpAddr,
+    path::Path,
     str::FromStr,
     sync::{
-        atomic::{AtomicU32, AtomicU64, Ordering},
+        atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering},
         Arc,
     },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
-    net::TcpStream,
-    sync::{broadcast, mpsc, Mutex, RwLock},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch, Mutex, RwLock},
     task::{JoinHandle, JoinSet},
 };
-use tokio_stream::{wrappers::TcpListenerStream, StreamExt};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, TcpListenerStream},
+    StreamExt, StreamMap,
+};
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 
 pub struct BitmapServer {
@@ -36,61 +53,215 @@ pub struct BitmapServer {
 
 struct SharedServerContext {
     settings: Settings,
-    bitmap: RwLock<Bitmap>,
+    /// The served boards, the first one is the default board
+    boards: Vec<Arc<Board>>,
     metrics: Arc<Metrics>,
     client_id_counter: AtomicU64,
+    started_at: Instant,
+    /// State of the connected WebSocket clients, by client ID
+    clients: std::sync::Mutex<HashMap<u64, Arc<ClientState>>>,
+    render_cache: std::sync::Mutex<RenderCache>,
+    snapshots: SnapshotStore,
+}
+
+/// Rendered images by board name, with the time they were rendered at
+type RenderCache = HashMap<(String, RenderTarget), (Instant, Vec<u8>)>;
+
+/// An image of a board rendered by the HTTP API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RenderTarget {
+    Chunk(usize),
+    Density { scale: usize },
+}
+
+/// A board with its own bitmap, state file and metrics.
+struct Board {
+    name: String,
+    geometry: BitmapGeometry,
+    bitmap: RwLock<Bitmap>,
+    /// The regions clients can't modify, replaced when the locks file is reloaded
+    locks: watch::Sender<Arc<RegionLocks>>,
+    metrics: BoardMetrics,
+    /// The flips since the state was last saved, unless the toggle log is disabled
+    toggle_log: Option<Arc<ToggleLog>>,
+}
+
+impl Board {
+    fn is_locked(&self, index: usize) -> bool {
+        self.locks.borrow().is_locked(index)
+    }
+}
+
+/// Window over which the toggle rate in the stats message is averaged
+const TOGGLE_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// The first minor version of clients that expect a Board Info message after the
+/// Client Hello Ack. Older clients are only accepted on boards with the default geometry.
+const BOARD_INFO_MIN_VERSION_MINOR: u16 = 14;
+
+/// The first minor version of clients that answer pings. Older clients, and clients that don't
+/// send a Client Hello, are never pinged.
+const PING_MIN_VERSION_MINOR: u16 = 9;
+
+/// Maximum size of the body of an admin request, enough for the largest chunk
+const MAX_ADMIN_BODY_SIZE: usize = MAX_MESSAGE_SIZE;
+
+/// Time allowed for receiving the body of an admin request
+const ADMIN_BODY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bits per pixel side of the board image if not given, which renders a chunk of the default
+/// size as 8x8 pixels
+const DEFAULT_DENSITY_SCALE: usize = 64;
+
+/// Per-connection state shared between the receive task and the client task.
+#[derive(Default)]
+struct ClientState {
+    /// Capabilities accepted during the ClientHello handshake
+    capabilities: AtomicU32,
+    /// Minor protocol version from the ClientHello, 0 if the client didn't send one
+    version_minor: AtomicU16,
+    /// Flags set with the SetUpdateFlags message or implied by capabilities
+    update_flags: AtomicU8,
+    /// Number of times the client fell behind the update backlog of a subscribed chunk
+    lag_events: AtomicU64,
+}
+
+impl ClientState {
+    fn has_capability(&self, capability: u32) -> bool {
+        self.capabilities.load(Ordering::Relaxed) & capability != 0
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 enum ClientTaskMessage {
     Subscribe { chunk: u16 },
+    AddSubscription { chunk: u16 },
+    RemoveSubscription { chunk: u16 },
     UnsubscribeAll,
     SendStats,
+    SendPing,
+    Pong { payload: u64 },
 }
 
 impl BitmapServer {
-    pub fn new(settings: Settings) -> Box<Self> {
-        let mut bitmap = Bitmap::new();
-        match bitmap.load_from_file(STATE_PATH) {
-            Ok(_) => log::info!("Loaded bitmap state from file"),
-            Err(e) => log::warn!("Failed to load bitmap state from file: {}", e),
-        }
+    /// Creates the server and loads the saved state of every board. Fails if a state file
+    /// exists but can't be loaded, so it isn't overwritten by the next save.
+    pub fn new(settings: Settings) -> PResult<Box<Self>> {
+        let (metrics, mut board_metrics) =
+            Metrics::load_from_file(settings.metrics_path()).unwrap_or_default();
+
+        let mut boards = Vec::new();
+        for board_settings in settings.boards() {
+            let name = board_settings.name.clone();
+            let geometry = settings.board_geometry(&board_settings);
+            geometry
+                .validate()
+                .map_err(|e| format!("Board \"{}\": {}", name, e))?;
+
+            let mut bitmap = Bitmap::with_options(
+                geometry,
+                ChangeTrackerOptions {
+                    backlog_capacity: settings.backlog_capacity,
+                    ..Default::default()
+                },
+            );
+            let state_path = settings.state_path(&name);
+            if state_path.exists() {
+                bitmap.load_from_file(&state_path).map_err(|e| {
+                    format!(
+                        "Failed to load state of board \"{}\" from file: {}",
+                        name, e
+                    )
+                })?;
+                log::info!("Loaded state of board \"{}\" from file", name);
+            } else {
+                log::warn!(
+                    "No state file for board \"{}\", starting with an empty bitmap",
+                    name
+                );
+            }
 
-        let metrics = match Metrics::load_from_file(METRICS_PATH) {
-            Ok(m) => Arc::new(m),
-            Err(_) => Arc::new(Metrics::default()),
-        };
+            let state_checksum = bitmap.state_checksum();
+            let toggle_log_path = settings.toggle_log_path(&name);
+            let replayed = ToggleLog::replay(&toggle_log_path, &mut bitmap, state_checksum)
+                .map_err(|e| format!("Failed to replay toggle log of board \"{}\": {}", name, e))?;
+            if replayed > 0 {
+                log::info!(
+                    "Replayed {} toggles of board \"{}\" from the toggle log",
+                    replayed,
+                    name
+                );
+            }
+
+            // The replayed records stay in the log until the next save, as the state file
+            // doesn't include them yet.
+            let toggle_log = if settings.toggle_log_flush_ms > 0 {
+                let toggle_log =
+                    ToggleLog::open(&toggle_log_path, state_checksum).map_err(|e| {
+                        format!("Failed to open toggle log of board \"{}\": {}", name, e)
+                    })?;
+                let toggle_log = Arc::new(toggle_log);
+                bitmap.set_toggle_log(toggle_log.clone());
+                Some(toggle_log)
+            } else {
+                // A log left by a run with the log enabled is never truncated, so it's folded
+                // into the state file instead of being replayed again on every start.
+                if toggle_log_path.exists() {
+                    bitmap.save_to_file(&state_path).map_err(|e| {
+                        format!("Failed to save state of board \"{}\": {}", name, e)
+                    })?;
+                    std::fs::remove_file(&toggle_log_path).map_err(|e| {
+                        format!("Failed to remove toggle log of board \"{}\": {}", name, e)
+                    })?;
+                    log::info!(
+                        "Saved the toggle log of board \"{}\" to the state file and removed it",
+                        name
+                    );
+                }
+                None
+            };
 
-        metrics.set_checked_bits(bitmap.count_ones() as u32);
+            let locks = RegionLocks::load_from_file(settings.locks_path(&name), &geometry)
+                .map_err(|e| format!("Failed to load locks of board \"{}\": {}", name, e))?;
 
+            let metrics = board_metrics.remove(&name).unwrap_or_default();
+            metrics.set_checked_bits(bitmap.count_ones() as u32);
+
+            boards.push(Arc::new(Board {
+                name,
+                geometry,
+                bitmap: RwLock::new(bitmap),
+                locks: watch::Sender::new(Arc::new(locks)),
+                metrics,
+                toggle_log,
+            }));
+        }
+
+        let snapshots = SnapshotStore::new(settings.snapshots_path());
         let ctx = Arc::new(SharedServerContext {
             settings,
-            bitmap: RwLock::new(bitmap),
-            metrics,
+            boards,
+            metrics: Arc::new(metrics),
             client_id_counter: AtomicU64::new(0),
+            started_at: Instant::now(),
+            clients: std::sync::Mutex::new(HashMap::new()),
+            render_cache: std::sync::Mutex::new(HashMap::new()),
+            snapshots,
         });
 
-        Box::new(Self { ctx })
+        Ok(Box::new(Self { ctx }))
     }
 
     pub async fn run(&self) -> PResult<()> {
-        let net_task = Self::net_task(self.ctx.clone());
-        let bitmap_task = Self::bitmap_task(self.ctx.clone());
-        let save_task = Self::save_task(self.ctx.clone());
-
-        let mut join_set = JoinSet::new();
-        join_set.spawn(async move { net_task.await });
-        join_set.spawn(async move { bitmap_task.await });
-        join_set.spawn(async move { save_task.await });
+        let listener = TcpListener::bind(&self.ctx.settings.bind_address).await?;
 
         let ctx = self.ctx.clone();
         tokio::spawn(async move {
-            let mut signals = Signals::new(&[SIGINT, SIGTERM, SIGQUIT]).unwrap();
+            let mut signals = Signals::new([SIGINT, SIGTERM, SIGQUIT]).unwrap();
             let handle = signals.handle();
 
-            while let Some(signal) = signals.next().await {
+            if let Some(signal) = signals.next().await {
                 log::info!("Quitting due to signal {}", signal);
-                break;
             }
 
             handle.close();
@@ -100,6 +271,42 @@ impl BitmapServer {
             std::process::exit(0);
         });
 
+        let ctx = self.ctx.clone();
+        tokio::spawn(async move {
+            let mut signals = Signals::new([SIGHUP]).unwrap();
+            while signals.next().await.is_some() {
+                Self::do_reload_locks(&ctx);
+            }
+        });
+
+        self.serve(listener).await
+    }
+
+    /// Serves clients from an already bound listener, until one of the server tasks fails.
+    /// Unlike `run`, doesn't handle signals, so it can be used to run servers in tests.
+    pub async fn serve(&self, listener: TcpListener) -> PResult<()> {
+        let mut join_set = JoinSet::new();
+        join_set.spawn(Self::net_task(self.ctx.clone(), listener));
+        join_set.spawn(Self::save_task(self.ctx.clone()));
+
+        if self.ctx.settings.snapshot_interval_secs > 0 {
+            join_set.spawn(Self::snapshot_task(self.ctx.clone()));
+        }
+
+        for board in self.ctx.boards.iter() {
+            join_set.spawn(Self::bitmap_task(board.clone()));
+            join_set.spawn(Self::toggle_rate_task(board.clone()));
+
+            if let Some(toggle_log) = &board.toggle_log {
+                let interval = Duration::from_millis(self.ctx.settings.toggle_log_flush_ms);
+                join_set.spawn(Self::toggle_log_task(
+                    board.name.clone(),
+                    toggle_log.clone(),
+                    interval,
+                ));
+            }
+        }
+
         while let Some(result) = join_set.join_next().await {
             result??;
         }
@@ -107,11 +314,11 @@ impl BitmapServer {
         Ok(())
     }
 
-    async fn bitmap_task(ctx: Arc<SharedServerContext>) -> PResult<()> {
+    async fn bitmap_task(board: Arc<Board>) -> PResult<()> {
         loop {
             tokio::time::sleep(std::time::Duration::from_millis(100)).await;
             {
-                ctx.bitmap.write().await.periodic_send_changes();
+                board.bitmap.write().await.periodic_send_changes();
             }
         }
     }
@@ -123,22 +330,154 @@ impl BitmapServer {
         }
     }
 
-    async fn do_save(ctx: &Arc<SharedServerContext>) {
-        if let Err(e) = ctx.metrics.save_to_file(METRICS_PATH) {
+    async fn toggle_log_task(
+        board_name: String,
+        toggle_log: Arc<ToggleLog>,
+        interval: Duration,
+    ) -> PResult<()> {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = toggle_log.flush() {
+                log::error!(
+                    "Failed to flush toggle log of board \"{}\": {}",
+                    board_name,
+                    e
+                );
+            }
+        }
+    }
+
+    async fn snapshot_task(ctx: Arc<SharedServerContext>) -> PResult<()> {
+        let interval = Duration::from_secs(ctx.settings.snapshot_interval_secs);
+        loop {
+            tokio::time::sleep(interval).await;
+            Self::do_snapshot(&ctx).await;
+        }
+    }
+
+    /// Samples the toggle counter of a board every second to keep its toggle rate up to date.
+    async fn toggle_rate_task(board: Arc<Board>) -> PResult<()> {
+        let sample_count = TOGGLE_RATE_WINDOW.as_secs() as usize + 1;
+        let mut samples: VecDeque<(Instant, u64)> = VecDeque::with_capacity(sample_count);
+
+        loop {
+            if samples.len() == sample_count {
+                samples.pop_front();
+            }
+            samples.push_back((
+                Instant::now(),
+                board.metrics.bit_toggles.load(Ordering::Relaxed),
+            ));
+
+            if let (Some(first), Some(last)) = (samples.front(), samples.back()) {
+                let elapsed = last.0.duration_since(first.0).as_secs_f64();
+                if elapsed > 0.0 {
+                    let rate = (last.1 - first.1) as f64 / elapsed;
+                    board
+                        .metrics
+                        .toggles_per_second
+                        .store(rate.round() as u32, Ordering::Relaxed);
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Saves the metrics and the state of every board. Returns whether everything was saved.
+    async fn do_save(ctx: &Arc<SharedServerContext>) -> bool {
+        let mut saved = true;
+
+        let metrics_path = ctx.settings.metrics_path();
+        if let Err(e) = ctx.metrics.save_to_file(&ctx.boards, metrics_path) {
             log::error!("Failed to save metrics: {}", e);
+            saved = false;
         } else {
             log::info!("Metrics saved.");
         }
 
-        if let Err(e) = ctx.bitmap.write().await.save_to_file(STATE_PATH) {
-            log::error!("Failed to save state: {}", e);
-        } else {
-            log::info!("State saved.");
+        for board in ctx.boards.iter() {
+            let bitmap = board.bitmap.write().await;
+            saved &= Self::save_board(ctx, board, &bitmap);
+        }
+
+        saved
+    }
+
+    /// Saves the state of a board and resets its toggle log. The bitmap must be locked for
+    /// writing, so no flips are recorded between saving and resetting.
+    fn save_board(ctx: &SharedServerContext, board: &Board, bitmap: &Bitmap) -> bool {
+        let state_path = ctx.settings.state_path(&board.name);
+        if let Err(e) = bitmap.save_to_file(state_path) {
+            log::error!("Failed to save state of board \"{}\": {}", board.name, e);
+            return false;
+        }
+        log::info!("State of board \"{}\" saved.", board.name);
+
+        if let Some(toggle_log) = &board.toggle_log {
+            if let Err(e) = toggle_log.reset(bitmap.state_checksum()) {
+                log::error!("Failed to reset toggle log of \"{}\": {}", board.name, e);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Takes a snapshot of every board and deletes the snapshots past `max_snapshots`. Returns
+    /// the timestamp of the snapshots if all of them were taken.
+    async fn do_snapshot(ctx: &Arc<SharedServerContext>) -> Option<u64> {
+        let timestamp = unix_millis();
+        let mut taken = true;
+
+        for board in ctx.boards.iter() {
+            let bitmap = board.bitmap.write().await;
+            if let Err(e) = ctx.snapshots.save(&board.name, timestamp, &bitmap) {
+                log::error!("Failed to snapshot board \"{}\": {}", board.name, e);
+                taken = false;
+                continue;
+            }
+            drop(bitmap);
+            log::info!("Snapshot {} of board \"{}\" taken.", timestamp, board.name);
+
+            match ctx.snapshots.prune(&board.name, ctx.settings.max_snapshots) {
+                Ok(0) => {}
+                Ok(deleted) => {
+                    log::info!(
+                        "Deleted {} old snapshots of board \"{}\".",
+                        deleted,
+                        board.name
+                    )
+                }
+                Err(e) => log::error!("Failed to prune snapshots of \"{}\": {}", board.name, e),
+            }
+        }
+
+        taken.then_some(timestamp)
+    }
+
+    /// Reloads the locked regions of every board from their files. Boards whose file can't
+    /// be loaded keep their current locks.
+    pub fn reload_locks(&self) {
+        Self::do_reload_locks(&self.ctx);
+    }
+
+    fn do_reload_locks(ctx: &SharedServerContext) {
+        for board in ctx.boards.iter() {
+            let path = ctx.settings.locks_path(&board.name);
+            match RegionLocks::load_from_file(path, &board.geometry) {
+                Ok(locks) => {
+                    board.locks.send_replace(Arc::new(locks));
+                    log::info!("Locks of board \"{}\" reloaded.", board.name);
+                }
+                Err(e) => {
+                    log::error!("Failed to reload locks of board \"{}\": {}", board.name, e);
+                }
+            }
         }
     }
 
-    async fn net_task(ctx: Arc<SharedServerContext>) -> PResult<()> {
-        let listener = tokio::net::TcpListener::bind(&ctx.settings.bind_address).await?;
+    async fn net_task(ctx: Arc<SharedServerContext>, listener: TcpListener) -> PResult<()> {
         log::info!("Server running on {}", listener.local_addr()?);
 
         let mut incoming = TcpListenerStream::new(listener);
@@ -156,6 +495,7 @@ impl BitmapServer {
 
                 log::debug!("[Client{}] Task finished", client_id);
 
+                ctx.clients.lock().unwrap().remove(&client_id);
                 ctx.metrics.dec_clients();
             });
         }
@@ -170,6 +510,14 @@ impl BitmapServer {
     ) -> PResult<()> {
         let socket = socket?;
         let peer_addr = socket.peer_addr().ok();
+        // Messages are small and latency sensitive, don't let them wait for ACKs.
+        if let Err(e) = socket.set_nodelay(true) {
+            log::warn!(
+                "Failed to disable Nagle's algorithm for {:?}: {}",
+                peer_addr,
+                e
+            );
+        }
         let mut server = Server::new(socket.compat());
 
         if ctx.settings.ws_permessage_deflate {
@@ -178,7 +526,7 @@ impl BitmapServer {
             server.add_extension(deflate);
         }
 
-        let websocket_key = {
+        let (websocket_key, board) = {
             let req = server.receive_request().await;
             let req = match req {
                 Ok(req) => req,
@@ -186,7 +534,17 @@ impl BitmapServer {
                     return Self::try_handle_as_http(ctx, server).await;
                 }
             };
-            req.key()
+            (req.key(), Self::select_board(ctx, req.path()).cloned())
+        };
+
+        let board = match board {
+            Some(board) => board,
+            None => {
+                log::debug!("[Client{}] Requested an unknown board", client_id);
+                let reject = Response::Reject { status_code: 404 };
+                server.send_response(&reject).await?;
+                return Ok(());
+            }
         };
 
         let ip = peer_addr.map(|addr| addr.ip());
@@ -197,7 +555,12 @@ impl BitmapServer {
         };
 
         if let Some(ip) = ip {
-            log::info!("[Client{}] New connection from {}", client_id, ip);
+            log::info!(
+                "[Client{}] New connection from {} to board \"{}\"",
+                client_id,
+                ip,
+                board.name
+            );
         }
 
         let accept = Response::Accept {
@@ -207,8 +570,7 @@ impl BitmapServer {
         server.send_response(&accept).await?;
 
         let mut builder = server.into_builder();
-        builder.set_max_message_size(512 * 1024);
-        builder.set_max_message_size(512 * 1024);
+        builder.set_max_message_size(MAX_MESSAGE_SIZE);
         let (mut sender, mut receiver) = builder.finish();
 
         let mut send_data = Vec::new();
@@ -217,8 +579,8 @@ impl BitmapServer {
         {
             let hello = MessageMut::create_message(MessageType::Hello, &mut send_data)?;
             if let MessageMut::Hello(hello) = hello {
-                hello.version_major = PROTOCOL_VERSION_MAJOR;
-                hello.version_minor = PROTOCOL_VERSION_MINOR;
+                hello.version_major.set(PROTOCOL_VERSION_MAJOR);
+                hello.version_minor.set(PROTOCOL_VERSION_MINOR);
             }
 
             sender.send_binary(&send_data).await?;
@@ -226,30 +588,49 @@ impl BitmapServer {
 
         let sender = Arc::new(Mutex::new(sender));
         let (ctm_sender, mut ctm_receiver) = mpsc::channel::<ClientTaskMessage>(8);
+        let client = Arc::new(ClientState::default());
+        ctx.clients
+            .lock()
+            .unwrap()
+            .insert(client_id, client.clone());
 
         let mut recv_task: JoinHandle<PResult<()>> = {
             let ctx = ctx.clone();
+            let board = board.clone();
             let sender = sender.clone();
             let ctm_sender = ctm_sender.clone();
+            let client = client.clone();
             tokio::spawn(async move {
                 let mut send_data = Vec::new();
+                let mut followup_data = Vec::new();
 
                 loop {
                     let data_type = receiver.receive_data(&mut recv_data).await?;
 
-                    BitmapServer::client_task_receive(
+                    let result = BitmapServer::client_task_receive(
                         &ctx,
+                        &board,
+                        &client,
                         data_type,
                         &recv_data,
                         &mut send_data,
+                        &mut followup_data,
                         &ctm_sender,
                     )
-                    .await?;
+                    .await;
 
+                    // Send the response even if the task is about to end, so the client can
+                    // learn why it's being disconnected.
                     if !send_data.is_empty() {
-                        sender.lock().await.send_binary(&send_data).await?;
+                        let mut sender = sender.lock().await;
+                        sender.send_binary(&send_data).await?;
+                        if !followup_data.is_empty() {
+                            sender.send_binary(&followup_data).await?;
+                        }
                     }
 
+                    result?;
+
                     recv_data.clear();
                 }
             })
@@ -265,17 +646,26 @@ impl BitmapServer {
             })
         };
 
-        let mut update_receiver = None;
+        let mut ping_task: JoinHandle<PResult<()>> = {
+            let ctm_sender = ctm_sender.clone();
+            let interval = std::time::Duration::from_secs(ctx.settings.ping_interval_secs);
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    ctm_sender.send(ClientTaskMessage::SendPing).await?;
+                }
+            })
+        };
 
-        async fn cond_recv_update(
-            receiver: &mut Option<broadcast::Receiver<Change>>,
-        ) -> Option<Change> {
-            if let Some(receiver) = receiver {
-                receiver.recv().await.ok()
-            } else {
-                std::future::pending().await
-            }
-        }
+        let mut update_receivers: StreamMap<u16, BroadcastStream<Change>> = StreamMap::new();
+        let mut ping_counter = 0u64;
+        // The payload and send time of the last unanswered ping
+        let mut pending_ping: Option<(u64, Instant)> = None;
+        let mut missed_pings = 0;
+        // Versions of the full states pushed after the client lagged behind, by chunk. Updates
+        // up to that version are already included in the full state.
+        let mut resynced_versions: HashMap<u16, u32> = HashMap::new();
+        let mut locks_receiver = board.locks.subscribe();
 
         loop {
             // log::info!("[Client{}] Client task loop", client_id);
@@ -287,93 +677,733 @@ impl BitmapServer {
                 res = &mut stats_task => {
                     return res?;
                 }
-                msg = cond_recv_update(&mut update_receiver) => {
-                    if let Some(msg) = msg {
-                        let psu = MessageMut::create_message(MessageType::PartialStateUpdate, &mut send_data)?;
-                        if let MessageMut::PartialStateUpdate(psu) = psu {
-                            psu.offset = msg.byte_array_offset;
-                            psu.chunk = msg.chunk_data;
+                res = &mut ping_task => {
+                    return res?;
+                }
+                Some((chunk, msg)) = update_receivers.next(), if !update_receivers.is_empty() => {
+                    match msg {
+                        Ok(msg) => {
+                            if let Some(&version) = resynced_versions.get(&chunk) {
+                                if msg.version.wrapping_sub(version) as i32 <= 0 {
+                                    continue;
+                                }
+                                resynced_versions.remove(&chunk);
+                            }
+
+                            let mut sender = sender.lock().await;
+                            let update_flags = client.update_flags.load(Ordering::Relaxed);
+
+                            match &msg.bits {
+                                Some(bits) if update_flags & UPDATE_FLAG_ALLOW_SPARSE != 0 => {
+                                    if !bits.is_empty() {
+                                        Self::create_sparse_update(msg.chunk_index as u16, bits, &mut send_data)?;
+                                        sender.send_binary(&send_data).await?;
+                                    }
+                                }
+                                _ => {
+                                    for window in msg.windows.iter() {
+                                        let psu = MessageMut::create_variable_message(
+                                            MessageType::PartialStateUpdate,
+                                            window.chunk_data.len(),
+                                            &mut send_data,
+                                        )?;
+                                        if let MessageMut::PartialStateUpdate(psu, data) = psu {
+                                            psu.offset.set(window.byte_array_offset);
+                                            data.copy_from_slice(&window.chunk_data);
+                                        }
+
+                                        sender.send_binary(&send_data).await?;
+                                    }
+                                }
+                            }
+
+                            if client.has_capability(CAPABILITY_CHUNK_VERSIONS) {
+                                Self::create_chunk_version(msg.chunk_index as u16, msg.version, &mut send_data)?;
+                                sender.send_binary(&send_data).await?;
+                            }
+                        }
+                        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                            let lag_events = client.lag_events.fetch_add(1, Ordering::Relaxed) + 1;
+                            log::info!("[Client{}] Skipped {} updates of chunk {}, sending full state ({} times so far)", client_id, skipped, chunk, lag_events);
+                            ctx.metrics.inc_lag_events(skipped);
+
+                            let mut sender = sender.lock().await;
+                            if client.has_capability(CAPABILITY_CHUNK_VERSIONS) {
+                                let resync = MessageMut::create_message(MessageType::ChunkResync, &mut send_data)?;
+                                if let MessageMut::ChunkResync(resync) = resync {
+                                    resync.chunk_index.set(chunk);
+                                }
+
+                                sender.send_binary(&send_data).await?;
+                            }
+
+                            let version = if client.has_capability(CAPABILITY_COMPRESSED_FULL_STATE) {
+                                Self::create_compressed_full_state_response(&board, chunk, &mut send_data).await?
+                            } else {
+                                Self::create_full_state_response(&board, chunk, &mut send_data).await?
+                            };
+                            sender.send_binary(&send_data).await?;
+
+                            if client.has_capability(CAPABILITY_CHUNK_VERSIONS) {
+                                Self::create_chunk_version(chunk, version, &mut send_data)?;
+                                sender.send_binary(&send_data).await?;
+                            }
+
+                            resynced_versions.insert(chunk, version);
+                        }
+                    }
+                }
+                Ok(()) = locks_receiver.changed() => {
+                    if client.has_capability(CAPABILITY_LOCKED_RANGES) {
+                        let locks = locks_receiver.borrow_and_update().clone();
+                        let mut sender = sender.lock().await;
+                        for &chunk in update_receivers.keys() {
+                            Self::create_locked_ranges(&board, &locks, chunk, &mut send_data)?;
+                            sender.send_binary(&send_data).await?;
                         }
-
-                        sender.lock().await.send_binary(&send_data).await?;
                     }
                 }
                 msg = ctm_receiver.recv() => {
                     if let Some(ClientTaskMessage::Subscribe { chunk }) = msg {
                         log::debug!("[Client{}] Received subscribe message for chunk {}", client_id, chunk);
-                        let mut bitmap = ctx.bitmap.write().await;
-                        update_receiver = Some(bitmap.subscribe(chunk as usize));
+                        update_receivers.clear();
+                        resynced_versions.clear();
+                        let receiver = board.bitmap.write().await.subscribe(chunk as usize);
+                        if let Some(receiver) = receiver {
+                            update_receivers.insert(chunk, BroadcastStream::new(receiver));
+                            if client.has_capability(CAPABILITY_LOCKED_RANGES) {
+                                let locks = board.locks.borrow().clone();
+                                Self::create_locked_ranges(&board, &locks, chunk, &mut send_data)?;
+                                sender.lock().await.send_binary(&send_data).await?;
+                            }
+                        }
+                    } else if let Some(ClientTaskMessage::AddSubscription { chunk }) = msg {
+                        log::debug!("[Client{}] Received add subscription message for chunk {}", client_id, chunk);
+                        if update_receivers.contains_key(&chunk) {
+                            continue;
+                        }
+
+                        if update_receivers.len() >= ctx.settings.max_subscriptions {
+                            log::debug!("[Client{}] Subscription limit reached, ignoring chunk {}", client_id, chunk);
+                            Self::create_error_response(
+                                &ProtocolError::TooManySubscriptions,
+                                MessageType::AddPartialStateSubscription as u8,
+                                chunk as u32,
+                                &mut send_data,
+                            )?;
+                            sender.lock().await.send_binary(&send_data).await?;
+                            continue;
+                        }
+
+                        let receiver = board.bitmap.write().await.subscribe(chunk as usize);
+                        if let Some(receiver) = receiver {
+                            update_receivers.insert(chunk, BroadcastStream::new(receiver));
+                            if client.has_capability(CAPABILITY_LOCKED_RANGES) {
+                                let locks = board.locks.borrow().clone();
+                                Self::create_locked_ranges(&board, &locks, chunk, &mut send_data)?;
+                                sender.lock().await.send_binary(&send_data).await?;
+                            }
+                        }
+                    } else if let Some(ClientTaskMessage::RemoveSubscription { chunk }) = msg {
+                        log::debug!("[Client{}] Received remove subscription message for chunk {}", client_id, chunk);
+                        update_receivers.remove(&chunk);
+                        resynced_versions.remove(&chunk);
                     } else if let Some(ClientTaskMessage::UnsubscribeAll) = msg {
                         log::debug!("[Client{}] Received unsubscribe all message", client_id);
-                        update_receiver = None;
+                        update_receivers.clear();
+                        resynced_versions.clear();
                     } else if let Some(ClientTaskMessage::SendStats) = msg {
                         log::debug!("[Client{}] Received send stats message", client_id);
                         let stats = MessageMut::create_message(MessageType::Stats, &mut send_data)?;
                         if let MessageMut::Stats(stats) = stats {
-                            stats.current_clients = ctx.metrics.clients.load(Ordering::Relaxed);
+                            let server_timestamp = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default();
+
+                            stats.current_clients.set(ctx.metrics.clients.load(Ordering::Relaxed));
+                            stats.checked_bits.set(board.metrics.checked_bits.load(Ordering::Relaxed));
+                            stats.toggles_per_second.set(board.metrics.toggles_per_second.load(Ordering::Relaxed));
+                            stats.uptime_seconds.set(ctx.started_at.elapsed().as_secs() as u32);
+                            stats.server_timestamp.set(server_timestamp.as_millis() as u64);
                         }
 
                         sender.lock().await.send_binary(&send_data).await?;
+                    } else if let Some(ClientTaskMessage::SendPing) = msg {
+                        if client.version_minor.load(Ordering::Relaxed) < PING_MIN_VERSION_MINOR {
+                            continue;
+                        }
+
+                        if pending_ping.is_some() {
+                            missed_pings += 1;
+                            if missed_pings >= ctx.settings.max_missed_pings {
+                                log::info!("[Client{}] Missed {} pings, disconnecting", client_id, missed_pings);
+                                recv_task.abort();
+                                sender.lock().await.close().await?;
+                                return Ok(());
+                            }
+                        }
+
+                        ping_counter += 1;
+                        pending_ping = Some((ping_counter, Instant::now()));
+
+                        let ping = MessageMut::create_message(MessageType::Ping, &mut send_data)?;
+                        if let MessageMut::Ping(ping) = ping {
+                            ping.payload.set(ping_counter);
+                        }
+
+                        sender.lock().await.send_binary(&send_data).await?;
+                    } else if let Some(ClientTaskMessage::Pong { payload }) = msg {
+                        if let Some((ping_payload, sent_at)) = pending_ping {
+                            if ping_payload == payload {
+                                let rtt = sent_at.elapsed();
+                                log::debug!("[Client{}] Round-trip time: {:?}", client_id, rtt);
+                                ctx.metrics.rtt.observe(rtt);
+                                pending_ping = None;
+                                missed_pings = 0;
+                            }
+                        }
                     }
                 }
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn client_task_receive(
         ctx: &Arc<SharedServerContext>,
+        board: &Board,
+        client: &ClientState,
         data_type: Data,
-        recv_data: &Vec<u8>,
+        recv_data: &[u8],
         send_data: &mut Vec<u8>,
+        followup_data: &mut Vec<u8>,
         ctm_sender: &mpsc::Sender<ClientTaskMessage>,
     ) -> PResult<()> {
+        send_data.clear();
+        followup_data.clear();
+
         if !data_type.is_binary() {
             return Ok(());
         }
 
-        let message = Message::from_slice(&recv_data)?;
+        let request_type = recv_data.first().copied().unwrap_or_default();
+        let message = match Message::from_slice(recv_data) {
+            Ok(message) => message,
+            Err(e) => {
+                log::debug!("Received invalid message: {}", e);
+                return Self::reject_invalid_request(ctx, &e, request_type, 0, send_data);
+            }
+        };
+
         if !message.id().is_client_message() {
-            return Ok(());
+            let error = ProtocolError::UnexpectedMessage;
+            return Self::reject_invalid_request(ctx, &error, request_type, 0, send_data);
         }
 
-        send_data.clear();
+        // Clients older than 1.14 assume the default geometry and would misinterpret every
+        // index on other boards, so they have to announce their version first.
+        let custom_geometry = board.geometry != BitmapGeometry::DEFAULT;
+        if custom_geometry
+            && !matches!(message, Message::ClientHello(_))
+            && client.version_minor.load(Ordering::Relaxed) < BOARD_INFO_MIN_VERSION_MINOR
+        {
+            let error = ProtocolError::InvalidMessageVersion;
+            Self::create_error_response(&error, request_type, 0, send_data)?;
+            return Err(Box::new(error));
+        }
+
+        // Every chunk-addressed message is validated here, so the handlers below can index
+        // the bitmap directly.
+        if let Some(chunk_index) = message.chunk_index() {
+            if chunk_index as usize >= board.geometry.chunk_count {
+                let error = ProtocolError::InvalidIndex;
+                let context = chunk_index as u32;
+                return Self::reject_invalid_request(ctx, &error, request_type, context, send_data);
+            }
+        }
 
         match message {
-            Message::ChunkFullStateRequest(msg) => {
-                let full_state =
-                    MessageMut::create_message(MessageType::ChunkFullStateResponse, send_data)?;
+            Message::ClientHello(msg) => {
+                if msg.version_major.get() != PROTOCOL_VERSION_MAJOR {
+                    let error = ProtocolError::InvalidMessageVersion;
+                    let version = u32::from(msg.version_major.get());
+                    Self::create_error_response(&error, request_type, version, send_data)?;
+                    return Err(Box::new(error));
+                }
 
-                if let MessageMut::ChunkFullStateResponse(full_state) = full_state {
-                    let bitmap = ctx.bitmap.read().await;
-                    full_state.chunk_index = msg.chunk_index;
-                    full_state
-                        .bitmap
-                        .copy_from_slice(bitmap.as_raw_slice(msg.chunk_index as usize));
+                if custom_geometry && msg.version_minor.get() < BOARD_INFO_MIN_VERSION_MINOR {
+                    let error = ProtocolError::InvalidMessageVersion;
+                    let version = u32::from(msg.version_minor.get());
+                    Self::create_error_response(&error, request_type, version, send_data)?;
+                    return Err(Box::new(error));
+                }
+
+                let capabilities = msg.capabilities.get() & SUPPORTED_CAPABILITIES;
+                client.capabilities.store(capabilities, Ordering::Relaxed);
+                client
+                    .version_minor
+                    .store(msg.version_minor.get(), Ordering::Relaxed);
+                if capabilities & CAPABILITY_SPARSE_UPDATES != 0 {
+                    client
+                        .update_flags
+                        .fetch_or(UPDATE_FLAG_ALLOW_SPARSE, Ordering::Relaxed);
+                }
+
+                let ack = MessageMut::create_message(MessageType::ClientHelloAck, send_data)?;
+                if let MessageMut::ClientHelloAck(ack) = ack {
+                    ack.capabilities.set(capabilities);
+                }
+
+                // Older clients don't know the message, and are only accepted on boards with the
+                // default geometry.
+                if msg.version_minor.get() >= BOARD_INFO_MIN_VERSION_MINOR {
+                    Self::create_board_info(board, followup_data)?;
+                }
+            }
+            Message::Ping(msg) => {
+                let pong = MessageMut::create_message(MessageType::Pong, send_data)?;
+                if let MessageMut::Pong(pong) = pong {
+                    pong.payload.set(msg.payload.get());
+                }
+            }
+            Message::Pong(msg) => {
+                ctm_sender
+                    .send(ClientTaskMessage::Pong {
+                        payload: msg.payload.get(),
+                    })
+                    .await?;
+            }
+            Message::ChunkFullStateRequest(msg) => {
+                let version = if client.has_capability(CAPABILITY_COMPRESSED_FULL_STATE) {
+                    Self::create_compressed_full_state_response(
+                        board,
+                        msg.chunk_index.get(),
+                        send_data,
+                    )
+                    .await?
+                } else {
+                    Self::create_full_state_response(board, msg.chunk_index.get(), send_data)
+                        .await?
+                };
+
+                if client.has_capability(CAPABILITY_CHUNK_VERSIONS) {
+                    Self::create_chunk_version(msg.chunk_index.get(), version, followup_data)?;
+                }
+            }
+            Message::ChunkFullStateRequestWithFlags(msg) => {
+                let version = if msg.flags & FULL_STATE_FLAG_ALLOW_COMPRESSION != 0 {
+                    Self::create_compressed_full_state_response(
+                        board,
+                        msg.chunk_index.get(),
+                        send_data,
+                    )
+                    .await?
+                } else {
+                    Self::create_full_state_response(board, msg.chunk_index.get(), send_data)
+                        .await?
+                };
+
+                if client.has_capability(CAPABILITY_CHUNK_VERSIONS) {
+                    Self::create_chunk_version(msg.chunk_index.get(), version, followup_data)?;
                 }
             }
             Message::ToggleBit(msg) => {
-                let idx = msg.index as usize;
+                let idx = msg.index.get() as usize;
                 log::debug!("Received toggle bit: {}", idx);
-                let addend = ctx.bitmap.read().await.toggle(idx);
-                ctx.metrics.inc_checked_bits(addend as i32);
-                ctx.metrics.inc_bit_toggles();
+                let bitmap = board.bitmap.read().await;
+                if idx >= bitmap.len() {
+                    let error = ProtocolError::InvalidIndex;
+                    let context = msg.index.get();
+                    return Self::reject_invalid_request(
+                        ctx,
+                        &error,
+                        request_type,
+                        context,
+                        send_data,
+                    );
+                }
+
+                if board.is_locked(idx) {
+                    let error = ProtocolError::ReadOnly;
+                    let context = msg.index.get();
+                    return Self::create_error_response(&error, request_type, context, send_data);
+                }
+
+                let addend = bitmap.toggle(idx);
+                board.metrics.inc_checked_bits(addend as i32);
+                board.metrics.inc_bit_toggles();
+            }
+            Message::ToggleBits(msgs) => {
+                log::debug!("Received toggle bits: {} bits", msgs.len());
+                let locks = board.locks.borrow().clone();
+                let bitmap = board.bitmap.read().await;
+                let (addend, toggled) = bitmap.toggle_many(
+                    msgs.iter()
+                        .map(|msg| msg.index.get() as usize)
+                        .filter(|&idx| !locks.is_locked(idx)),
+                );
+                board.metrics.inc_checked_bits(addend);
+                board.metrics.add_bit_toggles(toggled as u64);
+
+                // Valid and unlocked indices are still toggled, the error only reports the first
+                // rejected one.
+                let invalid = msgs
+                    .iter()
+                    .find(|msg| msg.index.get() as usize >= bitmap.len());
+                if let Some(msg) = invalid {
+                    let error = ProtocolError::InvalidIndex;
+                    let context = msg.index.get();
+                    return Self::reject_invalid_request(
+                        ctx,
+                        &error,
+                        request_type,
+                        context,
+                        send_data,
+                    );
+                }
+
+                let locked = msgs
+                    .iter()
+                    .find(|msg| locks.is_locked(msg.index.get() as usize));
+                if let Some(msg) = locked {
+                    let error = ProtocolError::ReadOnly;
+                    let context = msg.index.get();
+                    return Self::create_error_response(&error, request_type, context, send_data);
+                }
+            }
+            Message::SetBit(msg) => {
+                let idx = msg.index.get() as usize;
+                let value = msg.value != 0;
+                log::debug!("Received set bit: {} = {}", idx, value);
+                let bitmap = board.bitmap.read().await;
+                if idx >= bitmap.len() {
+                    let error = ProtocolError::InvalidIndex;
+                    let context = msg.index.get();
+                    return Self::reject_invalid_request(
+                        ctx,
+                        &error,
+                        request_type,
+                        context,
+                        send_data,
+                    );
+                }
+
+                if board.is_locked(idx) {
+                    let error = ProtocolError::ReadOnly;
+                    let context = msg.index.get();
+                    return Self::create_error_response(&error, request_type, context, send_data);
+                }
+
+                let addend = bitmap.set(idx, value);
+                board.metrics.inc_checked_bits(addend);
+                if addend != 0 {
+                    board.metrics.inc_bit_toggles();
+                }
             }
             Message::PartialStateSubscription(msg) => {
                 ctm_sender
                     .send(ClientTaskMessage::Subscribe {
-                        chunk: msg.chunk_index,
+                        chunk: msg.chunk_index.get(),
                     })
                     .await?;
             }
             Message::PartialStateUnsubscription => {
                 ctm_sender.send(ClientTaskMessage::UnsubscribeAll).await?;
             }
+            Message::SetUpdateFlags(msg) => {
+                client.update_flags.store(msg.flags, Ordering::Relaxed);
+            }
+            Message::AddPartialStateSubscription(msg) => {
+                ctm_sender
+                    .send(ClientTaskMessage::AddSubscription {
+                        chunk: msg.chunk_index.get(),
+                    })
+                    .await?;
+            }
+            Message::RemovePartialStateSubscription(msg) => {
+                ctm_sender
+                    .send(ClientTaskMessage::RemoveSubscription {
+                        chunk: msg.chunk_index.get(),
+                    })
+                    .await?;
+            }
+            Message::ChunkChecksumRequest(msgs) => {
+                // Keeps the response within MAX_MESSAGE_SIZE, repeated indices included.
+                if msgs.len() > board.geometry.chunk_count {
+                    let error = ProtocolError::InvalidMessageSize;
+                    return Self::reject_invalid_request(
+                        ctx,
+                        &error,
+                        request_type,
+                        msgs.len() as u32,
+                        send_data,
+                    );
+                }
+
+                let invalid = msgs
+                    .iter()
+                    .find(|msg| msg.chunk_index.get() as usize >= board.geometry.chunk_count);
+                if let Some(msg) = invalid {
+                    let error = ProtocolError::InvalidIndex;
+                    let context = msg.chunk_index.get() as u32;
+                    return Self::reject_invalid_request(
+                        ctx,
+                        &error,
+                        request_type,
+                        context,
+                        send_data,
+                    );
+                }
+
+                let chunk_indices: Vec<u16> = if msgs.is_empty() {
+                    (0..board.geometry.chunk_count)
+                        .map(|chunk_index| chunk_index as u16)
+                        .collect()
+                } else {
+                    msgs.iter().map(|msg| msg.chunk_index.get()).collect()
+                };
+                Self::create_checksum_response(board, &chunk_indices, send_data).await?;
+            }
             _ => (),
         }
 
         Ok(())
     }
 
+    /// Responds with an error and counts the request as invalid.
+    fn reject_invalid_request(
+        ctx: &Arc<SharedServerContext>,
+        error: &ProtocolError,
+        request_type: u8,
+        context: u32,
+        send_data: &mut Vec<u8>,
+    ) -> PResult<()> {
+        ctx.metrics.inc_invalid_requests();
+        Self::create_error_response(error, request_type, context, send_data)
+    }
+
+    fn create_error_response(
+        error: &ProtocolError,
+        request_type: u8,
+        context: u32,
+        send_data: &mut Vec<u8>,
+    ) -> PResult<()> {
+        let response = MessageMut::create_message(MessageType::Error, send_data)?;
+        if let MessageMut::Error(response) = response {
+            response.code = error.code() as u8;
+            response.request_type = request_type;
+            response.context.set(context);
+        }
+
+        Ok(())
+    }
+
+    fn create_board_info(board: &Board, send_data: &mut Vec<u8>) -> PResult<()> {
+        let geometry = board.geometry;
+        let board_info = MessageMut::create_message(MessageType::BoardInfo, send_data)?;
+        if let MessageMut::BoardInfo(board_info) = board_info {
+            board_info.chunk_size.set(geometry.chunk_size as u32);
+            board_info.chunk_count.set(geometry.chunk_count as u32);
+            board_info
+                .update_chunk_size
+                .set(geometry.update_chunk_size as u16);
+        }
+
+        Ok(())
+    }
+
+    fn create_locked_ranges(
+        board: &Board,
+        locks: &RegionLocks,
+        chunk_index: u16,
+        send_data: &mut Vec<u8>,
+    ) -> PResult<()> {
+        let ranges = locks.ranges_in_chunk(chunk_index as usize, board.geometry.chunk_size);
+        let message = MessageMut::create_variable_message(
+            MessageType::LockedRanges,
+            ranges.len() * size_of::<LockedRangeMessage>(),
+            send_data,
+        )?;
+
+        if let MessageMut::LockedRanges(message, entries) = message {
+            message.chunk_index.set(chunk_index);
+            for (entry, range) in entries.iter_mut().zip(ranges) {
+                entry.start.set(range.start as u32);
+                entry.end.set(range.end as u32);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the version of the chunk the response was created from.
+    async fn create_full_state_response(
+        board: &Board,
+        chunk_index: u16,
+        send_data: &mut Vec<u8>,
+    ) -> PResult<u32> {
+        let full_state = MessageMut::create_variable_message(
+            MessageType::ChunkFullStateResponse,
+            board.geometry.chunk_size_bytes(),
+            send_data,
+        )?;
+
+        let bitmap = board.bitmap.read().await;
+        if let MessageMut::ChunkFullStateResponse(full_state, data) = full_state {
+            full_state.chunk_index.set(chunk_index);
+            data.copy_from_slice(bitmap.as_raw_slice(chunk_index as usize));
+        }
+
+        Ok(bitmap.chunk_version(chunk_index as usize))
+    }
+
+    /// Encodes the chunk with whichever encoding is the smallest for its current contents.
+    /// Returns the version of the chunk the response was created from.
+    async fn create_compressed_full_state_response(
+        board: &Board,
+        chunk_index: u16,
+        send_data: &mut Vec<u8>,
+    ) -> PResult<u32> {
+        let mut encoded = Vec::new();
+        let (encoding, version) = {
+            let bitmap = board.bitmap.read().await;
+            let encoding =
+                encoding::encode_smallest(bitmap.as_raw_slice(chunk_index as usize), &mut encoded);
+            (encoding, bitmap.chunk_version(chunk_index as usize))
+        };
+
+        let full_state = MessageMut::create_variable_message(
+            MessageType::CompressedChunkFullStateResponse,
+            encoded.len(),
+            send_data,
+        )?;
+
+        if let MessageMut::CompressedChunkFullStateResponse(full_state, data) = full_state {
+            full_state.chunk_index.set(chunk_index);
+            full_state.encoding = encoding as u8;
+            data.copy_from_slice(&encoded);
+        }
+
+        Ok(version)
+    }
+
+    fn create_chunk_version(
+        chunk_index: u16,
+        version: u32,
+        send_data: &mut Vec<u8>,
+    ) -> PResult<()> {
+        let chunk_version = MessageMut::create_message(MessageType::ChunkVersion, send_data)?;
+        if let MessageMut::ChunkVersion(chunk_version) = chunk_version {
+            chunk_version.chunk_index.set(chunk_index);
+            chunk_version.version.set(version);
+        }
+
+        Ok(())
+    }
+
+    async fn create_checksum_response(
+        board: &Board,
+        chunk_indices: &[u16],
+        send_data: &mut Vec<u8>,
+    ) -> PResult<()> {
+        let response = MessageMut::create_variable_message(
+            MessageType::ChunkChecksumResponse,
+            chunk_indices.len() * size_of::<ChunkChecksumMessage>(),
+            send_data,
+        )?;
+
+        if let MessageMut::ChunkChecksumResponse(entries) = response {
+            let bitmap = board.bitmap.read().await;
+            for (entry, &chunk_index) in entries.iter_mut().zip(chunk_indices) {
+                entry.chunk_index.set(chunk_index);
+                entry
+                    .checksum
+                    .set(bitmap.chunk_checksum(chunk_index as usize));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn create_sparse_update(
+        chunk_index: u16,
+        bits: &[(u32, bool)],
+        send_data: &mut Vec<u8>,
+    ) -> PResult<()> {
+        let update = MessageMut::create_variable_message(
+            MessageType::SparsePartialStateUpdate,
+            bits.len() * size_of::<u32>(),
+            send_data,
+        )?;
+
+        if let MessageMut::SparsePartialStateUpdate(update, entries) = update {
+            update.chunk_index.set(chunk_index);
+            for (entry, &(offset, value)) in entries.chunks_exact_mut(size_of::<u32>()).zip(bits) {
+                let entry_value = if value {
+                    offset | SPARSE_UPDATE_VALUE_BIT
+                } else {
+                    offset
+                };
+                entry.copy_from_slice(&entry_value.to_le_bytes());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends the metrics of the connected clients to the Prometheus output. Counts of
+    /// individual clients are logged instead, as a label per client would grow without bound.
+    fn write_client_metrics(ctx: &SharedServerContext, output: &mut String) {
+        use std::fmt::Write;
+
+        // Only connected clients are listed, so the series disappear on disconnect.
+        let mut lagging: Vec<(u64, u64)> = ctx
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, client)| (id, client.lag_events.load(Ordering::Relaxed)))
+            .filter(|&(_, lag_events)| lag_events > 0)
+            .collect();
+        lagging.sort_unstable();
+
+        let _ = write!(
+            output,
+            "# TYPE bitmap_lagging_clients gauge\n\
+            # HELP bitmap_lagging_clients Number of connected clients that fell behind the \
+            update backlog at least once\n\
+            bitmap_lagging_clients {}\n\
+            # TYPE bitmap_client_lag_events counter\n\
+            # HELP bitmap_client_lag_events Number of times a connected client fell behind the \
+            update backlog\n",
+            lagging.len()
+        );
+        for (id, lag_events) in lagging {
+            let _ = writeln!(
+                output,
+                "bitmap_client_lag_events{{client=\"{}\"}} {}",
+                id, lag_events
+            );
+        }
+    }
+
+    /// Selects the board named by the request path (`/event`) or by the `board` query
+    /// parameter (`/?board=event`). The root path selects the default board.
+    fn select_board<'a>(ctx: &'a SharedServerContext, path: &str) -> Option<&'a Arc<Board>> {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let name = query_param(query, "board").unwrap_or_else(|| path.trim_matches('/'));
+        Self::find_board(ctx, name)
+    }
+
+    /// Finds a board by name. An empty name selects the default board.
+    fn find_board<'a>(ctx: &'a SharedServerContext, name: &str) -> Option<&'a Arc<Board>> {
+        if name.is_empty() {
+            ctx.boards.first()
+        } else {
+            ctx.boards.iter().find(|board| board.name == name)
+        }
+    }
+
     async fn try_handle_as_http(
         ctx: &Arc<SharedServerContext>,
         mut server: Server<'_, Compat<TcpStream>>,
@@ -383,39 +1413,377 @@ impl BitmapServer {
 
         let buffer = server.take_buffer();
 
-        match request.parse(buffer.as_ref()) {
-            Ok(httparse::Status::Complete(_)) => (),
+        let header_len = match request.parse(buffer.as_ref()) {
+            Ok(httparse::Status::Complete(header_len)) => header_len,
             _ => return Err(Box::new(BitmapError::InvalidHttp)),
         };
 
         let mut stream = server.into_inner();
 
-        if let Some("GET") = request.method {
-            if let Some("/metrics") = request.path {
-                let metrics = ctx.metrics.to_prometheus();
-
-                let response = format!(
-                    "HTTP/1.1 200 OK\r\n\
-					Content-Type: text/plain; version=0.0.4\r\n\
-					Content-Length: {}\r\n\
-					Connection: close\r\n\
-					\r\n\
-					{}",
-                    metrics.len(),
-                    metrics
+        let path = request.path.unwrap_or("/");
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+
+        let response = match (request.method, path) {
+            (Some("GET"), "/metrics") => {
+                let mut metrics = ctx.metrics.to_prometheus(&ctx.boards);
+                Self::write_client_metrics(ctx, &mut metrics);
+
+                HttpResponse::new("200 OK", "text/plain; version=0.0.4", metrics)
+            }
+            (Some("GET"), path) if path.starts_with("/render/") => {
+                Self::handle_render_request(ctx, &path["/render/".len()..], query).await
+            }
+            (Some("GET"), path) if path.starts_with("/history/") => {
+                Self::handle_history_request(ctx, &path["/history/".len()..], query)
+            }
+            (method, path) if path.starts_with("/admin/") => {
+                let content_length = request
+                    .headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+                    .map(|header| std::str::from_utf8(header.value).ok()?.trim().parse().ok());
+                let content_length = match content_length {
+                    None => 0,
+                    Some(Some(content_length)) => content_length,
+                    Some(None) => return Err(Box::new(BitmapError::InvalidHttp)),
+                };
+
+                // The body is only read from authorized clients.
+                if let Some(response) = Self::check_admin_token(ctx, request.headers) {
+                    response
+                } else if content_length > MAX_ADMIN_BODY_SIZE {
+                    HttpResponse::text("413 Payload Too Large", "Request body is too large")
+                } else {
+                    // The handshake may have already read a part of the body.
+                    let mut body = buffer[header_len..].to_vec();
+                    body.truncate(content_length);
+                    let read = body.len();
+                    body.resize(content_length, 0);
+                    tokio::time::timeout(ADMIN_BODY_TIMEOUT, stream.read_exact(&mut body[read..]))
+                        .await
+                        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+                    let operation = &path["/admin/".len()..];
+                    Self::handle_admin_request(ctx, method, operation, query, &body).await
+                }
+            }
+            _ => HttpResponse::text("404 Not Found", "Not found"),
+        };
+
+        response.write_to(&mut stream).await?;
+        Ok(())
+    }
+
+    /// Renders a chunk (`chunk/<index>.png`) or the density of the whole board (`board.png`)
+    /// as a PNG image. Images are cached for `render_cache_secs` if it's set.
+    async fn handle_render_request(
+        ctx: &Arc<SharedServerContext>,
+        path: &str,
+        query: &str,
+    ) -> HttpResponse {
+        let board_name = query_param(query, "board").unwrap_or_default();
+        let Some(board) = Self::find_board(ctx, board_name) else {
+            return HttpResponse::text("404 Not Found", "Unknown board");
+        };
+
+        let target = if let Some(chunk_index) = path
+            .strip_prefix("chunk/")
+            .and_then(|name| name.strip_suffix(".png"))
+        {
+            match chunk_index.parse() {
+                Ok(chunk_index) if chunk_index < board.geometry.chunk_count => {
+                    RenderTarget::Chunk(chunk_index)
+                }
+                _ => return HttpResponse::text("404 Not Found", "Unknown chunk"),
+            }
+        } else if path == "board.png" {
+            let scale = query_param(query, "scale").map_or(Ok(DEFAULT_DENSITY_SCALE), str::parse);
+            match scale {
+                Ok(scale) if scale > 0 && scale % 8 == 0 && scale <= render::CHUNK_IMAGE_WIDTH => {
+                    RenderTarget::Density { scale }
+                }
+                _ => {
+                    return HttpResponse::text(
+                        "400 Bad Request",
+                        "Scale must be a multiple of 8 up to 512",
+                    )
+                }
+            }
+        } else {
+            return HttpResponse::text("404 Not Found", "Not found");
+        };
+
+        let cache_duration = Duration::from_secs(ctx.settings.render_cache_secs);
+        let cache_control = if cache_duration.is_zero() {
+            "no-cache".to_string()
+        } else {
+            format!("public, max-age={}", cache_duration.as_secs())
+        };
+
+        let key = (board.name.clone(), target);
+        if !cache_duration.is_zero() {
+            let cache = ctx.render_cache.lock().unwrap();
+            if let Some((rendered_at, png)) = cache.get(&key) {
+                if rendered_at.elapsed() < cache_duration {
+                    return HttpResponse::new("200 OK", "image/png", png.clone())
+                        .with_header("Cache-Control", cache_control);
+                }
+            }
+        }
+
+        let bitmap = board.bitmap.read().await;
+        let png = match target {
+            RenderTarget::Chunk(chunk_index) => {
+                render::render_chunk(bitmap.as_raw_slice(chunk_index), &board.geometry)
+            }
+            RenderTarget::Density { scale } => render::render_density(&bitmap, scale),
+        };
+        drop(bitmap);
+
+        let png = match png {
+            Ok(png) => png,
+            Err(e) => {
+                log::error!(
+                    "Failed to render {:?} of board \"{}\": {}",
+                    target,
+                    board.name,
+                    e
                 );
+                return HttpResponse::text("500 Internal Server Error", "Failed to render");
+            }
+        };
+
+        if !cache_duration.is_zero() {
+            let mut cache = ctx.render_cache.lock().unwrap();
+            cache.retain(|_, (rendered_at, _)| rendered_at.elapsed() < cache_duration);
+            cache.insert(key, (Instant::now(), png.clone()));
+        }
+
+        HttpResponse::new("200 OK", "image/png", png).with_header("Cache-Control", cache_control)
+    }
+
+    /// Lists the snapshots of a board (`snapshots`), or returns a chunk as of the snapshot
+    /// taken at or before the `at` timestamp (`chunk/<index>.bin` or `chunk/<index>.png`). The
+    /// latest snapshot is used if `at` isn't given.
+    fn handle_history_request(
+        ctx: &Arc<SharedServerContext>,
+        path: &str,
+        query: &str,
+    ) -> HttpResponse {
+        let board_name = query_param(query, "board").unwrap_or_default();
+        let Some(board) = Self::find_board(ctx, board_name) else {
+            return HttpResponse::text("404 Not Found", "Unknown board");
+        };
+
+        if path == "snapshots" {
+            return match ctx.snapshots.list(&board.name) {
+                Ok(timestamps) => {
+                    HttpResponse::json("200 OK", serde_json::json!({ "snapshots": timestamps }))
+                }
+                Err(e) => {
+                    log::error!("Failed to list snapshots of \"{}\": {}", board.name, e);
+                    HttpResponse::text("500 Internal Server Error", "Failed to list snapshots")
+                }
+            };
+        }
+
+        let Some((chunk_index, extension)) = path
+            .strip_prefix("chunk/")
+            .and_then(|name| name.split_once('.'))
+        else {
+            return HttpResponse::text("404 Not Found", "Not found");
+        };
+        let chunk_index = match chunk_index.parse() {
+            Ok(chunk_index) if chunk_index < board.geometry.chunk_count => chunk_index,
+            _ => return HttpResponse::text("404 Not Found", "Unknown chunk"),
+        };
+        if extension != "bin" && extension != "png" {
+            return HttpResponse::text("404 Not Found", "Not found");
+        }
 
-                stream.write_all(response.as_bytes()).await?;
+        let at = match query_param(query, "at").map(str::parse) {
+            None => unix_millis(),
+            Some(Ok(at)) => at,
+            Some(Err(_)) => return HttpResponse::text("400 Bad Request", "Invalid timestamp"),
+        };
+
+        let chunk = ctx.snapshots.find(&board.name, at).and_then(|timestamp| {
+            let Some(timestamp) = timestamp else {
+                return Ok(None);
+            };
+            let chunk =
+                ctx.snapshots
+                    .read_chunk(&board.name, timestamp, &board.geometry, chunk_index)?;
+            let body = if extension == "png" {
+                render::render_chunk(&chunk, &board.geometry)?
             } else {
-                let response = "HTTP/1.1 404 Not Found\r\n\r\nNot found";
-                stream.write_all(response.as_bytes()).await?;
+                chunk
+            };
+            Ok(Some((timestamp, body)))
+        });
+
+        match chunk {
+            Ok(Some((timestamp, body))) => {
+                let content_type = if extension == "png" {
+                    "image/png"
+                } else {
+                    "application/octet-stream"
+                };
+                HttpResponse::new("200 OK", content_type, body)
+                    .with_header("X-Snapshot-Timestamp", timestamp.to_string())
+            }
+            Ok(None) => HttpResponse::text("404 Not Found", "No snapshot at or before that time"),
+            Err(e) => {
+                log::error!("Failed to read snapshot of \"{}\": {}", board.name, e);
+                HttpResponse::text("500 Internal Server Error", "Failed to read snapshot")
             }
         }
+    }
 
-        // let response = Self::handle_http_request(request.method, request.path);
-        // server.send_response(&response).await?;
+    /// Returns the response to an admin request without a valid token, or None if the
+    /// request is authorized.
+    fn check_admin_token(
+        ctx: &SharedServerContext,
+        headers: &[httparse::Header<'_>],
+    ) -> Option<HttpResponse> {
+        let Some(token) = ctx.settings.admin_token.as_deref() else {
+            return Some(HttpResponse::text("404 Not Found", "Not found"));
+        };
 
-        Ok(())
+        let authorized = headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("Authorization"))
+            .filter_map(|header| header.value.strip_prefix(b"Bearer "))
+            .any(|value| constant_time_eq(value, token.as_bytes()));
+        if !authorized {
+            return Some(HttpResponse::text(
+                "401 Unauthorized",
+                "Invalid admin token",
+            ));
+        }
+
+        None
+    }
+
+    /// Handles an authorized request to the admin API. Bits are modified through the bitmap
+    /// like toggles of clients, so subscribers receive the changes on the next tick, but locks
+    /// don't apply.
+    async fn handle_admin_request(
+        ctx: &Arc<SharedServerContext>,
+        method: Option<&str>,
+        operation: &str,
+        query: &str,
+        body: &[u8],
+    ) -> HttpResponse {
+        if method != Some("POST") {
+            return HttpResponse::text("405 Method Not Allowed", "Admin requests must use POST");
+        }
+
+        if operation == "save" {
+            log::info!("Save requested through the admin API.");
+            return if Self::do_save(ctx).await {
+                HttpResponse::json("200 OK", serde_json::json!({ "saved": true }))
+            } else {
+                HttpResponse::text("500 Internal Server Error", "Failed to save")
+            };
+        }
+
+        if operation == "snapshot" {
+            log::info!("Snapshot requested through the admin API.");
+            return match Self::do_snapshot(ctx).await {
+                Some(timestamp) => {
+                    HttpResponse::json("200 OK", serde_json::json!({ "timestamp": timestamp }))
+                }
+                None => HttpResponse::text("500 Internal Server Error", "Failed to take snapshot"),
+            };
+        }
+
+        let board_name = query_param(query, "board").unwrap_or_default();
+        let Some(board) = Self::find_board(ctx, board_name) else {
+            return HttpResponse::text("404 Not Found", "Unknown board");
+        };
+
+        if operation == "locks" {
+            return Self::replace_locks(ctx, board, body);
+        }
+
+        let param = |name| query_param(query, name).and_then(|value| value.parse::<usize>().ok());
+
+        let (addend, changed) = match operation {
+            "clear" | "fill" | "invert" => {
+                let (Some(start), Some(end)) = (param("start"), param("end")) else {
+                    return HttpResponse::text("400 Bad Request", "Missing start or end");
+                };
+                if start >= end || end > board.geometry.bitmap_size() {
+                    return HttpResponse::text("400 Bad Request", "Invalid range");
+                }
+
+                let mut bitmap = board.bitmap.write().await;
+                match operation {
+                    "clear" => bitmap.set_range(start..end, false),
+                    "fill" => bitmap.set_range(start..end, true),
+                    _ => bitmap.toggle_range(start..end),
+                }
+            }
+            "chunk" => {
+                let Some(chunk_index) = param("index") else {
+                    return HttpResponse::text("400 Bad Request", "Missing chunk index");
+                };
+                let offset = param("offset").unwrap_or(0);
+
+                let mut bitmap = board.bitmap.write().await;
+                match bitmap.write_chunk_bytes(chunk_index, offset, body) {
+                    Some(result) => result,
+                    None => {
+                        return HttpResponse::text(
+                            "400 Bad Request",
+                            "Data doesn't fit in the chunk",
+                        )
+                    }
+                }
+            }
+            _ => return HttpResponse::text("404 Not Found", "Unknown admin operation"),
+        };
+
+        board.metrics.add_checked_bits(addend);
+        log::info!(
+            "Admin {} on board \"{}\" changed {} bits.",
+            operation,
+            board.name,
+            changed
+        );
+
+        HttpResponse::json(
+            "200 OK",
+            serde_json::json!({
+                "changed_bits": changed,
+                "checked_bits": board.metrics.checked_bits.load(Ordering::Relaxed),
+            }),
+        )
+    }
+
+    /// Replaces the locked regions of a board with the lock file in the request body, and
+    /// saves them so they survive a restart or a reload.
+    fn replace_locks(ctx: &SharedServerContext, board: &Board, body: &[u8]) -> HttpResponse {
+        let locks = serde_json::from_slice::<LockFile>(body)
+            .map_err(|e| e.into())
+            .and_then(|file| RegionLocks::new(file, &board.geometry));
+        let locks = match locks {
+            Ok(locks) => locks,
+            Err(e) => return HttpResponse::text("400 Bad Request", &e.to_string()),
+        };
+
+        if let Err(e) = locks.save_to_file(ctx.settings.locks_path(&board.name)) {
+            log::error!("Failed to save locks of board \"{}\": {}", board.name, e);
+            return HttpResponse::text("500 Internal Server Error", "Failed to save locks");
+        }
+
+        board.locks.send_replace(Arc::new(locks));
+        log::info!(
+            "Locks of board \"{}\" replaced through the admin API.",
+            board.name
+        );
+        HttpResponse::json("200 OK", serde_json::json!({ "saved": true }))
     }
 
     fn get_ip_from_proxy_headers(
@@ -467,6 +1835,78 @@ impl BitmapServer {
     }
 }
 
+/// Returns the value of a parameter in a query string.
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+/// The current time as a Unix timestamp in milliseconds.
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Compares two byte strings in time that only depends on their length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A response to a plain HTTP request. The connection is closed after it's sent.
+struct HttpResponse {
+    status: &'static str,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(status: &'static str, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn text(status: &'static str, body: &str) -> Self {
+        Self::new(status, "text/plain", body)
+    }
+
+    fn json(status: &'static str, body: serde_json::Value) -> Self {
+        Self::new(status, "application/json", body.to_string())
+    }
+
+    async fn write_to(&self, stream: &mut Compat<TcpStream>) -> io::Result<()> {
+        let mut header = format!(
+            "HTTP/1.1 {}\r\n\
+            Content-Type: {}\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        );
+        for (name, value) in self.headers.iter() {
+            header += &format!("{}: {}\r\n", name, value);
+        }
+        header += "\r\n";
+
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(&self.body).await
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum BitmapError {
     InvalidHttp,
@@ -490,23 +1930,87 @@ struct Metrics {
     clients: AtomicU32,
     // Peak number of clients connected at the same time
     peak_clients: AtomicU32,
+    // Number of rejected invalid requests
+    #[serde(default)]
+    invalid_requests: AtomicU64,
+    // Number of times a client fell behind the update backlog of a subscribed chunk
+    #[serde(default)]
+    lag_events: AtomicU64,
+    // Number of updates skipped by clients that fell behind
+    #[serde(default)]
+    lagged_updates: AtomicU64,
+    #[serde(skip)]
+    // Round-trip times of server pings
+    rtt: Histogram,
+}
+
+/// Statistics of a single board
+#[derive(Serialize, Deserialize, Default)]
+struct BoardMetrics {
     // Number of currently checked bits
     checked_bits: AtomicU32,
     // Number of bit toggles
     bit_toggles: AtomicU64,
+    #[serde(skip)]
+    // Average number of bit toggles per second over TOGGLE_RATE_WINDOW
+    toggles_per_second: AtomicU32,
+}
+
+/// The metrics file, with the metrics of each board by name.
+#[derive(Serialize)]
+struct MetricsFile<'a> {
+    #[serde(flatten)]
+    metrics: &'a Metrics,
+    boards: BTreeMap<&'a str, &'a BoardMetrics>,
+}
+
+/// The metrics file as loaded, which may have been written before multiple boards were
+/// supported.
+#[derive(Deserialize)]
+struct LoadedMetricsFile {
+    #[serde(flatten)]
+    metrics: Metrics,
+    #[serde(default)]
+    boards: HashMap<String, BoardMetrics>,
+    // Toggle count of the only board of older files
+    #[serde(default)]
+    bit_toggles: Option<u64>,
 }
 
 impl Metrics {
-    pub fn save_to_file(&self, path: &str) -> std::io::Result<()> {
-        let data = serde_json::to_string(self)?;
+    pub fn save_to_file(
+        &self,
+        boards: &[Arc<Board>],
+        path: impl AsRef<Path>,
+    ) -> std::io::Result<()> {
+        let file = MetricsFile {
+            metrics: self,
+            boards: boards
+                .iter()
+                .map(|board| (board.name.as_str(), &board.metrics))
+                .collect(),
+        };
+        let data = serde_json::to_string(&file)?;
         std::fs::write(path, data)?;
         Ok(())
     }
 
-    pub fn load_from_file(path: &str) -> std::io::Result<Self> {
+    /// Loads the server metrics and the metrics of each board, by name.
+    pub fn load_from_file(
+        path: impl AsRef<Path>,
+    ) -> std::io::Result<(Self, HashMap<String, BoardMetrics>)> {
         let data = std::fs::read_to_string(path)?;
-        let stats = serde_json::from_str(&data)?;
-        Ok(stats)
+        let mut file: LoadedMetricsFile = serde_json::from_str(&data)?;
+        if let Some(bit_toggles) = file.bit_toggles {
+            file.boards
+                .entry(DEFAULT_BOARD.to_string())
+                .or_insert_with(|| BoardMetrics {
+                    bit_toggles: AtomicU64::new(bit_toggles),
+                    ..Default::default()
+                });
+        }
+
+        Ok((file.metrics, file.boards))
     }
 
     pub fn inc_clients(&self) {
@@ -518,6 +2022,53 @@ impl Metrics {
         self.clients.fetch_sub(1, Ordering::Relaxed);
     }
 
+    pub fn inc_invalid_requests(&self) {
+        self.invalid_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_lag_events(&self, skipped: u64) {
+        self.lag_events.fetch_add(1, Ordering::Relaxed);
+        self.lagged_updates.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    pub fn to_prometheus(&self, boards: &[Arc<Board>]) -> String {
+        let mut output = format!(
+            "# TYPE bitmap_clients gauge\n\
+            # HELP bitmap_clients Number of clients connected\n\
+            bitmap_clients {}\n\
+            # TYPE bitmap_peak_clients counter\n\
+            # HELP bitmap_peak_clients Peak number of clients connected at the same time\n\
+            bitmap_peak_clients {}\n\
+            # TYPE bitmap_invalid_requests counter\n\
+            # HELP bitmap_invalid_requests Number of rejected invalid requests\n\
+            bitmap_invalid_requests {}\n\
+            # TYPE bitmap_lag_events counter\n\
+            # HELP bitmap_lag_events Number of times a client fell behind the update backlog\n\
+            bitmap_lag_events {}\n\
+            # TYPE bitmap_lagged_updates_total counter\n\
+            # HELP bitmap_lagged_updates_total Number of updates skipped by clients that fell \
+            behind\n\
+            bitmap_lagged_updates_total {}\n",
+            self.clients.load(Ordering::Relaxed),
+            self.peak_clients.load(Ordering::Relaxed),
+            self.invalid_requests.load(Ordering::Relaxed),
+            self.lag_events.load(Ordering::Relaxed),
+            self.lagged_updates.load(Ordering::Relaxed),
+        );
+
+        BoardMetrics::write_prometheus(boards, &mut output);
+
+        self.rtt.write_prometheus(
+            &mut output,
+            "bitmap_rtt_seconds",
+            "Round-trip time of server pings",
+        );
+
+        output
+    }
+}
+
+impl BoardMetrics {
     pub fn set_checked_bits(&self, value: u32) {
         self.checked_bits.store(value, Ordering::Relaxed);
     }
@@ -527,28 +2078,104 @@ impl Metrics {
             .fetch_add(amount as u32, Ordering::Relaxed);
     }
 
+    pub fn add_checked_bits(&self, amount: i64) {
+        self.checked_bits
+            .fetch_add(amount as u32, Ordering::Relaxed);
+    }
+
     pub fn inc_bit_toggles(&self) {
         self.bit_toggles.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn to_prometheus(&self) -> String {
-        format!(
-            "# TYPE bitmap_clients gauge\n\
-            # HELP bitmap_clients Number of clients connected\n\
-            bitmap_clients {}\n\
-            # TYPE bitmap_peak_clients counter\n\
-            # HELP bitmap_peak_clients Peak number of clients connected at the same time\n\
-            bitmap_peak_clients {}\n\
-            # TYPE bitmap_checked_bits gauge\n\
-            # HELP bitmap_checked_bits Number of currently checked bits\n\
-            bitmap_checked_bits {}\n\
-            # TYPE bitmap_bit_toggles counter\n\
-            # HELP bitmap_bit_toggles Number of bit toggles\n\
-            bitmap_bit_toggles {}\n",
-            self.clients.load(Ordering::Relaxed),
-            self.peak_clients.load(Ordering::Relaxed),
-            self.checked_bits.load(Ordering::Relaxed),
-            self.bit_toggles.load(Ordering::Relaxed),
-        )
+    pub fn add_bit_toggles(&self, amount: u64) {
+        self.bit_toggles.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Writes the metrics of every board, labeled with the board name.
+    pub fn write_prometheus(boards: &[Arc<Board>], output: &mut String) {
+        use std::fmt::Write;
+
+        let _ = writeln!(output, "# TYPE bitmap_checked_bits gauge");
+        let _ = writeln!(
+            output,
+            "# HELP bitmap_checked_bits Number of currently checked bits"
+        );
+        for board in boards {
+            let _ = writeln!(
+                output,
+                "bitmap_checked_bits{{board=\"{}\"}} {}",
+                board.name,
+                board.metrics.checked_bits.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(output, "# TYPE bitmap_bit_toggles counter");
+        let _ = writeln!(output, "# HELP bitmap_bit_toggles Number of bit toggles");
+        for board in boards {
+            let _ = writeln!(
+                output,
+                "bitmap_bit_toggles{{board=\"{}\"}} {}",
+                board.name,
+                board.metrics.bit_toggles.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+/// Upper bounds of the histogram buckets, in seconds
+const HISTOGRAM_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// A Prometheus-style histogram of durations.
+#[derive(Default)]
+struct Histogram {
+    // Number of observations in each bucket, not cumulative. The last bucket is +Inf.
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS.len() + 1],
+    // Sum of all observations, in microseconds
+    sum_micros: AtomicU64,
+    // Number of observations
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = HISTOGRAM_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(HISTOGRAM_BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn write_prometheus(&self, output: &mut String, name: &str, help: &str) {
+        use std::fmt::Write;
+
+        let _ = writeln!(output, "# TYPE {} histogram", name);
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            match HISTOGRAM_BUCKETS.get(i) {
+                Some(bound) => {
+                    let _ = writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+                }
+                None => {
+                    let _ = writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+                }
+            }
+        }
+
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(output, "{}_sum {}", name, sum);
+        let _ = writeln!(
+            output,
+            "{}_count {}",
+            name,
+            self.count.load(Ordering::Relaxed)
+        );
     }
 }
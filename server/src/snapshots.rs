@@ -0,0 +1,106 @@
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+use crate::{
+    bitmap::{Bitmap, BitmapGeometry},
+    common::PResult,
+};
+
+/// Snapshots of the boards, stored as `<board>/<unix timestamp in milliseconds>.bin` in the
+/// snapshots directory, in the same format as the state file.
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn board_dir(&self, board: &str) -> PathBuf {
+        self.dir.join(board)
+    }
+
+    fn path(&self, board: &str, timestamp: u64) -> PathBuf {
+        self.board_dir(board).join(format!("{}.bin", timestamp))
+    }
+
+    /// Saves a snapshot of the bitmap. `save_to_file` writes it under a temporary name first,
+    /// so an interrupted save doesn't leave a partial snapshot behind.
+    pub fn save(&self, board: &str, timestamp: u64, bitmap: &Bitmap) -> PResult<()> {
+        std::fs::create_dir_all(self.board_dir(board))?;
+
+        bitmap.save_to_file(self.path(board, timestamp))
+    }
+
+    /// Returns the timestamps of the snapshots of a board, oldest first.
+    pub fn list(&self, board: &str) -> PResult<Vec<u64>> {
+        let dir = self.board_dir(board);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut timestamps = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let timestamp = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".bin"))
+                .and_then(|timestamp| timestamp.parse().ok());
+            if let Some(timestamp) = timestamp {
+                timestamps.push(timestamp);
+            }
+        }
+
+        timestamps.sort_unstable();
+        Ok(timestamps)
+    }
+
+    /// Returns the timestamp of the latest snapshot of a board taken at or before `timestamp`.
+    pub fn find(&self, board: &str, timestamp: u64) -> PResult<Option<u64>> {
+        let timestamps = self.list(board)?;
+        let position = timestamps.partition_point(|&t| t <= timestamp);
+        Ok(position.checked_sub(1).map(|position| timestamps[position]))
+    }
+
+    /// Deletes the oldest snapshots of a board, keeping at most `keep` of them. Returns the
+    /// number of deleted snapshots.
+    pub fn prune(&self, board: &str, keep: usize) -> PResult<usize> {
+        let timestamps = self.list(board)?;
+        let excess = timestamps.len().saturating_sub(keep);
+        for &timestamp in timestamps[..excess].iter() {
+            std::fs::remove_file(self.path(board, timestamp))?;
+        }
+        Ok(excess)
+    }
+
+    /// Reads a single chunk of a snapshot. Fails if the size of the snapshot doesn't match the
+    /// geometry.
+    pub fn read_chunk(
+        &self,
+        board: &str,
+        timestamp: u64,
+        geometry: &BitmapGeometry,
+        chunk_index: usize,
+    ) -> PResult<Vec<u8>> {
+        let mut file = std::fs::File::open(self.path(board, timestamp))?;
+        let expected_size = (geometry.chunk_count * geometry.chunk_size_bytes()) as u64;
+        let size = file.metadata()?.len();
+        if size != expected_size {
+            return Err(format!(
+                "Snapshot is {} bytes, but the bitmap geometry requires {} bytes",
+                size, expected_size
+            )
+            .into());
+        }
+
+        let mut chunk = vec![0; geometry.chunk_size_bytes()];
+        file.seek(SeekFrom::Start(
+            (chunk_index * geometry.chunk_size_bytes()) as u64,
+        ))?;
+        file.read_exact(&mut chunk)?;
+        Ok(chunk)
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{bitmap::Bitmap, common::PResult};
+
+/// Start of the file, followed by the little-endian CRC32 of the state file the records apply
+/// to.
+const MAGIC: &[u8; 4] = b"CBTL";
+const HEADER_SIZE: usize = 8;
+
+/// Every record starts with its kind and a little-endian `u64` Unix timestamp in milliseconds.
+/// Flips are followed by a little-endian `u32` bit index, and ranges by the little-endian `u32`
+/// start and exclusive end.
+const FLIP_RECORD: u8 = 0;
+const CLEAR_RECORD: u8 = 1;
+const FILL_RECORD: u8 = 2;
+const INVERT_RECORD: u8 = 3;
+const FLIP_RECORD_SIZE: usize = 13;
+const RANGE_RECORD_SIZE: usize = 17;
+
+/// Number of buffers for pending records, selected by bit index, so concurrent flips rarely
+/// wait for each other.
+const PENDING_SHARDS: usize = 16;
+
+/// Size at which a pending buffer is written to the file without waiting for the next flush.
+const MAX_PENDING_SIZE: usize = 64 * 1024;
+
+/// An append-only log of the bits flipped since the state file was last saved. Toggles and
+/// sets are recorded as the flips they caused, which can be replayed in any order, so the log
+/// stays correct even if concurrent changes are recorded in a different order than they were
+/// applied. Ranges are written after every earlier record, as the bitmap is locked for
+/// writing while they're modified.
+///
+/// Flips can't be replayed twice, so the header ties the log to the checksum of the state file.
+/// If the state is saved but the log isn't reset, as after a crash in between, the log no
+/// longer matches the state file and is ignored.
+pub struct ToggleLog {
+    file: Mutex<File>,
+    /// Whether records were written to the file since it was last synced
+    unsynced: AtomicBool,
+    /// Records not yet written to the file
+    pending: Box<[Mutex<Vec<u8>>]>,
+    /// Timestamp of new records, updated on every flush so flips don't read the clock. The
+    /// timestamps are accurate to the flush interval.
+    timestamp: AtomicU64,
+}
+
+impl ToggleLog {
+    /// Opens the log for appending, creating it if it doesn't exist. A log that doesn't apply
+    /// to the state with the given checksum is reset.
+    pub fn open(path: impl AsRef<Path>, state_checksum: u32) -> io::Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut header = [0; HEADER_SIZE];
+        let matches =
+            file.read_exact(&mut header).is_ok() && header == Self::header(state_checksum);
+        if !matches {
+            Self::reset_file(&mut file, state_checksum)?;
+        }
+
+        Ok(Self {
+            file: Mutex::new(file),
+            unsynced: AtomicBool::new(false),
+            pending: (0..PENDING_SHARDS).map(|_| Mutex::default()).collect(),
+            timestamp: AtomicU64::new(unix_millis()),
+        })
+    }
+
+    fn header(state_checksum: u32) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(MAGIC);
+        header[4..].copy_from_slice(&state_checksum.to_le_bytes());
+        header
+    }
+
+    fn reset_file(file: &mut File, state_checksum: u32) -> io::Result<()> {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&Self::header(state_checksum))?;
+        file.sync_data()
+    }
+
+    /// Applies every record in the log, if it applies to the state with the given checksum.
+    /// Records past the end of the bitmap and an incomplete last record, left by a crash
+    /// during a write, are skipped. Returns the number of replayed records.
+    pub fn replay(
+        path: impl AsRef<Path>,
+        bitmap: &mut Bitmap,
+        state_checksum: u32,
+    ) -> PResult<usize> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let mut data = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+        if data.len() < HEADER_SIZE || data[..HEADER_SIZE] != Self::header(state_checksum) {
+            log::warn!(
+                "Ignoring the toggle log {}, the state was saved after it was written",
+                path.display()
+            );
+            return Ok(0);
+        }
+
+        let read_u32 = |data: &[u8], offset: usize| {
+            u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize
+        };
+
+        let mut records = &data[HEADER_SIZE..];
+        let mut replayed = 0;
+        while let Some(&kind) = records.first() {
+            let size = match kind {
+                FLIP_RECORD => FLIP_RECORD_SIZE,
+                CLEAR_RECORD | FILL_RECORD | INVERT_RECORD => RANGE_RECORD_SIZE,
+                _ => return Err(format!("Unknown toggle log record kind {}", kind).into()),
+            };
+            if records.len() < size {
+                break;
+            }
+
+            let start = read_u32(records, 9);
+            if start < bitmap.len() {
+                match kind {
+                    FLIP_RECORD => {
+                        bitmap.toggle(start);
+                    }
+                    CLEAR_RECORD => {
+                        bitmap.set_range(start..read_u32(records, 13), false);
+                    }
+                    FILL_RECORD => {
+                        bitmap.set_range(start..read_u32(records, 13), true);
+                    }
+                    _ => {
+                        bitmap.toggle_range(start..read_u32(records, 13));
+                    }
+                }
+                replayed += 1;
+            }
+            records = &records[size..];
+        }
+
+        Ok(replayed)
+    }
+
+    /// Records a flip of the bit. The record is written to the file by the next `flush`, or
+    /// right away if its buffer is full.
+    pub fn record_flip(&self, index: usize) {
+        let timestamp = self.timestamp.load(Ordering::Relaxed);
+        let mut pending = self.pending[index % PENDING_SHARDS].lock().unwrap();
+        pending.push(FLIP_RECORD);
+        pending.extend_from_slice(&timestamp.to_le_bytes());
+        pending.extend_from_slice(&(index as u32).to_le_bytes());
+
+        if pending.len() >= MAX_PENDING_SIZE {
+            // The buffer is released before locking the file, which `flush` locks first.
+            let records = std::mem::take(&mut *pending);
+            drop(pending);
+
+            // Synced by the next flush.
+            match self.file.lock().unwrap().write_all(&records) {
+                Ok(()) => self.unsynced.store(true, Ordering::Relaxed),
+                Err(e) => log::error!("Failed to write to the toggle log: {}", e),
+            }
+        }
+    }
+
+    /// Records that every bit in the range was set to `value`, or toggled if it's None. The
+    /// bitmap must be locked for writing, so the record is written after the pending flips
+    /// that came before it. Synced by the next flush.
+    pub fn record_range(&self, range: Range<usize>, value: Option<bool>) {
+        let kind = match value {
+            Some(false) => CLEAR_RECORD,
+            Some(true) => FILL_RECORD,
+            None => INVERT_RECORD,
+        };
+        let mut record = Vec::with_capacity(RANGE_RECORD_SIZE);
+        record.push(kind);
+        record.extend_from_slice(&unix_millis().to_le_bytes());
+        record.extend_from_slice(&(range.start as u32).to_le_bytes());
+        record.extend_from_slice(&(range.end as u32).to_le_bytes());
+
+        let mut file = self.file.lock().unwrap();
+        let written = self
+            .write_pending(&mut file)
+            .and_then(|()| file.write_all(&record));
+        match written {
+            Ok(()) => self.unsynced.store(true, Ordering::Relaxed),
+            Err(e) => log::error!("Failed to write to the toggle log: {}", e),
+        }
+    }
+
+    /// Writes the pending records to the file and syncs it to disk.
+    pub fn flush(&self) -> io::Result<()> {
+        self.timestamp.store(unix_millis(), Ordering::Relaxed);
+
+        // The file stays locked until the records are written, so `reset` can't run between
+        // taking the records and writing them.
+        let mut file = self.file.lock().unwrap();
+        self.write_pending(&mut file)?;
+
+        if self.unsynced.swap(false, Ordering::Relaxed) {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn write_pending(&self, file: &mut File) -> io::Result<()> {
+        for pending in self.pending.iter() {
+            let records = std::mem::take(&mut *pending.lock().unwrap());
+            if !records.is_empty() {
+                file.write_all(&records)?;
+                self.unsynced.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards every record, after the state they lead to was saved with the given checksum.
+    /// The bitmap must not be modified until this returns.
+    pub fn reset(&self, state_checksum: u32) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        for pending in self.pending.iter() {
+            pending.lock().unwrap().clear();
+        }
+        self.unsynced.store(false, Ordering::Relaxed);
+        Self::reset_file(&mut file, state_checksum)
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
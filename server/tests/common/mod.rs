@@ -0,0 +1,367 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use checkboxes_server::{
+    common::PResult,
+    config::Settings,
+    protocol::{Message, MessageMut, MessageType},
+    server::BitmapServer,
+};
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use soketto::{
+    connection::{Receiver, Sender},
+    handshake::{Client, ServerResponse},
+};
+use tokio::{
+    net::{TcpListener, TcpSocket, TcpStream},
+    task::JoinHandle,
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+/// How long to wait for a message before failing the test.
+pub const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static SERVER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A server running in the test process, listening on an ephemeral port, with its own
+/// temporary data directory. The server is stopped and the directory removed on drop.
+pub struct TestServer {
+    pub address: SocketAddr,
+    pub data_dir: PathBuf,
+    server: Arc<BitmapServer>,
+    task: JoinHandle<PResult<()>>,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Starts a server with settings adjusted by `configure`. The bind address is overridden.
+    /// The data directory is already created when `configure` is called, so it can prepare
+    /// files in it, but it can't be changed.
+    pub async fn start_with(configure: impl FnOnce(&mut Settings)) -> Self {
+        let data_dir = std::env::temp_dir().join(format!(
+            "checkboxes-test-{}-{}",
+            std::process::id(),
+            SERVER_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        Self::start_in(data_dir, configure).await
+    }
+
+    /// Stops the server without saving, as if it crashed, and starts a new one with the same
+    /// data directory and settings adjusted by `configure`.
+    pub async fn crash_and_restart(mut self, configure: impl FnOnce(&mut Settings)) -> Self {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+
+        let data_dir = std::mem::take(&mut self.data_dir);
+        Self::start_in(data_dir, configure).await
+    }
+
+    async fn start_in(data_dir: PathBuf, configure: impl FnOnce(&mut Settings)) -> Self {
+        // Set before and after `configure`, which may derive paths from the data directory.
+        let mut settings = Settings {
+            data_dir: data_dir.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        configure(&mut settings);
+        settings.data_dir = data_dir.to_string_lossy().into_owned();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server: Arc<BitmapServer> = BitmapServer::new(settings).unwrap().into();
+        let task = {
+            let server = server.clone();
+            tokio::spawn(async move { server.serve(listener).await })
+        };
+
+        Self {
+            address,
+            data_dir,
+            server,
+            task,
+        }
+    }
+
+    /// Reloads the locks files, as if the server received SIGHUP.
+    pub fn reload_locks(&self) {
+        self.server.reload_locks();
+    }
+
+    /// Fetches the Prometheus metrics of the server.
+    pub async fn metrics(&self) -> String {
+        let (status, body) = self.http_request("GET", "/metrics", None, &[]).await;
+        assert_eq!(status, 200);
+        body
+    }
+
+    /// Sends a plain HTTP request, with a bearer token if given. Returns the status code and
+    /// the body of the response.
+    pub async fn http_request(
+        &self,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &[u8],
+    ) -> (u16, String) {
+        let (status, _, body) = self.http_request_raw(method, path, token, body).await;
+        (status, String::from_utf8(body).unwrap())
+    }
+
+    /// Sends a plain HTTP request like `http_request`. Returns the status code, the headers
+    /// and the binary body of the response.
+    pub async fn http_request_raw(
+        &self,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &[u8],
+    ) -> (u16, String, Vec<u8>) {
+        let stream = TcpStream::connect(self.address).await.unwrap();
+        let mut stream = stream.compat();
+
+        let mut request = format!("{} {} HTTP/1.1\r\nConnection: close\r\n", method, path);
+        if let Some(token) = token {
+            request += &format!("Authorization: Bearer {}\r\n", token);
+        }
+        request += &format!("Content-Length: {}\r\n\r\n", body.len());
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let head_len = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8(response[..head_len].to_vec()).unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, head, response[head_len + 4..].to_vec())
+    }
+
+    /// Sends only the head of an HTTP request, without the body it announces. Returns the
+    /// status code, failing the test if there's no response after RECEIVE_TIMEOUT.
+    pub async fn http_request_head(&self, head: &str) -> u16 {
+        let stream = TcpStream::connect(self.address).await.unwrap();
+        let mut stream = stream.compat();
+        stream.write_all(head.as_bytes()).await.unwrap();
+
+        let mut status_line = [0; 12];
+        tokio::time::timeout(RECEIVE_TIMEOUT, stream.read_exact(&mut status_line))
+            .await
+            .expect("Timed out waiting for the response")
+            .unwrap();
+        std::str::from_utf8(&status_line[9..])
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        // Restarted servers hand the directory over to the new server.
+        if !self.data_dir.as_os_str().is_empty() {
+            let _ = std::fs::remove_dir_all(&self.data_dir);
+        }
+    }
+}
+
+/// A WebSocket connection that sends and receives raw protocol messages.
+pub struct RawClient {
+    sender: Sender<Compat<TcpStream>>,
+    receiver: Receiver<Compat<TcpStream>>,
+    recv_data: Vec<u8>,
+}
+
+impl RawClient {
+    /// Connects to the server, without consuming the Hello message.
+    pub async fn connect(address: SocketAddr) -> Self {
+        Self::connect_to(address, "/").await
+    }
+
+    /// Connects to the given request path, without consuming the Hello message.
+    pub async fn connect_to(address: SocketAddr, path: &str) -> Self {
+        let socket = TcpStream::connect(address).await.unwrap();
+        match Self::try_handshake(socket, address, path).await {
+            Ok(client) => client,
+            Err(status_code) => panic!("Handshake rejected with status {}", status_code),
+        }
+    }
+
+    /// Connects to the given request path, returning the status code if the server rejects
+    /// the handshake.
+    pub async fn try_connect_to(address: SocketAddr, path: &str) -> Result<Self, u16> {
+        let socket = TcpStream::connect(address).await.unwrap();
+        Self::try_handshake(socket, address, path).await
+    }
+
+    /// Connects with a small socket receive buffer, so the server's writes block quickly if
+    /// the client doesn't read. The Hello message isn't consumed.
+    pub async fn connect_with_small_buffer(address: SocketAddr) -> Self {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let socket = socket.connect(address).await.unwrap();
+        Self::try_handshake(socket, address, "/").await.unwrap()
+    }
+
+    async fn try_handshake(
+        socket: TcpStream,
+        address: SocketAddr,
+        path: &str,
+    ) -> Result<Self, u16> {
+        socket.set_nodelay(true).unwrap();
+
+        let host = address.to_string();
+        let mut client = Client::new(socket.compat(), &host, path);
+        match client.handshake().await.unwrap() {
+            ServerResponse::Accepted { .. } => {}
+            ServerResponse::Rejected { status_code } => return Err(status_code),
+            response => panic!("Handshake failed: {:?}", response),
+        }
+
+        let (sender, receiver) = client.into_builder().finish();
+        Ok(Self {
+            sender,
+            receiver,
+            recv_data: Vec::new(),
+        })
+    }
+
+    /// Connects to the server and checks that the first message is a Hello message.
+    pub async fn connect_and_skip_hello(address: SocketAddr) -> Self {
+        let mut client = Self::connect(address).await;
+        let message = client.receive().await;
+        assert!(matches!(message, Message::Hello(_)));
+        client
+    }
+
+    pub async fn send(&mut self, data: &[u8]) {
+        self.sender.send_binary(data).await.unwrap();
+    }
+
+    /// Receives the next message, failing the test after RECEIVE_TIMEOUT.
+    pub async fn receive(&mut self) -> Message<'_> {
+        self.recv_data.clear();
+        tokio::time::timeout(
+            RECEIVE_TIMEOUT,
+            self.receiver.receive_data(&mut self.recv_data),
+        )
+        .await
+        .expect("Timed out waiting for a message")
+        .unwrap();
+
+        Message::from_slice(&self.recv_data).unwrap()
+    }
+
+    /// Receives messages until one of the given type arrives, skipping periodic messages
+    /// like Stats and Ping.
+    pub async fn receive_type(&mut self, message_type: MessageType) -> Message<'_> {
+        loop {
+            self.recv_data.clear();
+            tokio::time::timeout(
+                RECEIVE_TIMEOUT,
+                self.receiver.receive_data(&mut self.recv_data),
+            )
+            .await
+            .expect("Timed out waiting for a message")
+            .unwrap();
+
+            let id = Message::from_slice(&self.recv_data).unwrap().id();
+            if id == message_type {
+                return Message::from_slice(&self.recv_data).unwrap();
+            }
+
+            assert!(
+                matches!(id, MessageType::Stats | MessageType::Ping),
+                "Expected {:?}, got {:?}",
+                message_type,
+                id
+            );
+        }
+    }
+
+    /// Sends a Ping and waits for its Pong. Messages are handled in order, so every message
+    /// sent before has been processed once this returns.
+    pub async fn sync(&mut self) {
+        const PAYLOAD: u64 = 0x5359_4e43;
+        self.send(&create_message(MessageType::Ping, |message| {
+            if let MessageMut::Ping(message) = message {
+                message.payload.set(PAYLOAD);
+            }
+        }))
+        .await;
+
+        let Message::Pong(pong) = self.receive_type(MessageType::Pong).await else {
+            unreachable!();
+        };
+        assert_eq!(pong.payload.get(), PAYLOAD);
+    }
+
+    /// Waits until the server closes the connection, skipping any messages sent before.
+    pub async fn expect_closed(&mut self) {
+        loop {
+            self.recv_data.clear();
+            let result = tokio::time::timeout(
+                RECEIVE_TIMEOUT,
+                self.receiver.receive_data(&mut self.recv_data),
+            )
+            .await
+            .expect("Timed out waiting for the connection to close");
+            if result.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Returns the type of the next message other than Stats and Ping, or None if there was
+    /// no such message within `timeout`.
+    pub async fn try_receive_type(&mut self, timeout: Duration) -> Option<MessageType> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            self.recv_data.clear();
+            let result =
+                tokio::time::timeout_at(deadline, self.receiver.receive_data(&mut self.recv_data))
+                    .await;
+            if result.is_err() {
+                return None;
+            }
+            result.unwrap().unwrap();
+
+            let id = Message::from_slice(&self.recv_data).unwrap().id();
+            if !matches!(id, MessageType::Stats | MessageType::Ping) {
+                return Some(id);
+            }
+        }
+    }
+}
+
+/// Serializes a message of the given type, filled in by `fill`.
+pub fn create_message(message_type: MessageType, fill: impl FnOnce(MessageMut)) -> Vec<u8> {
+    let mut data = Vec::new();
+    fill(MessageMut::create_message(message_type, &mut data).unwrap());
+    data
+}
+
+/// Polls `condition` until it holds, failing the test after RECEIVE_TIMEOUT.
+pub async fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = tokio::time::Instant::now() + RECEIVE_TIMEOUT;
+    while !condition() {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "Timed out waiting for a condition"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
@@ -0,0 +1,529 @@
+//! Encodes every message type, compares the result with the expected little-endian wire bytes,
+//! then parses the bytes back. The expected bytes are spelled out explicitly, so these tests
+//! pass on big-endian targets only if the wire format is the same as on little-endian ones.
+
+use checkboxes_server::{
+    bitmap::{Bitmap, BitmapGeometry},
+    protocol::{Message, MessageMut, MessageType, SPARSE_UPDATE_VALUE_BIT},
+};
+
+fn encode(id: MessageType, extra_size: usize, fill: impl FnOnce(MessageMut)) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    let message = MessageMut::create_variable_message(id, extra_size, &mut buffer).unwrap();
+    assert_eq!(message.id(), id);
+    fill(message);
+    buffer
+}
+
+/// Checks that `bytes` starts with the message ID followed by `expected`, and parses it.
+fn decode<'a>(id: MessageType, bytes: &'a [u8], expected: &[u8]) -> Message<'a> {
+    assert_eq!(bytes[0], id as u8);
+    assert_eq!(&bytes[1..], expected);
+
+    let message = Message::from_slice(bytes).unwrap();
+    assert_eq!(message.id(), id);
+    message
+}
+
+#[test]
+fn hello() {
+    let bytes = encode(MessageType::Hello, 0, |message| {
+        let MessageMut::Hello(hello) = message else {
+            unreachable!();
+        };
+        hello.version_major.set(0x0102);
+        hello.version_minor.set(0x0304);
+    });
+
+    let Message::Hello(hello) = decode(MessageType::Hello, &bytes, &[0x02, 0x01, 0x04, 0x03])
+    else {
+        unreachable!();
+    };
+    assert_eq!(hello.version_major.get(), 0x0102);
+    assert_eq!(hello.version_minor.get(), 0x0304);
+}
+
+#[test]
+fn stats() {
+    let bytes = encode(MessageType::Stats, 0, |message| {
+        let MessageMut::Stats(stats) = message else {
+            unreachable!();
+        };
+        stats.current_clients.set(0x0102_0304);
+        stats.checked_bits.set(0x0506_0708);
+        stats.toggles_per_second.set(0x090a_0b0c);
+        stats.uptime_seconds.set(0x0d0e_0f10);
+        stats.server_timestamp.set(0x1112_1314_1516_1718);
+    });
+
+    let mut expected = vec![
+        0x04, 0x03, 0x02, 0x01, 0x08, 0x07, 0x06, 0x05, 0x0c, 0x0b, 0x0a, 0x09, 0x10, 0x0f, 0x0e,
+        0x0d, 0x18, 0x17, 0x16, 0x15, 0x14, 0x13, 0x12, 0x11,
+    ];
+    expected.resize(64, 0);
+
+    let Message::Stats(stats) = decode(MessageType::Stats, &bytes, &expected) else {
+        unreachable!();
+    };
+    assert_eq!(stats.current_clients.get(), 0x0102_0304);
+    assert_eq!(stats.checked_bits.get(), 0x0506_0708);
+    assert_eq!(stats.toggles_per_second.get(), 0x090a_0b0c);
+    assert_eq!(stats.uptime_seconds.get(), 0x0d0e_0f10);
+    assert_eq!(stats.server_timestamp.get(), 0x1112_1314_1516_1718);
+}
+
+#[test]
+fn client_hello() {
+    let bytes = encode(MessageType::ClientHello, 0, |message| {
+        let MessageMut::ClientHello(hello) = message else {
+            unreachable!();
+        };
+        hello.version_major.set(0x0102);
+        hello.version_minor.set(0x0304);
+        hello.capabilities.set(0x0506_0708);
+    });
+
+    let expected = [0x02, 0x01, 0x04, 0x03, 0x08, 0x07, 0x06, 0x05];
+    let Message::ClientHello(hello) = decode(MessageType::ClientHello, &bytes, &expected) else {
+        unreachable!();
+    };
+    assert_eq!(hello.version_major.get(), 0x0102);
+    assert_eq!(hello.version_minor.get(), 0x0304);
+    assert_eq!(hello.capabilities.get(), 0x0506_0708);
+}
+
+#[test]
+fn client_hello_ack() {
+    let bytes = encode(MessageType::ClientHelloAck, 0, |message| {
+        let MessageMut::ClientHelloAck(ack) = message else {
+            unreachable!();
+        };
+        ack.capabilities.set(0x0102_0304);
+    });
+
+    let expected = [0x04, 0x03, 0x02, 0x01];
+    let Message::ClientHelloAck(ack) = decode(MessageType::ClientHelloAck, &bytes, &expected)
+    else {
+        unreachable!();
+    };
+    assert_eq!(ack.capabilities.get(), 0x0102_0304);
+}
+
+#[test]
+fn error() {
+    let bytes = encode(MessageType::Error, 0, |message| {
+        let MessageMut::Error(error) = message else {
+            unreachable!();
+        };
+        error.code = 0x01;
+        error.request_type = 0x02;
+        error.context.set(0x0304_0506);
+    });
+
+    let expected = [0x01, 0x02, 0x06, 0x05, 0x04, 0x03];
+    let Message::Error(error) = decode(MessageType::Error, &bytes, &expected) else {
+        unreachable!();
+    };
+    assert_eq!(error.code, 0x01);
+    assert_eq!(error.request_type, 0x02);
+    assert_eq!(error.context.get(), 0x0304_0506);
+}
+
+#[test]
+fn ping_and_pong() {
+    let expected = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+
+    let bytes = encode(MessageType::Ping, 0, |message| {
+        let MessageMut::Ping(ping) = message else {
+            unreachable!();
+        };
+        ping.payload.set(0x0102_0304_0506_0708);
+    });
+    let Message::Ping(ping) = decode(MessageType::Ping, &bytes, &expected) else {
+        unreachable!();
+    };
+    assert_eq!(ping.payload.get(), 0x0102_0304_0506_0708);
+
+    let bytes = encode(MessageType::Pong, 0, |message| {
+        let MessageMut::Pong(pong) = message else {
+            unreachable!();
+        };
+        pong.payload.set(0x0102_0304_0506_0708);
+    });
+    let Message::Pong(pong) = decode(MessageType::Pong, &bytes, &expected) else {
+        unreachable!();
+    };
+    assert_eq!(pong.payload.get(), 0x0102_0304_0506_0708);
+}
+
+#[test]
+fn board_info() {
+    let bytes = encode(MessageType::BoardInfo, 0, |message| {
+        let MessageMut::BoardInfo(info) = message else {
+            unreachable!();
+        };
+        info.chunk_size.set(0x0102_0304);
+        info.chunk_count.set(0x0506_0708);
+        info.update_chunk_size.set(0x090a);
+    });
+
+    let expected = [0x04, 0x03, 0x02, 0x01, 0x08, 0x07, 0x06, 0x05, 0x0a, 0x09];
+    let Message::BoardInfo(info) = decode(MessageType::BoardInfo, &bytes, &expected) else {
+        unreachable!();
+    };
+    assert_eq!(info.chunk_size.get(), 0x0102_0304);
+    assert_eq!(info.chunk_count.get(), 0x0506_0708);
+    assert_eq!(info.update_chunk_size.get(), 0x090a);
+}
+
+#[test]
+fn chunk_index_messages() {
+    let types = [
+        MessageType::ChunkFullStateRequest,
+        MessageType::PartialStateSubscription,
+        MessageType::AddPartialStateSubscription,
+        MessageType::RemovePartialStateSubscription,
+        MessageType::ChunkResync,
+    ];
+
+    for id in types {
+        let bytes = encode(id, 0, |message| match message {
+            MessageMut::ChunkFullStateRequest(msg) => msg.chunk_index.set(0x0102),
+            MessageMut::PartialStateSubscription(msg)
+            | MessageMut::AddPartialStateSubscription(msg)
+            | MessageMut::RemovePartialStateSubscription(msg) => msg.chunk_index.set(0x0102),
+            MessageMut::ChunkResync(msg) => msg.chunk_index.set(0x0102),
+            _ => unreachable!(),
+        });
+
+        let message = decode(id, &bytes, &[0x02, 0x01]);
+        assert_eq!(message.chunk_index(), Some(0x0102));
+    }
+}
+
+#[test]
+fn partial_state_unsubscription() {
+    let bytes = encode(MessageType::PartialStateUnsubscription, 0, |_| {});
+    let message = decode(MessageType::PartialStateUnsubscription, &bytes, &[]);
+    assert!(matches!(message, Message::PartialStateUnsubscription));
+}
+
+#[test]
+fn chunk_full_state_response() {
+    let bytes = encode(MessageType::ChunkFullStateResponse, 3, |message| {
+        let MessageMut::ChunkFullStateResponse(response, bitmap) = message else {
+            unreachable!();
+        };
+        response.chunk_index.set(0x0102);
+        bitmap.copy_from_slice(&[0x03, 0x04, 0x05]);
+    });
+
+    let Message::ChunkFullStateResponse(response, bitmap) = decode(
+        MessageType::ChunkFullStateResponse,
+        &bytes,
+        &[0x02, 0x01, 0x03, 0x04, 0x05],
+    ) else {
+        unreachable!();
+    };
+    assert_eq!(response.chunk_index.get(), 0x0102);
+    assert_eq!(bitmap, [0x03, 0x04, 0x05]);
+}
+
+#[test]
+fn chunk_full_state_request_with_flags() {
+    let bytes = encode(MessageType::ChunkFullStateRequestWithFlags, 0, |message| {
+        let MessageMut::ChunkFullStateRequestWithFlags(request) = message else {
+            unreachable!();
+        };
+        request.chunk_index.set(0x0102);
+        request.flags = 0x03;
+    });
+
+    let Message::ChunkFullStateRequestWithFlags(request) = decode(
+        MessageType::ChunkFullStateRequestWithFlags,
+        &bytes,
+        &[0x02, 0x01, 0x03],
+    ) else {
+        unreachable!();
+    };
+    assert_eq!(request.chunk_index.get(), 0x0102);
+    assert_eq!(request.flags, 0x03);
+}
+
+#[test]
+fn compressed_chunk_full_state_response() {
+    let bytes = encode(
+        MessageType::CompressedChunkFullStateResponse,
+        3,
+        |message| {
+            let MessageMut::CompressedChunkFullStateResponse(response, payload) = message else {
+                unreachable!();
+            };
+            response.chunk_index.set(0x0102);
+            response.encoding = 0x03;
+            payload.copy_from_slice(&[0x04, 0x05, 0x06]);
+        },
+    );
+
+    let Message::CompressedChunkFullStateResponse(response, payload) = decode(
+        MessageType::CompressedChunkFullStateResponse,
+        &bytes,
+        &[0x02, 0x01, 0x03, 0x04, 0x05, 0x06],
+    ) else {
+        unreachable!();
+    };
+    assert_eq!(response.chunk_index.get(), 0x0102);
+    assert_eq!(response.encoding, 0x03);
+    assert_eq!(payload, [0x04, 0x05, 0x06]);
+}
+
+#[test]
+fn partial_state_update() {
+    let bytes = encode(MessageType::PartialStateUpdate, 2, |message| {
+        let MessageMut::PartialStateUpdate(update, chunk) = message else {
+            unreachable!();
+        };
+        update.offset.set(0x0102_0304);
+        chunk.copy_from_slice(&[0x05, 0x06]);
+    });
+
+    let Message::PartialStateUpdate(update, chunk) = decode(
+        MessageType::PartialStateUpdate,
+        &bytes,
+        &[0x04, 0x03, 0x02, 0x01, 0x05, 0x06],
+    ) else {
+        unreachable!();
+    };
+    assert_eq!(update.offset.get(), 0x0102_0304);
+    assert_eq!(chunk, [0x05, 0x06]);
+}
+
+#[test]
+fn toggle_bit() {
+    let bytes = encode(MessageType::ToggleBit, 0, |message| {
+        let MessageMut::ToggleBit(toggle) = message else {
+            unreachable!();
+        };
+        toggle.index.set(0x0102_0304);
+    });
+
+    let expected = [0x04, 0x03, 0x02, 0x01];
+    let Message::ToggleBit(toggle) = decode(MessageType::ToggleBit, &bytes, &expected) else {
+        unreachable!();
+    };
+    assert_eq!(toggle.index.get(), 0x0102_0304);
+}
+
+#[test]
+fn toggle_bits() {
+    let indices = [0x0102_0304, 0x0506_0708];
+    let bytes = encode(MessageType::ToggleBits, size_of_val(&indices), |message| {
+        let MessageMut::ToggleBits(toggles) = message else {
+            unreachable!();
+        };
+        for (toggle, index) in toggles.iter_mut().zip(indices) {
+            toggle.index.set(index);
+        }
+    });
+
+    let expected = [0x04, 0x03, 0x02, 0x01, 0x08, 0x07, 0x06, 0x05];
+    let Message::ToggleBits(toggles) = decode(MessageType::ToggleBits, &bytes, &expected) else {
+        unreachable!();
+    };
+    let decoded: Vec<u32> = toggles.iter().map(|toggle| toggle.index.get()).collect();
+    assert_eq!(decoded, indices);
+}
+
+#[test]
+fn set_bit() {
+    let bytes = encode(MessageType::SetBit, 0, |message| {
+        let MessageMut::SetBit(set) = message else {
+            unreachable!();
+        };
+        set.index.set(0x0102_0304);
+        set.value = 1;
+    });
+
+    let expected = [0x04, 0x03, 0x02, 0x01, 0x01];
+    let Message::SetBit(set) = decode(MessageType::SetBit, &bytes, &expected) else {
+        unreachable!();
+    };
+    assert_eq!(set.index.get(), 0x0102_0304);
+    assert_eq!(set.value, 1);
+}
+
+#[test]
+fn set_update_flags() {
+    let bytes = encode(MessageType::SetUpdateFlags, 0, |message| {
+        let MessageMut::SetUpdateFlags(flags) = message else {
+            unreachable!();
+        };
+        flags.flags = 0x01;
+    });
+
+    let Message::SetUpdateFlags(flags) = decode(MessageType::SetUpdateFlags, &bytes, &[0x01])
+    else {
+        unreachable!();
+    };
+    assert_eq!(flags.flags, 0x01);
+}
+
+#[test]
+fn sparse_partial_state_update() {
+    let entry = 0x0001_0203 | SPARSE_UPDATE_VALUE_BIT;
+    let bytes = encode(MessageType::SparsePartialStateUpdate, 4, |message| {
+        let MessageMut::SparsePartialStateUpdate(update, payload) = message else {
+            unreachable!();
+        };
+        update.chunk_index.set(0x0102);
+        payload.copy_from_slice(&entry.to_le_bytes());
+    });
+
+    let Message::SparsePartialStateUpdate(update, payload) = decode(
+        MessageType::SparsePartialStateUpdate,
+        &bytes,
+        &[0x02, 0x01, 0x03, 0x02, 0x01, 0x80],
+    ) else {
+        unreachable!();
+    };
+    assert_eq!(update.chunk_index.get(), 0x0102);
+    assert_eq!(u32::from_le_bytes(payload.try_into().unwrap()), entry);
+}
+
+#[test]
+fn chunk_version() {
+    let bytes = encode(MessageType::ChunkVersion, 0, |message| {
+        let MessageMut::ChunkVersion(version) = message else {
+            unreachable!();
+        };
+        version.chunk_index.set(0x0102);
+        version.version.set(0x0304_0506);
+    });
+
+    let expected = [0x02, 0x01, 0x06, 0x05, 0x04, 0x03];
+    let Message::ChunkVersion(version) = decode(MessageType::ChunkVersion, &bytes, &expected)
+    else {
+        unreachable!();
+    };
+    assert_eq!(version.chunk_index.get(), 0x0102);
+    assert_eq!(version.version.get(), 0x0304_0506);
+}
+
+#[test]
+fn chunk_checksum_request() {
+    let bytes = encode(MessageType::ChunkChecksumRequest, 4, |message| {
+        let MessageMut::ChunkChecksumRequest(entries) = message else {
+            unreachable!();
+        };
+        entries[0].chunk_index.set(0x0102);
+        entries[1].chunk_index.set(0x0304);
+    });
+
+    let expected = [0x02, 0x01, 0x04, 0x03];
+    let Message::ChunkChecksumRequest(entries) =
+        decode(MessageType::ChunkChecksumRequest, &bytes, &expected)
+    else {
+        unreachable!();
+    };
+    let decoded: Vec<u16> = entries
+        .iter()
+        .map(|entry| entry.chunk_index.get())
+        .collect();
+    assert_eq!(decoded, [0x0102, 0x0304]);
+}
+
+#[test]
+fn chunk_checksum_response() {
+    let bytes = encode(MessageType::ChunkChecksumResponse, 6, |message| {
+        let MessageMut::ChunkChecksumResponse(entries) = message else {
+            unreachable!();
+        };
+        entries[0].chunk_index.set(0x0102);
+        entries[0].checksum.set(0x0304_0506);
+    });
+
+    let expected = [0x02, 0x01, 0x06, 0x05, 0x04, 0x03];
+    let Message::ChunkChecksumResponse(entries) =
+        decode(MessageType::ChunkChecksumResponse, &bytes, &expected)
+    else {
+        unreachable!();
+    };
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].chunk_index.get(), 0x0102);
+    assert_eq!(entries[0].checksum.get(), 0x0304_0506);
+}
+
+#[test]
+fn locked_ranges() {
+    let bytes = encode(MessageType::LockedRanges, 8, |message| {
+        let MessageMut::LockedRanges(header, entries) = message else {
+            unreachable!();
+        };
+        header.chunk_index.set(0x0102);
+        entries[0].start.set(0x0304_0506);
+        entries[0].end.set(0x0708_090a);
+    });
+
+    let expected = [0x02, 0x01, 0x06, 0x05, 0x04, 0x03, 0x0a, 0x09, 0x08, 0x07];
+    let Message::LockedRanges(header, entries) =
+        decode(MessageType::LockedRanges, &bytes, &expected)
+    else {
+        unreachable!();
+    };
+    assert_eq!(header.chunk_index.get(), 0x0102);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].start.get(), 0x0304_0506);
+    assert_eq!(entries[0].end.get(), 0x0708_090a);
+
+    // Entries are fixed size, a partial entry is invalid.
+    assert!(Message::from_slice(&bytes[..bytes.len() - 1]).is_err());
+}
+
+/// Chunk data is sent as raw bytes, so bit `n` must be bit `n % 8` of byte `n / 8` on any target.
+#[test]
+fn bitmap_bits_are_least_significant_first() {
+    let geometry = BitmapGeometry::DEFAULT;
+    let chunk_size = geometry.chunk_size;
+    let bitmap = Bitmap::new(geometry);
+    for index in [0, 9, 23, chunk_size - 1, chunk_size + 2] {
+        bitmap.toggle(index);
+    }
+    bitmap.set(30, true);
+
+    let chunk = bitmap.as_raw_slice(0);
+    assert_eq!(chunk[0], 0x01);
+    assert_eq!(chunk[1], 0x02);
+    assert_eq!(chunk[2], 0x80);
+    assert_eq!(chunk[3], 0x40);
+    assert_eq!(chunk[geometry.chunk_size_bytes() - 1], 0x80);
+    assert_eq!(bitmap.as_raw_slice(1)[0], 0x04);
+}
+
+#[test]
+fn state_file_must_match_geometry() {
+    let path = std::env::temp_dir().join(format!("checkboxes-state-{}.bin", std::process::id()));
+    let small = BitmapGeometry {
+        chunk_size: 1024,
+        chunk_count: 4,
+        update_chunk_size: 16,
+    };
+
+    let bitmap = Bitmap::new(small);
+    bitmap.toggle(1500);
+    bitmap.save_to_file(&path).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * 128);
+
+    let mut loaded = Bitmap::new(small);
+    loaded.load_from_file(&path).unwrap();
+    assert!(loaded.get(1500));
+
+    let larger = BitmapGeometry {
+        chunk_count: 8,
+        ..small
+    };
+    let mut mismatched = Bitmap::new(larger);
+    assert!(mismatched.load_from_file(&path).is_err());
+    assert_eq!(mismatched.count_ones(), 0);
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,1411 @@
+mod common;
+
+use std::time::Duration;
+
+use checkboxes_server::{
+    bitmap::{self, BitmapGeometry},
+    config::{BoardSettings, Settings},
+    encoding::{self, ChunkEncoding},
+    locks::{LockFile, LockedRange, RegionLocks},
+    protocol::{
+        ErrorCode, Message, MessageMut, MessageType, CAPABILITY_LOCKED_RANGES,
+        FULL_STATE_FLAG_ALLOW_COMPRESSION, PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR,
+        SPARSE_UPDATE_VALUE_BIT, SUPPORTED_CAPABILITIES, UPDATE_FLAG_ALLOW_SPARSE,
+    },
+};
+use common::{create_message, wait_until, RawClient, TestServer};
+
+// Servers are started with the default geometry unless a test configures another one.
+const CHUNK_SIZE: usize = BitmapGeometry::DEFAULT.chunk_size;
+const CHUNK_SIZE_BYTES: usize = BitmapGeometry::DEFAULT.chunk_size_bytes();
+const CHUNK_COUNT: usize = BitmapGeometry::DEFAULT.chunk_count;
+const UPDATE_CHUNK_SIZE: usize = BitmapGeometry::DEFAULT.update_chunk_size;
+
+fn client_hello(version_minor: u16, capabilities: u32) -> Vec<u8> {
+    create_message(MessageType::ClientHello, |message| {
+        if let MessageMut::ClientHello(message) = message {
+            message.version_major.set(PROTOCOL_VERSION_MAJOR);
+            message.version_minor.set(version_minor);
+            message.capabilities.set(capabilities);
+        }
+    })
+}
+
+fn full_state_request(chunk_index: u16) -> Vec<u8> {
+    create_message(MessageType::ChunkFullStateRequest, |message| {
+        if let MessageMut::ChunkFullStateRequest(message) = message {
+            message.chunk_index.set(chunk_index);
+        }
+    })
+}
+
+fn subscription(chunk_index: u16) -> Vec<u8> {
+    create_message(MessageType::PartialStateSubscription, |message| {
+        if let MessageMut::PartialStateSubscription(message) = message {
+            message.chunk_index.set(chunk_index);
+        }
+    })
+}
+
+fn add_subscription(chunk_index: u16) -> Vec<u8> {
+    create_message(MessageType::AddPartialStateSubscription, |message| {
+        if let MessageMut::AddPartialStateSubscription(message) = message {
+            message.chunk_index.set(chunk_index);
+        }
+    })
+}
+
+fn remove_subscription(chunk_index: u16) -> Vec<u8> {
+    create_message(MessageType::RemovePartialStateSubscription, |message| {
+        if let MessageMut::RemovePartialStateSubscription(message) = message {
+            message.chunk_index.set(chunk_index);
+        }
+    })
+}
+
+fn toggle(index: u32) -> Vec<u8> {
+    create_message(MessageType::ToggleBit, |message| {
+        if let MessageMut::ToggleBit(message) = message {
+            message.index.set(index);
+        }
+    })
+}
+
+fn set_bit(index: u32, value: u8) -> Vec<u8> {
+    create_message(MessageType::SetBit, |message| {
+        if let MessageMut::SetBit(message) = message {
+            message.index.set(index);
+            message.value = value;
+        }
+    })
+}
+
+fn toggle_bits(indices: &[u32]) -> Vec<u8> {
+    let mut message = vec![MessageType::ToggleBits as u8];
+    for index in indices {
+        message.extend_from_slice(&index.to_le_bytes());
+    }
+    message
+}
+
+fn checksum_request(chunk_indices: &[u16]) -> Vec<u8> {
+    let mut request = vec![MessageType::ChunkChecksumRequest as u8];
+    for chunk_index in chunk_indices {
+        request.extend_from_slice(&chunk_index.to_le_bytes());
+    }
+    request
+}
+
+/// Sends a checksum request and returns the (chunk index, checksum) pairs of the response.
+async fn request_checksums(client: &mut RawClient, chunk_indices: &[u16]) -> Vec<(u16, u32)> {
+    client.send(&checksum_request(chunk_indices)).await;
+    let message = client
+        .receive_type(MessageType::ChunkChecksumResponse)
+        .await;
+    let Message::ChunkChecksumResponse(entries) = message else {
+        unreachable!();
+    };
+
+    entries
+        .iter()
+        .map(|entry| (entry.chunk_index.get(), entry.checksum.get()))
+        .collect()
+}
+
+fn is_bit_set(data: &[u8], bit_index: usize) -> bool {
+    data[bit_index / 8] & (1 << (bit_index % 8)) != 0
+}
+
+/// Checks that the next message is an error with the given code and request type, and
+/// returns its context.
+async fn expect_error(client: &mut RawClient, code: ErrorCode, request_type: u8) -> u32 {
+    let Message::Error(error) = client.receive_type(MessageType::Error).await else {
+        unreachable!();
+    };
+
+    assert_eq!(ErrorCode::from_u8(error.code), code);
+    assert_eq!(error.request_type, request_type);
+    error.context.get()
+}
+
+/// Negotiates the Locked Ranges capability, so the server confirms every subscription with a
+/// Locked Ranges message.
+async fn negotiate_locked_ranges(client: &mut RawClient) {
+    client
+        .send(&client_hello(
+            PROTOCOL_VERSION_MINOR,
+            CAPABILITY_LOCKED_RANGES,
+        ))
+        .await;
+    client.receive_type(MessageType::ClientHelloAck).await;
+    client.receive_type(MessageType::BoardInfo).await;
+}
+
+/// Sends a subscription message and waits until the server has processed it.
+async fn subscribe(client: &mut RawClient, message: &[u8]) {
+    client.send(message).await;
+    receive_locked_ranges(client).await;
+}
+
+/// Receives the next Locked Ranges message, as the chunk index and the ranges.
+async fn receive_locked_ranges(client: &mut RawClient) -> (u16, Vec<(u32, u32)>) {
+    let message = client.receive_type(MessageType::LockedRanges).await;
+    let Message::LockedRanges(header, entries) = message else {
+        unreachable!();
+    };
+    let ranges = entries
+        .iter()
+        .map(|entry| (entry.start.get(), entry.end.get()))
+        .collect();
+    (header.chunk_index.get(), ranges)
+}
+
+/// Receives the next Sparse Partial State Update, checks its chunk index and returns its
+/// entries.
+async fn receive_sparse_update(client: &mut RawClient, chunk_index: u16) -> Vec<u32> {
+    let message = client
+        .receive_type(MessageType::SparsePartialStateUpdate)
+        .await;
+    let Message::SparsePartialStateUpdate(update, entries) = message else {
+        unreachable!();
+    };
+    assert_eq!(update.chunk_index.get(), chunk_index);
+    entries
+        .chunks_exact(4)
+        .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+        .collect()
+}
+
+/// Decodes a grayscale PNG written by the server into its size and rows, each prefixed with
+/// the filter type.
+fn decode_png(png: &[u8]) -> (u32, u32, Vec<u8>) {
+    use std::io::Read;
+
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    let mut offset = 8;
+    let mut size = (0, 0);
+    let mut data = Vec::new();
+    while offset < png.len() {
+        let length = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+        let chunk_type = &png[offset + 4..offset + 8];
+        let chunk_data = &png[offset + 8..offset + 8 + length];
+        let crc = u32::from_be_bytes(
+            png[offset + 8 + length..offset + 12 + length]
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(crc, crc32fast::hash(&png[offset + 4..offset + 8 + length]));
+
+        match chunk_type {
+            b"IHDR" => {
+                let width = u32::from_be_bytes(chunk_data[..4].try_into().unwrap());
+                let height = u32::from_be_bytes(chunk_data[4..8].try_into().unwrap());
+                size = (width, height);
+            }
+            b"IDAT" => data.extend_from_slice(chunk_data),
+            _ => {}
+        }
+        offset += 12 + length;
+    }
+
+    let mut pixels = Vec::new();
+    flate2::read::ZlibDecoder::new(&data[..])
+        .read_to_end(&mut pixels)
+        .unwrap();
+    (size.0, size.1, pixels)
+}
+
+/// Sends an authenticated admin request to a server started with the token "secret".
+async fn admin(server: &TestServer, path: &str, body: &[u8]) -> (u16, String) {
+    server
+        .http_request("POST", path, Some("secret"), body)
+        .await
+}
+
+fn range_path(operation: &str, start: usize, end: usize) -> String {
+    format!("/admin/{}?start={}&end={}", operation, start, end)
+}
+
+/// Returns the value of an unlabeled metric.
+fn metric_value(metrics: &str, name: &str) -> u64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| panic!("Missing metric {}", name))
+}
+
+#[tokio::test]
+async fn hello_is_sent_first() {
+    let server = TestServer::start().await;
+    let mut client = RawClient::connect(server.address).await;
+
+    let Message::Hello(hello) = client.receive().await else {
+        panic!("Expected a Hello message");
+    };
+    let (major, minor) = (hello.version_major.get(), hello.version_minor.get());
+    assert_eq!(major, PROTOCOL_VERSION_MAJOR);
+    assert_eq!(minor, PROTOCOL_VERSION_MINOR);
+}
+
+#[tokio::test]
+async fn client_hello_negotiates_capabilities() {
+    let server = TestServer::start().await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+
+    client
+        .send(&client_hello(PROTOCOL_VERSION_MINOR, u32::MAX))
+        .await;
+
+    let Message::ClientHelloAck(ack) = client.receive_type(MessageType::ClientHelloAck).await
+    else {
+        unreachable!();
+    };
+    let capabilities = ack.capabilities.get();
+    assert_eq!(capabilities, SUPPORTED_CAPABILITIES);
+}
+
+#[tokio::test]
+async fn configured_geometry_is_announced_and_used() {
+    let geometry = BitmapGeometry {
+        chunk_size: 4096,
+        chunk_count: 16,
+        update_chunk_size: 16,
+    };
+    let server = TestServer::start_with(|settings| {
+        settings.chunk_size = geometry.chunk_size;
+        settings.chunk_count = geometry.chunk_count;
+        settings.update_chunk_size = geometry.update_chunk_size;
+    })
+    .await;
+
+    // Clients from before 1.14 assume the default geometry, so they're turned away.
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+    client.send(&client_hello(13, 0)).await;
+    let request_type = MessageType::ClientHello as u8;
+    let context = expect_error(&mut client, ErrorCode::UnsupportedVersion, request_type).await;
+    assert_eq!(context, 13);
+    client.expect_closed().await;
+
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+    client.send(&full_state_request(0)).await;
+    let request_type = MessageType::ChunkFullStateRequest as u8;
+    let context = expect_error(&mut client, ErrorCode::UnsupportedVersion, request_type).await;
+    assert_eq!(context, 0);
+    client.expect_closed().await;
+
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+    // Without sparse updates and compression, so updates are sent as windows and full states
+    // uncompressed.
+    client
+        .send(&client_hello(
+            PROTOCOL_VERSION_MINOR,
+            CAPABILITY_LOCKED_RANGES,
+        ))
+        .await;
+    client.receive_type(MessageType::ClientHelloAck).await;
+    let Message::BoardInfo(info) = client.receive_type(MessageType::BoardInfo).await else {
+        unreachable!();
+    };
+    assert_eq!(info.chunk_size.get() as usize, geometry.chunk_size);
+    assert_eq!(info.chunk_count.get() as usize, geometry.chunk_count);
+    assert_eq!(
+        info.update_chunk_size.get() as usize,
+        geometry.update_chunk_size
+    );
+
+    let last_chunk = geometry.chunk_count as u16 - 1;
+    subscribe(&mut client, &subscription(last_chunk)).await;
+
+    let index = geometry.bitmap_size() - 1;
+    client.send(&toggle(index as u32)).await;
+    let message = client.receive_type(MessageType::PartialStateUpdate).await;
+    let Message::PartialStateUpdate(update, chunk) = message else {
+        unreachable!();
+    };
+    assert_eq!(
+        update.offset.get() as usize,
+        geometry.bitmap_size() / 8 - geometry.update_chunk_size
+    );
+    assert_eq!(chunk.len(), geometry.update_chunk_size);
+    assert_eq!(chunk[geometry.update_chunk_size - 1], 0x80);
+
+    client.send(&full_state_request(last_chunk)).await;
+    let message = client
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert_eq!(bitmap.len(), geometry.chunk_size_bytes());
+    assert!(is_bit_set(bitmap, geometry.chunk_size - 1));
+
+    client
+        .send(&full_state_request(geometry.chunk_count as u16))
+        .await;
+    let request_type = MessageType::ChunkFullStateRequest as u8;
+    let context = expect_error(&mut client, ErrorCode::InvalidIndex, request_type).await;
+    assert_eq!(context, geometry.chunk_count as u32);
+
+    client.send(&toggle(geometry.bitmap_size() as u32)).await;
+    let request_type = MessageType::ToggleBit as u8;
+    let context = expect_error(&mut client, ErrorCode::InvalidIndex, request_type).await;
+    assert_eq!(context, geometry.bitmap_size() as u32);
+}
+
+#[tokio::test]
+async fn boards_are_selected_by_request_path() {
+    let server = TestServer::start_with(|settings| {
+        settings.boards = vec![
+            BoardSettings::named("main"),
+            BoardSettings {
+                chunk_count: Some(16),
+                ..BoardSettings::named("event")
+            },
+        ];
+    })
+    .await;
+
+    let status = RawClient::try_connect_to(server.address, "/staging")
+        .await
+        .err();
+    assert_eq!(status, Some(404));
+
+    for path in ["/event", "/?board=event"] {
+        let mut client = RawClient::connect_to(server.address, path).await;
+        assert!(matches!(client.receive().await, Message::Hello(_)));
+        client.send(&client_hello(PROTOCOL_VERSION_MINOR, 0)).await;
+        client.receive_type(MessageType::ClientHelloAck).await;
+        let Message::BoardInfo(info) = client.receive_type(MessageType::BoardInfo).await else {
+            unreachable!();
+        };
+        assert_eq!(info.chunk_count.get(), 16);
+    }
+
+    let mut event = RawClient::connect_to(server.address, "/event").await;
+    assert!(matches!(event.receive().await, Message::Hello(_)));
+    event.send(&client_hello(PROTOCOL_VERSION_MINOR, 0)).await;
+    event.receive_type(MessageType::ClientHelloAck).await;
+    event.receive_type(MessageType::BoardInfo).await;
+    let mut main = RawClient::connect_and_skip_hello(server.address).await;
+
+    event.send(&toggle(5)).await;
+    event.send(&full_state_request(0)).await;
+    let message = event
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert!(is_bit_set(bitmap, 5));
+
+    main.send(&full_state_request(0)).await;
+    let message = main.receive_type(MessageType::ChunkFullStateResponse).await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert!(!is_bit_set(bitmap, 5));
+
+    let metrics = server.metrics().await;
+    assert!(metrics.contains("bitmap_bit_toggles{board=\"event\"} 1\n"));
+    assert!(metrics.contains("bitmap_bit_toggles{board=\"main\"} 0\n"));
+}
+
+#[tokio::test]
+async fn full_state_of_new_chunk_is_empty() {
+    let server = TestServer::start().await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+
+    client.send(&full_state_request(5)).await;
+
+    let message = client
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(response, bitmap) = message else {
+        unreachable!();
+    };
+    let chunk_index = response.chunk_index.get();
+    assert_eq!(chunk_index, 5);
+    assert_eq!(bitmap.len(), CHUNK_SIZE_BYTES);
+    assert!(bitmap.iter().all(|&byte| byte == 0));
+}
+
+#[tokio::test]
+async fn compressed_full_state_decodes_to_chunk() {
+    let server = TestServer::start().await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+
+    let base = 6 * CHUNK_SIZE as u32;
+    let indices: Vec<u32> = (100..300).chain([5000, 70000]).map(|i| base + i).collect();
+    client.send(&toggle_bits(&indices)).await;
+    client.send(&full_state_request(6)).await;
+    let message = client
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    let expected = bitmap.to_vec();
+
+    client
+        .send(&create_message(
+            MessageType::ChunkFullStateRequestWithFlags,
+            |message| {
+                if let MessageMut::ChunkFullStateRequestWithFlags(message) = message {
+                    message.chunk_index.set(6);
+                    message.flags = FULL_STATE_FLAG_ALLOW_COMPRESSION;
+                }
+            },
+        ))
+        .await;
+    let message = client
+        .receive_type(MessageType::CompressedChunkFullStateResponse)
+        .await;
+    let Message::CompressedChunkFullStateResponse(response, encoded) = message else {
+        unreachable!();
+    };
+    assert_eq!(response.chunk_index.get(), 6);
+    let encoding = ChunkEncoding::from_u8(response.encoding).unwrap();
+    assert_eq!(encoding, ChunkEncoding::RunLength);
+    assert!(encoded.len() < 16);
+
+    let mut decoded = vec![0; CHUNK_SIZE_BYTES];
+    encoding::decode(encoding, encoded, &mut decoded).unwrap();
+    assert_eq!(decoded, expected);
+}
+
+#[tokio::test]
+async fn toggle_produces_partial_update() {
+    let server = TestServer::start().await;
+    let mut subscriber = RawClient::connect_and_skip_hello(server.address).await;
+    let mut toggler = RawClient::connect_and_skip_hello(server.address).await;
+
+    negotiate_locked_ranges(&mut subscriber).await;
+    subscribe(&mut subscriber, &subscription(2)).await;
+
+    let index = 2 * CHUNK_SIZE + 1000;
+    toggler.send(&toggle(index as u32)).await;
+
+    let message = subscriber
+        .receive_type(MessageType::PartialStateUpdate)
+        .await;
+    let Message::PartialStateUpdate(update, chunk) = message else {
+        unreachable!();
+    };
+    let offset = update.offset.get() as usize;
+    let window_offset = index / 8 / UPDATE_CHUNK_SIZE * UPDATE_CHUNK_SIZE;
+    assert_eq!(offset, window_offset);
+    assert!(is_bit_set(chunk, index - window_offset * 8));
+
+    toggler.send(&full_state_request(2)).await;
+    let message = toggler
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert!(is_bit_set(bitmap, index - 2 * CHUNK_SIZE));
+}
+
+#[tokio::test]
+async fn updates_are_sent_for_every_subscription() {
+    let server = TestServer::start().await;
+    let mut subscriber = RawClient::connect_and_skip_hello(server.address).await;
+    let mut toggler = RawClient::connect_and_skip_hello(server.address).await;
+
+    negotiate_locked_ranges(&mut subscriber).await;
+    subscribe(&mut subscriber, &add_subscription(1)).await;
+    subscribe(&mut subscriber, &add_subscription(3)).await;
+
+    let (first, second) = (CHUNK_SIZE as u32 + 5, 3 * CHUNK_SIZE as u32 + 5);
+    toggler.send(&toggle_bits(&[first, second])).await;
+    let mut offsets = Vec::new();
+    for _ in 0..2 {
+        let message = subscriber
+            .receive_type(MessageType::PartialStateUpdate)
+            .await;
+        let Message::PartialStateUpdate(update, _) = message else {
+            unreachable!();
+        };
+        offsets.push(update.offset.get() as usize);
+    }
+    offsets.sort_unstable();
+    assert_eq!(offsets, [CHUNK_SIZE_BYTES, 3 * CHUNK_SIZE_BYTES]);
+
+    // Subscriptions are processed in order, so the removal is done once the next one is
+    // confirmed. An update of the removed chunk would arrive before the one of chunk 5.
+    subscriber.send(&remove_subscription(1)).await;
+    subscribe(&mut subscriber, &add_subscription(5)).await;
+    toggler.send(&toggle_bits(&[first, second])).await;
+    let message = subscriber
+        .receive_type(MessageType::PartialStateUpdate)
+        .await;
+    let Message::PartialStateUpdate(update, _) = message else {
+        unreachable!();
+    };
+    assert_eq!(update.offset.get() as usize, 3 * CHUNK_SIZE_BYTES);
+
+    toggler.send(&toggle(5 * CHUNK_SIZE as u32)).await;
+    let message = subscriber
+        .receive_type(MessageType::PartialStateUpdate)
+        .await;
+    let Message::PartialStateUpdate(update, _) = message else {
+        unreachable!();
+    };
+    assert_eq!(update.offset.get() as usize, 5 * CHUNK_SIZE_BYTES);
+}
+
+#[tokio::test]
+async fn subscriptions_are_limited() {
+    let server = TestServer::start_with(|settings| {
+        settings.max_subscriptions = 2;
+    })
+    .await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+
+    negotiate_locked_ranges(&mut client).await;
+    subscribe(&mut client, &add_subscription(0)).await;
+    subscribe(&mut client, &add_subscription(1)).await;
+
+    client.send(&add_subscription(2)).await;
+    let request_type = MessageType::AddPartialStateSubscription as u8;
+    let context = expect_error(&mut client, ErrorCode::TooManySubscriptions, request_type).await;
+    assert_eq!(context, 2);
+
+    // Removing a subscription makes room for another one.
+    client.send(&remove_subscription(0)).await;
+    client.send(&add_subscription(2)).await;
+    let (chunk_index, _) = receive_locked_ranges(&mut client).await;
+    assert_eq!(chunk_index, 2);
+
+    // A new subscription replaces all of them.
+    subscribe(&mut client, &subscription(3)).await;
+    subscribe(&mut client, &add_subscription(4)).await;
+    client.send(&toggle(4 * CHUNK_SIZE as u32)).await;
+    let message = client.receive_type(MessageType::PartialStateUpdate).await;
+    let Message::PartialStateUpdate(update, _) = message else {
+        unreachable!();
+    };
+    assert_eq!(update.offset.get() as usize, 4 * CHUNK_SIZE_BYTES);
+}
+
+#[tokio::test]
+async fn sparse_updates_list_changed_bits() {
+    let server = TestServer::start().await;
+    let mut subscriber = RawClient::connect_and_skip_hello(server.address).await;
+    let mut toggler = RawClient::connect_and_skip_hello(server.address).await;
+
+    negotiate_locked_ranges(&mut subscriber).await;
+    subscriber
+        .send(&create_message(MessageType::SetUpdateFlags, |message| {
+            if let MessageMut::SetUpdateFlags(message) = message {
+                message.flags = UPDATE_FLAG_ALLOW_SPARSE;
+            }
+        }))
+        .await;
+    subscribe(&mut subscriber, &subscription(2)).await;
+
+    // Bits toggled twice didn't change and aren't listed.
+    let base = 2 * CHUNK_SIZE as u32;
+    let indices = [base + 3, base + 1000, base + 7, base + 7];
+    toggler.send(&toggle_bits(&indices)).await;
+    let entries = receive_sparse_update(&mut subscriber, 2).await;
+    assert_eq!(
+        entries,
+        [3 | SPARSE_UPDATE_VALUE_BIT, 1000 | SPARSE_UPDATE_VALUE_BIT]
+    );
+
+    toggler.send(&toggle(base + 3)).await;
+    let entries = receive_sparse_update(&mut subscriber, 2).await;
+    assert_eq!(entries, [3]);
+}
+
+#[tokio::test]
+async fn toggle_bits_toggles_every_index() {
+    let server = TestServer::start().await;
+    let mut subscriber = RawClient::connect_and_skip_hello(server.address).await;
+    let mut toggler = RawClient::connect_and_skip_hello(server.address).await;
+
+    negotiate_locked_ranges(&mut subscriber).await;
+    subscribe(&mut subscriber, &subscription(1)).await;
+
+    // Repeated indices are toggled once for every occurrence.
+    let base = CHUNK_SIZE as u32;
+    let indices = [base + 1, base + 9, base + 1, base + 1, base + 20];
+    toggler.send(&toggle_bits(&indices)).await;
+
+    let message = subscriber
+        .receive_type(MessageType::PartialStateUpdate)
+        .await;
+    let Message::PartialStateUpdate(update, chunk) = message else {
+        unreachable!();
+    };
+    assert_eq!(update.offset.get() as usize, CHUNK_SIZE_BYTES);
+    assert_eq!(&chunk[..3], [0x02, 0x02, 0x10]);
+
+    toggler.send(&full_state_request(1)).await;
+    let message = toggler
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert_eq!(&bitmap[..3], [0x02, 0x02, 0x10]);
+
+    let metrics = server.metrics().await;
+    assert!(metrics.contains("bitmap_bit_toggles{board=\"main\"} 5\n"));
+    assert!(metrics.contains("bitmap_checked_bits{board=\"main\"} 3\n"));
+
+    toggler
+        .send(&[MessageType::ToggleBits as u8, 1, 2, 3])
+        .await;
+    let request_type = MessageType::ToggleBits as u8;
+    expect_error(&mut toggler, ErrorCode::InvalidMessageSize, request_type).await;
+}
+
+#[tokio::test]
+async fn set_bit_is_idempotent() {
+    let server = TestServer::start().await;
+    let mut subscriber = RawClient::connect_and_skip_hello(server.address).await;
+    let mut setter = RawClient::connect_and_skip_hello(server.address).await;
+
+    negotiate_locked_ranges(&mut subscriber).await;
+    subscribe(&mut subscriber, &subscription(1)).await;
+
+    // Any non-zero value checks the bit.
+    let base = CHUNK_SIZE as u32;
+    setter.send(&set_bit(base + 3, 1)).await;
+    setter.send(&set_bit(base + 3, 1)).await;
+    setter.send(&set_bit(base + 4, 2)).await;
+    setter.send(&full_state_request(1)).await;
+    let message = setter
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert_eq!(bitmap[0], 0x18);
+
+    let metrics = server.metrics().await;
+    assert!(metrics.contains("bitmap_bit_toggles{board=\"main\"} 2\n"));
+    assert!(metrics.contains("bitmap_checked_bits{board=\"main\"} 2\n"));
+
+    // The changes may be split across several updates.
+    loop {
+        let message = subscriber
+            .receive_type(MessageType::PartialStateUpdate)
+            .await;
+        let Message::PartialStateUpdate(_, chunk) = message else {
+            unreachable!();
+        };
+        if chunk[0] == 0x18 {
+            break;
+        }
+    }
+
+    // Setting a bit to its current value doesn't produce an update. An update caused by it
+    // would be sent on the next tick, before the one holding the cleared bit.
+    setter.send(&set_bit(base + 4, 1)).await;
+    setter.sync().await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    setter.send(&set_bit(base + 3, 0)).await;
+    let message = subscriber
+        .receive_type(MessageType::PartialStateUpdate)
+        .await;
+    let Message::PartialStateUpdate(_, chunk) = message else {
+        unreachable!();
+    };
+    assert_eq!(chunk[0], 0x10);
+    let metrics = server.metrics().await;
+    assert!(metrics.contains("bitmap_bit_toggles{board=\"main\"} 3\n"));
+
+    setter.send(&set_bit(CHUNK_COUNT as u32 * base, 1)).await;
+    let request_type = MessageType::SetBit as u8;
+    let context = expect_error(&mut setter, ErrorCode::InvalidIndex, request_type).await;
+    assert_eq!(context, CHUNK_COUNT as u32 * base);
+}
+
+#[tokio::test]
+async fn unsubscribe_stops_updates() {
+    let server = TestServer::start().await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+
+    negotiate_locked_ranges(&mut client).await;
+    subscribe(&mut client, &subscription(3)).await;
+    client.send(&toggle(3 * CHUNK_SIZE as u32)).await;
+    client.receive_type(MessageType::PartialStateUpdate).await;
+
+    client
+        .send(&create_message(
+            MessageType::PartialStateUnsubscription,
+            |_| {},
+        ))
+        .await;
+    // Subscriptions are processed in order, so the unsubscription is done once this one is
+    // confirmed.
+    subscribe(&mut client, &add_subscription(5)).await;
+    client.send(&toggle(3 * CHUNK_SIZE as u32)).await;
+
+    let message_type = client.try_receive_type(Duration::from_millis(500)).await;
+    assert_eq!(message_type, None);
+}
+
+#[tokio::test]
+async fn ping_is_answered_with_pong() {
+    let server = TestServer::start().await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+
+    client
+        .send(&create_message(MessageType::Ping, |message| {
+            if let MessageMut::Ping(message) = message {
+                message.payload.set(0x0123_4567_89ab_cdef);
+            }
+        }))
+        .await;
+
+    let Message::Pong(pong) = client.receive_type(MessageType::Pong).await else {
+        unreachable!();
+    };
+    let payload = pong.payload.get();
+    assert_eq!(payload, 0x0123_4567_89ab_cdef);
+}
+
+#[tokio::test]
+async fn only_clients_from_1_9_are_pinged() {
+    let server = TestServer::start_with(|settings| {
+        settings.ping_interval_secs = 1;
+        settings.max_missed_pings = 1;
+    })
+    .await;
+    let mut old_client = RawClient::connect_and_skip_hello(server.address).await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+    client.send(&client_hello(9, 0)).await;
+    client.receive_type(MessageType::ClientHelloAck).await;
+
+    loop {
+        match client.receive().await {
+            Message::Ping(_) => break,
+            Message::Stats(_) => {}
+            message => panic!("Unexpected message {:?}", message.id()),
+        }
+    }
+
+    // Unanswered pings would have closed the connection by now.
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    old_client.send(&full_state_request(0)).await;
+    loop {
+        match old_client.receive().await {
+            Message::ChunkFullStateResponse(..) => break,
+            Message::Stats(_) => {}
+            message => panic!("Unexpected message {:?}", message.id()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn invalid_messages_are_rejected() {
+    let server = TestServer::start().await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+
+    client.send(&[0xFF]).await;
+    expect_error(&mut client, ErrorCode::UnsupportedMessage, 0xFF).await;
+
+    client.send(&[MessageType::ToggleBit as u8, 0, 0]).await;
+    let request_type = MessageType::ToggleBit as u8;
+    expect_error(&mut client, ErrorCode::InvalidMessageSize, request_type).await;
+
+    // Server messages can't be sent by clients.
+    client.send(&[MessageType::Hello as u8, 1, 0, 0, 0]).await;
+    let request_type = MessageType::Hello as u8;
+    expect_error(&mut client, ErrorCode::UnsupportedMessage, request_type).await;
+
+    client.send(&full_state_request(CHUNK_COUNT as u16)).await;
+    let request_type = MessageType::ChunkFullStateRequest as u8;
+    let context = expect_error(&mut client, ErrorCode::InvalidIndex, request_type).await;
+    assert_eq!(context, CHUNK_COUNT as u32);
+
+    client.send(&toggle(u32::MAX)).await;
+    let request_type = MessageType::ToggleBit as u8;
+    let context = expect_error(&mut client, ErrorCode::InvalidIndex, request_type).await;
+    assert_eq!(context, u32::MAX);
+
+    // The connection is still usable.
+    client.send(&full_state_request(0)).await;
+    client
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+}
+
+#[tokio::test]
+async fn checksums_follow_chunk_changes() {
+    let server = TestServer::start().await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+
+    let empty_checksum = bitmap::chunk_checksum(&[0; CHUNK_SIZE_BYTES]);
+    let checksums = request_checksums(&mut client, &[4, 7]).await;
+    assert_eq!(checksums, [(4, empty_checksum), (7, empty_checksum)]);
+
+    client.send(&toggle(4 * CHUNK_SIZE as u32 + 12345)).await;
+
+    let checksums = request_checksums(&mut client, &[4]).await;
+    client.send(&full_state_request(4)).await;
+    let message = client
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, data) = message else {
+        unreachable!();
+    };
+    let expected = bitmap::chunk_checksum(data);
+    assert_ne!(expected, empty_checksum);
+    assert_eq!(checksums, [(4, expected)]);
+
+    let checksums = request_checksums(&mut client, &[]).await;
+    assert_eq!(checksums.len(), CHUNK_COUNT);
+    assert!(checksums
+        .iter()
+        .enumerate()
+        .all(|(i, &(chunk_index, _))| chunk_index as usize == i));
+    assert_eq!(checksums[4].1, expected);
+    assert_eq!(checksums[5].1, empty_checksum);
+
+    client
+        .send(&checksum_request(&[1, CHUNK_COUNT as u16]))
+        .await;
+    let context = expect_error(
+        &mut client,
+        ErrorCode::InvalidIndex,
+        MessageType::ChunkChecksumRequest as u8,
+    )
+    .await;
+    assert_eq!(context, CHUNK_COUNT as u32);
+
+    client.send(&checksum_request(&[1; CHUNK_COUNT + 1])).await;
+    expect_error(
+        &mut client,
+        ErrorCode::InvalidMessageSize,
+        MessageType::ChunkChecksumRequest as u8,
+    )
+    .await;
+    let checksums = request_checksums(&mut client, &[1; CHUNK_COUNT]).await;
+    assert_eq!(checksums.len(), CHUNK_COUNT);
+}
+
+#[tokio::test]
+async fn lagging_subscriber_is_sent_full_state() {
+    const CHUNKS: u16 = 16;
+
+    let server = TestServer::start_with(|settings| {
+        settings.backlog_capacity = 1;
+        settings.max_subscriptions = CHUNKS as usize;
+    })
+    .await;
+    let mut subscriber = RawClient::connect_with_small_buffer(server.address).await;
+    let mut toggler = RawClient::connect_and_skip_hello(server.address).await;
+
+    assert!(matches!(subscriber.receive().await, Message::Hello(_)));
+    negotiate_locked_ranges(&mut subscriber).await;
+    for chunk_index in 0..CHUNKS {
+        subscribe(&mut subscriber, &add_subscription(chunk_index)).await;
+    }
+
+    // A bit in every update window of the subscribed chunks, so each tick produces as many
+    // updates as possible. Loopback sockets buffer a lot, so it takes a few megabytes to
+    // block the server.
+    let mut toggles = vec![MessageType::ToggleBits as u8];
+    for chunk_index in 0..CHUNKS as usize {
+        for window in 0..CHUNK_SIZE_BYTES / UPDATE_CHUNK_SIZE {
+            let index = chunk_index * CHUNK_SIZE + window * UPDATE_CHUNK_SIZE * 8;
+            toggles.extend_from_slice(&(index as u32).to_le_bytes());
+        }
+    }
+
+    // The subscriber doesn't read for a while, so the server blocks on sending to it and
+    // misses several ticks of updates.
+    for _ in 0..20 {
+        toggler.send(&toggles).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // The updates sent before falling behind are still queued, followed by the full state.
+    loop {
+        let message = subscriber.receive().await;
+        match message {
+            Message::ChunkFullStateResponse(response, _) => {
+                assert!(response.chunk_index.get() < CHUNKS);
+                break;
+            }
+            Message::PartialStateUpdate(..) | Message::Stats(_) => {}
+            message => panic!("Unexpected message {:?}", message.id()),
+        }
+    }
+
+    let metrics = server.metrics().await;
+    assert!(metric_value(&metrics, "bitmap_lag_events") > 0);
+    assert!(metric_value(&metrics, "bitmap_lagged_updates_total") > 0);
+    assert_eq!(metric_value(&metrics, "bitmap_lagging_clients"), 1);
+    assert_eq!(metrics.matches("bitmap_client_lag_events{").count(), 1);
+
+    // Series of clients are removed when they disconnect.
+    drop(subscriber);
+    let mut metrics = metrics;
+    for _ in 0..100 {
+        if !metrics.contains("bitmap_client_lag_events{") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        metrics = server.metrics().await;
+    }
+    assert!(!metrics.contains("bitmap_client_lag_events{"));
+}
+
+#[tokio::test]
+async fn locked_regions_are_read_only() {
+    let chunk_size = CHUNK_SIZE as u32;
+    let server = TestServer::start_with(|settings| {
+        let file = LockFile {
+            chunks: vec![2],
+            ranges: vec![LockedRange {
+                start: chunk_size + 10,
+                end: chunk_size + 20,
+            }],
+        };
+        let locks = RegionLocks::new(file, &settings.geometry()).unwrap();
+        locks.save_to_file(settings.locks_path("main")).unwrap();
+    })
+    .await;
+
+    let mut subscriber = RawClient::connect_and_skip_hello(server.address).await;
+    negotiate_locked_ranges(&mut subscriber).await;
+
+    // Ranges are relative to the chunk.
+    subscriber.send(&subscription(1)).await;
+    let locked = receive_locked_ranges(&mut subscriber).await;
+    assert_eq!(locked, (1, vec![(10, 20)]));
+    subscriber.send(&add_subscription(2)).await;
+    let locked = receive_locked_ranges(&mut subscriber).await;
+    assert_eq!(locked, (2, vec![(0, chunk_size)]));
+
+    let mut toggler = RawClient::connect_and_skip_hello(server.address).await;
+    toggler.send(&toggle(chunk_size + 15)).await;
+    let request_type = MessageType::ToggleBit as u8;
+    let context = expect_error(&mut toggler, ErrorCode::ReadOnly, request_type).await;
+    assert_eq!(context, chunk_size + 15);
+
+    toggler.send(&set_bit(2 * chunk_size + 1, 1)).await;
+    let request_type = MessageType::SetBit as u8;
+    let context = expect_error(&mut toggler, ErrorCode::ReadOnly, request_type).await;
+    assert_eq!(context, 2 * chunk_size + 1);
+
+    // Unlocked bits of the same message are still toggled.
+    let mut toggles = vec![MessageType::ToggleBits as u8];
+    for index in [chunk_size + 12, 3 * chunk_size + 30] {
+        toggles.extend_from_slice(&index.to_le_bytes());
+    }
+    toggler.send(&toggles).await;
+    let request_type = MessageType::ToggleBits as u8;
+    let context = expect_error(&mut toggler, ErrorCode::ReadOnly, request_type).await;
+    assert_eq!(context, chunk_size + 12);
+
+    toggler.send(&full_state_request(3)).await;
+    let message = toggler
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert!(is_bit_set(bitmap, 30));
+
+    // Subscribers are told about the new locks after a reload.
+    std::fs::remove_file(server.data_dir.join("locks.json")).unwrap();
+    server.reload_locks();
+    let mut unlocked = vec![
+        receive_locked_ranges(&mut subscriber).await,
+        receive_locked_ranges(&mut subscriber).await,
+    ];
+    unlocked.sort_unstable();
+    assert_eq!(unlocked, [(1, vec![]), (2, vec![])]);
+
+    toggler.send(&toggle(chunk_size + 15)).await;
+    toggler.send(&full_state_request(1)).await;
+    let message = toggler
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert!(is_bit_set(bitmap, 15));
+    assert!(!is_bit_set(bitmap, 12));
+}
+
+#[tokio::test]
+async fn admin_api_replaces_locks() {
+    let server = TestServer::start_with(|settings| {
+        settings.admin_token = Some("secret".to_string());
+    })
+    .await;
+
+    let mut subscriber = RawClient::connect_and_skip_hello(server.address).await;
+    negotiate_locked_ranges(&mut subscriber).await;
+    subscribe(&mut subscriber, &subscription(1)).await;
+
+    let out_of_range = format!(r#"{{"chunks":[{}]}}"#, CHUNK_COUNT);
+    let (status, _) = admin(&server, "/admin/locks", out_of_range.as_bytes()).await;
+    assert_eq!(status, 400);
+    let (status, _) = admin(&server, "/admin/locks", b"not json").await;
+    assert_eq!(status, 400);
+
+    let (status, _) = admin(&server, "/admin/locks", br#"{"chunks":[1]}"#).await;
+    assert_eq!(status, 200);
+    let locked = receive_locked_ranges(&mut subscriber).await;
+    assert_eq!(locked, (1, vec![(0, CHUNK_SIZE as u32)]));
+
+    let mut toggler = RawClient::connect_and_skip_hello(server.address).await;
+    toggler.send(&toggle(CHUNK_SIZE as u32 + 1)).await;
+    let request_type = MessageType::ToggleBit as u8;
+    expect_error(&mut toggler, ErrorCode::ReadOnly, request_type).await;
+
+    // The locks are saved, so a reload keeps them.
+    let saved = std::fs::read_to_string(server.data_dir.join("locks.json")).unwrap();
+    assert!(saved.contains("\"chunks\""));
+    server.reload_locks();
+    let locked = receive_locked_ranges(&mut subscriber).await;
+    assert_eq!(locked, (1, vec![(0, CHUNK_SIZE as u32)]));
+}
+
+#[tokio::test]
+async fn admin_api_modifies_bits() {
+    let server = TestServer::start().await;
+    let (status, _) = server.http_request("POST", "/admin/save", None, &[]).await;
+    assert_eq!(status, 404);
+
+    let server = TestServer::start_with(|settings| {
+        settings.admin_token = Some("secret".to_string());
+    })
+    .await;
+
+    let (status, _) = server.http_request("POST", "/admin/save", None, &[]).await;
+    assert_eq!(status, 401);
+    let (status, _) = server
+        .http_request("POST", "/admin/save", Some("wrong"), &[])
+        .await;
+    assert_eq!(status, 401);
+    let (status, _) = server
+        .http_request("GET", "/admin/save", Some("secret"), &[])
+        .await;
+    assert_eq!(status, 405);
+
+    // Unauthorized requests are answered without waiting for the body.
+    let head = "POST /admin/chunk?index=0 HTTP/1.1\r\nContent-Length: 1000\r\n\r\n";
+    assert_eq!(server.http_request_head(head).await, 401);
+
+    assert_eq!(
+        admin(&server, "/admin/fill?start=5&end=5", &[]).await.0,
+        400
+    );
+    assert_eq!(
+        admin(&server, "/admin/fill?start=0&end=1&board=x", &[])
+            .await
+            .0,
+        404
+    );
+
+    let mut subscriber = RawClient::connect_and_skip_hello(server.address).await;
+    negotiate_locked_ranges(&mut subscriber).await;
+    subscribe(&mut subscriber, &subscription(1)).await;
+
+    // Bits 8..16 of chunk 1, then 12..20, then the whole chunk.
+    let (status, body) = admin(
+        &server,
+        &range_path("fill", CHUNK_SIZE + 8, CHUNK_SIZE + 16),
+        &[],
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert!(body.contains("\"changed_bits\":8"));
+    let message = subscriber
+        .receive_type(MessageType::PartialStateUpdate)
+        .await;
+    let Message::PartialStateUpdate(update, chunk) = message else {
+        unreachable!();
+    };
+    assert_eq!(update.offset.get() as usize, CHUNK_SIZE_BYTES);
+    assert_eq!(&chunk[..3], [0x00, 0xFF, 0x00]);
+
+    let (_, body) = admin(
+        &server,
+        &range_path("invert", CHUNK_SIZE + 12, CHUNK_SIZE + 20),
+        &[],
+    )
+    .await;
+    assert!(body.contains("\"checked_bits\":8"));
+    let message = subscriber
+        .receive_type(MessageType::PartialStateUpdate)
+        .await;
+    let Message::PartialStateUpdate(_, chunk) = message else {
+        unreachable!();
+    };
+    assert_eq!(&chunk[..3], [0x00, 0x0F, 0x0F]);
+
+    let (_, body) = admin(
+        &server,
+        &range_path("clear", CHUNK_SIZE, 2 * CHUNK_SIZE),
+        &[],
+    )
+    .await;
+    assert!(body.contains("\"changed_bits\":8"));
+    assert!(body.contains("\"checked_bits\":0"));
+    let message = subscriber
+        .receive_type(MessageType::PartialStateUpdate)
+        .await;
+    let Message::PartialStateUpdate(_, chunk) = message else {
+        unreachable!();
+    };
+    assert_eq!(&chunk[..3], [0x00, 0x00, 0x00]);
+
+    // Raw bytes, with only the differing bits changed.
+    let (status, body) = admin(&server, "/admin/chunk?index=3&offset=2", &[0xAA, 0x01]).await;
+    assert_eq!(status, 200);
+    assert!(body.contains("\"changed_bits\":5"));
+    let (status, _) = admin(
+        &server,
+        &format!("/admin/chunk?index=3&offset={}", CHUNK_SIZE_BYTES - 1),
+        &[0xAA, 0x01],
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    subscriber.send(&full_state_request(3)).await;
+    let message = subscriber
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert_eq!(&bitmap[..4], [0x00, 0x00, 0xAA, 0x01]);
+
+    let metrics = server.metrics().await;
+    assert!(metrics.contains("bitmap_checked_bits{board=\"main\"} 5\n"));
+    assert!(metrics.contains("bitmap_bit_toggles{board=\"main\"} 0\n"));
+
+    let (status, _) = admin(&server, "/admin/save", &[]).await;
+    assert_eq!(status, 200);
+    let state = std::fs::read(server.data_dir.join("state.bin")).unwrap();
+    assert_eq!(state[3 * CHUNK_SIZE_BYTES + 2], 0xAA);
+
+    // Ranges spanning the whole board, with unaligned ends.
+    let bitmap_size = CHUNK_SIZE * CHUNK_COUNT;
+    let (_, body) = admin(&server, &range_path("invert", 3, bitmap_size), &[]).await;
+    assert!(body.contains(&format!("\"changed_bits\":{}", bitmap_size - 3)));
+    assert!(body.contains(&format!("\"checked_bits\":{}", bitmap_size - 8)));
+    let (_, body) = admin(&server, &range_path("fill", 0, bitmap_size - 5), &[]).await;
+    assert!(body.contains(&format!("\"changed_bits\":{}", 8)));
+    assert!(body.contains(&format!("\"checked_bits\":{}", bitmap_size)));
+}
+
+#[tokio::test]
+async fn chunks_and_board_are_rendered_as_png() {
+    let server = TestServer::start_with(|settings| {
+        settings.admin_token = Some("secret".to_string());
+        settings.render_cache_secs = 60;
+    })
+    .await;
+
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+    client.send(&toggle(CHUNK_SIZE as u32 + 513)).await;
+    client.sync().await;
+    admin(
+        &server,
+        &range_path("fill", 5 * CHUNK_SIZE, 6 * CHUNK_SIZE),
+        &[],
+    )
+    .await;
+
+    // The second row of the chunk starts at bit 512, checked bits are black.
+    let (status, head, png) = server
+        .http_request_raw("GET", "/render/chunk/1.png", None, &[])
+        .await;
+    assert_eq!(status, 200);
+    assert!(head.contains("Content-Type: image/png"));
+    assert!(head.contains("Cache-Control: public, max-age=60"));
+    let (width, height, pixels) = decode_png(&png);
+    assert_eq!((width, height), (512, 512));
+    let (first_row, second_row) = (&pixels[..65], &pixels[65..130]);
+    assert!(first_row[1..].iter().all(|&byte| byte == 0xFF));
+    assert_eq!(&second_row[1..3], [0xBF, 0xFF]);
+
+    // One pixel per chunk, 64 chunks per row.
+    let (status, _, png) = server
+        .http_request_raw("GET", "/render/board.png?scale=512", None, &[])
+        .await;
+    assert_eq!(status, 200);
+    let (width, height, pixels) = decode_png(&png);
+    assert_eq!((width, height), (64, 64));
+    assert_eq!(&pixels[1..1 + 7], [255, 255, 255, 255, 255, 0, 255]);
+
+    // Cached images don't change until they expire.
+    admin(
+        &server,
+        &range_path("clear", 5 * CHUNK_SIZE, 6 * CHUNK_SIZE),
+        &[],
+    )
+    .await;
+    let (_, _, cached) = server
+        .http_request_raw("GET", "/render/board.png?scale=512", None, &[])
+        .await;
+    assert_eq!(cached, png);
+
+    let (status, _, _) = server
+        .http_request_raw("GET", "/render/board.png?scale=12", None, &[])
+        .await;
+    assert_eq!(status, 400);
+    let path = format!("/render/chunk/{}.png", CHUNK_COUNT);
+    let (status, _, _) = server.http_request_raw("GET", &path, None, &[]).await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn snapshots_keep_chunk_history() {
+    let server = TestServer::start_with(|settings| {
+        settings.admin_token = Some("secret".to_string());
+        settings.max_snapshots = 2;
+    })
+    .await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+
+    let (status, _) = server
+        .http_request("GET", "/history/chunk/1.bin", None, &[])
+        .await;
+    assert_eq!(status, 404);
+
+    let mut timestamps = Vec::new();
+    for index in [CHUNK_SIZE + 1, CHUNK_SIZE + 2, CHUNK_SIZE + 1] {
+        client.send(&toggle(index as u32)).await;
+        client.sync().await;
+
+        let (status, body) = admin(&server, "/admin/snapshot", &[]).await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        timestamps.push(body["timestamp"].as_u64().unwrap());
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // Only the last two snapshots are kept.
+    let (_, body) = server
+        .http_request("GET", "/history/snapshots", None, &[])
+        .await;
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["snapshots"], serde_json::json!(timestamps[1..]));
+
+    let path = format!("/history/chunk/1.bin?at={}", timestamps[0]);
+    let (status, _, _) = server.http_request_raw("GET", &path, None, &[]).await;
+    assert_eq!(status, 404);
+
+    let path = format!("/history/chunk/1.bin?at={}", timestamps[2] - 1);
+    let (status, head, chunk) = server.http_request_raw("GET", &path, None, &[]).await;
+    assert_eq!(status, 200);
+    assert!(head.contains(&format!("X-Snapshot-Timestamp: {}", timestamps[1])));
+    assert_eq!(chunk.len(), CHUNK_SIZE_BYTES);
+    assert_eq!(chunk[0], 0b110);
+
+    // The latest snapshot by default.
+    let (_, _, chunk) = server
+        .http_request_raw("GET", "/history/chunk/1.bin", None, &[])
+        .await;
+    assert_eq!(chunk[0], 0b100);
+
+    let (status, head, png) = server
+        .http_request_raw("GET", "/history/chunk/1.png", None, &[])
+        .await;
+    assert_eq!(status, 200);
+    assert!(head.contains("Content-Type: image/png"));
+    let (_, _, pixels) = decode_png(&png);
+    assert_eq!(pixels[1], 0b1101_1111);
+}
+
+#[tokio::test]
+async fn toggle_log_is_replayed_after_crash() {
+    let configure = |settings: &mut Settings| {
+        settings.admin_token = Some("secret".to_string());
+        settings.toggle_log_flush_ms = 20;
+    };
+    let server = TestServer::start_with(configure).await;
+
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+    for index in [CHUNK_SIZE + 1, CHUNK_SIZE + 2, CHUNK_SIZE + 1] {
+        client.send(&toggle(index as u32)).await;
+    }
+    client.send(&set_bit(CHUNK_SIZE as u32 + 3, 1)).await;
+    let log_path = server.data_dir.join("toggles.log");
+    wait_until(|| std::fs::metadata(&log_path).is_ok_and(|m| m.len() == 8 + 4 * 13)).await;
+    drop(client);
+
+    // Nothing was saved, the state only comes from the log.
+    let server = server.crash_and_restart(configure).await;
+    assert!(!server.data_dir.join("state.bin").exists());
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+    client.send(&full_state_request(1)).await;
+    let message = client
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert_eq!(bitmap[0], 0b1100);
+    let metrics = server.metrics().await;
+    assert!(metrics.contains("bitmap_checked_bits{board=\"main\"} 2\n"));
+
+    // Saving resets the log to its header. A log written before the last save, as after a
+    // crash between saving and resetting the log, isn't replayed again.
+    let stale_log = std::fs::read(&log_path).unwrap();
+    let (status, _) = admin(&server, "/admin/save", &[]).await;
+    assert_eq!(status, 200);
+    assert_eq!(std::fs::metadata(&log_path).unwrap().len(), 8);
+    drop(client);
+
+    std::fs::write(&log_path, &stale_log).unwrap();
+    let server = server.crash_and_restart(configure).await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+    client.send(&full_state_request(1)).await;
+    let message = client
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert_eq!(bitmap[0], 0b1100);
+
+    // Later flips and ranges are replayed on top of the state file.
+    client.send(&toggle(CHUNK_SIZE as u32 + 2)).await;
+    wait_until(|| std::fs::metadata(&log_path).is_ok_and(|m| m.len() == 8 + 13)).await;
+    drop(client);
+    let (status, _) = admin(
+        &server,
+        &range_path("fill", CHUNK_SIZE + 4, CHUNK_SIZE + 6),
+        &[],
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(std::fs::metadata(&log_path).unwrap().len(), 8 + 13 + 17);
+
+    let server = server.crash_and_restart(configure).await;
+    let mut client = RawClient::connect_and_skip_hello(server.address).await;
+    client.send(&full_state_request(1)).await;
+    let message = client
+        .receive_type(MessageType::ChunkFullStateResponse)
+        .await;
+    let Message::ChunkFullStateResponse(_, bitmap) = message else {
+        unreachable!();
+    };
+    assert_eq!(bitmap[0], 0b11_1000);
+    drop(client);
+
+    // With the log disabled, it's saved to the state file once and removed.
+    let disable = |settings: &mut Settings| settings.toggle_log_flush_ms = 0;
+    let server = server.crash_and_restart(disable).await;
+    assert!(!log_path.exists());
+    let state = std::fs::read(server.data_dir.join("state.bin")).unwrap();
+    assert_eq!(state[CHUNK_SIZE_BYTES], 0b11_1000);
+    let server = server.crash_and_restart(disable).await;
+    let state = std::fs::read(server.data_dir.join("state.bin")).unwrap();
+    assert_eq!(state[CHUNK_SIZE_BYTES], 0b11_1000);
+}